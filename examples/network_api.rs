@@ -1,6 +1,9 @@
+#[cfg(windows)]
 use hcn::{api, schema::*};
+#[cfg(windows)]
 use windows::core::GUID;
 
+#[cfg(windows)]
 pub fn main() -> Result<(), Box<dyn std::error::Error>> {
     //turn logging on with $env:RUST_LOG="debug"      
     let _ = env_logger::try_init();
@@ -29,3 +32,6 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[cfg(not(windows))]
+fn main() {}
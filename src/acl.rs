@@ -0,0 +1,464 @@
+//! Endpoint ACLs compiled from ordered allow and block rules.
+//!
+//! HNS evaluates an endpoint's ACL policies by priority, lowest first. An
+//! [`AclRule`] list reads like a firewall instead: the first rule matching a
+//! packet decides. [`AclCompiler`] keeps that order by giving every rule its
+//! own priority and points out rules that can never match. [`diff`] compares
+//! the result with the policies an endpoint has, so only changes are applied.
+
+#[cfg(windows)]
+use crate::endpoint::modify_policies;
+use crate::prefix::IpPrefix;
+use crate::schema::*;
+use anyhow::{bail, Context, Result};
+use std::fmt;
+use std::str::FromStr;
+
+pub const PROTOCOL_ICMPV4: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+pub const PROTOCOL_ICMPV6: u8 = 58;
+
+/// An inclusive range of ports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PortRange {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl PortRange {
+    pub fn new(first: u16, last: u16) -> Result<Self> {
+        if first > last {
+            bail!("invalid port range {}-{}", first, last);
+        }
+        Ok(Self { first, last })
+    }
+
+    pub fn single(port: u16) -> Self {
+        Self {
+            first: port,
+            last: port,
+        }
+    }
+
+    pub fn contains_range(&self, other: &PortRange) -> bool {
+        self.first <= other.first && other.last <= self.last
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = anyhow::Error;
+
+    /// Parse `port` or `first-last`.
+    fn from_str(s: &str) -> Result<Self> {
+        let port = |p: &str| {
+            p.trim()
+                .parse::<u16>()
+                .with_context(|| format!("invalid port range {}", s))
+        };
+        match s.split_once('-') {
+            Some((first, last)) => Self::new(port(first)?, port(last)?),
+            None => Ok(Self::single(port(s)?)),
+        }
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.first == self.last {
+            write!(f, "{}", self.first)
+        } else {
+            write!(f, "{}-{}", self.first, self.last)
+        }
+    }
+}
+
+/// A rule of an ordered ACL. Empty address and port lists match anything.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AclRule {
+    pub action: AclAction,
+    pub direction: AclDirection,
+    /// The IP protocol number, any protocol when not set.
+    pub protocol: Option<u8>,
+    pub local_addresses: Vec<IpPrefix>,
+    pub remote_addresses: Vec<IpPrefix>,
+    /// Only for TCP and UDP rules.
+    pub local_ports: Vec<PortRange>,
+    /// Only for TCP and UDP rules.
+    pub remote_ports: Vec<PortRange>,
+}
+
+impl AclRule {
+    pub fn allow(direction: AclDirection) -> Self {
+        Self::new(AclAction::Allow, direction)
+    }
+
+    pub fn block(direction: AclDirection) -> Self {
+        Self::new(AclAction::Block, direction)
+    }
+
+    fn new(action: AclAction, direction: AclDirection) -> Self {
+        Self {
+            action,
+            direction,
+            protocol: None,
+            local_addresses: vec![],
+            remote_addresses: vec![],
+            local_ports: vec![],
+            remote_ports: vec![],
+        }
+    }
+
+    pub fn protocol(mut self, protocol: u8) -> Self {
+        self.protocol = Some(protocol);
+        self
+    }
+
+    pub fn local_address(mut self, prefix: IpPrefix) -> Self {
+        self.local_addresses.push(prefix);
+        self
+    }
+
+    pub fn remote_address(mut self, prefix: IpPrefix) -> Self {
+        self.remote_addresses.push(prefix);
+        self
+    }
+
+    pub fn local_ports(mut self, ports: PortRange) -> Self {
+        self.local_ports.push(ports);
+        self
+    }
+
+    pub fn remote_ports(mut self, ports: PortRange) -> Self {
+        self.remote_ports.push(ports);
+        self
+    }
+
+    /// True when `self` matches every packet `other` matches.
+    pub fn covers(&self, other: &AclRule) -> bool {
+        self.direction == other.direction
+            && (self.protocol.is_none() || self.protocol == other.protocol)
+            && covers(
+                &self.local_addresses,
+                &other.local_addresses,
+                IpPrefix::contains_prefix,
+            )
+            && covers(
+                &self.remote_addresses,
+                &other.remote_addresses,
+                IpPrefix::contains_prefix,
+            )
+            && covers(
+                &self.local_ports,
+                &other.local_ports,
+                PortRange::contains_range,
+            )
+            && covers(
+                &self.remote_ports,
+                &other.remote_ports,
+                PortRange::contains_range,
+            )
+    }
+
+    fn check(&self) -> Result<()> {
+        let has_ports = !self.local_ports.is_empty() || !self.remote_ports.is_empty();
+        if has_ports && !matches!(self.protocol, Some(PROTOCOL_TCP) | Some(PROTOCOL_UDP)) {
+            bail!("ports can only be matched for TCP and UDP");
+        }
+        Ok(())
+    }
+
+    fn setting(&self, rule_type: AclRuleType, priority: u16) -> AclPolicySetting {
+        AclPolicySetting {
+            protocols: self.protocol.map(|p| p.to_string()).unwrap_or_default(),
+            action: self.action,
+            direction: self.direction,
+            local_addresses: join(&self.local_addresses),
+            remote_addresses: join(&self.remote_addresses),
+            local_ports: join(&self.local_ports),
+            remote_ports: join(&self.remote_ports),
+            rule_type: Some(rule_type),
+            priority: Some(priority),
+            extra_fields: ExtraFields::new(),
+        }
+    }
+}
+
+// An empty list matches anything, so it covers everything and is only
+// covered by another empty list.
+fn covers<T>(outer: &[T], inner: &[T], contains: impl Fn(&T, &T) -> bool) -> bool {
+    outer.is_empty()
+        || (!inner.is_empty() && inner.iter().all(|i| outer.iter().any(|o| contains(o, i))))
+}
+
+fn join<T: fmt::Display>(values: &[T]) -> String {
+    let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+    values.join(",")
+}
+
+/// A rule that can never match because an earlier one matches all its traffic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shadowed {
+    /// Index of the shadowed rule.
+    pub rule: usize,
+    /// Index of the earlier rule that covers it.
+    pub by: usize,
+    /// The rules have different actions, so the shadowed rule was probably
+    /// meant to take effect.
+    pub conflicting: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompiledAcls {
+    pub policies: Vec<EndpointPolicy>,
+    pub shadowed: Vec<Shadowed>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AclCompiler {
+    /// Priority of the first rule.
+    pub first_priority: u16,
+    /// Gap between the priorities of consecutive rules.
+    pub step: u16,
+    pub rule_type: AclRuleType,
+}
+
+impl Default for AclCompiler {
+    fn default() -> Self {
+        Self {
+            first_priority: 100,
+            step: 1,
+            rule_type: AclRuleType::Switch,
+        }
+    }
+}
+
+impl AclCompiler {
+    /// Turn `rules`, first match wins, into ACL policies. Shadowed rules are
+    /// still compiled, and reported.
+    pub fn compile(&self, rules: &[AclRule]) -> Result<CompiledAcls> {
+        if self.step == 0 {
+            bail!("ACL priority step must not be zero");
+        }
+
+        let mut policies = vec![];
+        let mut shadowed = vec![];
+        for (index, rule) in rules.iter().enumerate() {
+            rule.check()
+                .with_context(|| format!("invalid ACL rule {}", index))?;
+            if let Some(by) = rules[..index].iter().position(|r| r.covers(rule)) {
+                shadowed.push(Shadowed {
+                    rule: index,
+                    by,
+                    conflicting: rules[by].action != rule.action,
+                });
+            }
+
+            let priority = u16::try_from(index)
+                .ok()
+                .and_then(|i| i.checked_mul(self.step))
+                .and_then(|offset| self.first_priority.checked_add(offset))
+                .with_context(|| format!("ACL rule {} is past the highest priority", index))?;
+            policies.push(EndpointPolicy::new(
+                EndpointPolicyType::ACL,
+                &rule.setting(self.rule_type, priority),
+            )?);
+        }
+
+        Ok(CompiledAcls { policies, shadowed })
+    }
+}
+
+/// ACL policies to add to and remove from an endpoint.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AclDiff {
+    pub add: Vec<EndpointPolicy>,
+    pub remove: Vec<EndpointPolicy>,
+}
+
+impl AclDiff {
+    pub fn is_empty(&self) -> bool {
+        self.add.is_empty() && self.remove.is_empty()
+    }
+}
+
+/// The changes that turn the ACL policies in `current`, e.g. an endpoint's
+/// policies, into `desired`. Other policy types are left alone, and fields HNS
+/// adds to the settings it returns are ignored in the comparison.
+pub fn diff(desired: &[EndpointPolicy], current: &[EndpointPolicy]) -> Result<AclDiff> {
+    let desired = acl_settings(desired)?;
+    let current = acl_settings(current)?;
+
+    let add = desired
+        .iter()
+        .filter(|(d, _)| !current.iter().any(|(c, _)| c == d))
+        .map(|(_, policy)| (*policy).clone())
+        .collect();
+    let remove = current
+        .iter()
+        .filter(|(c, _)| !desired.iter().any(|(d, _)| d == c))
+        .map(|(_, policy)| (*policy).clone())
+        .collect();
+    Ok(AclDiff { add, remove })
+}
+
+fn acl_settings(policies: &[EndpointPolicy]) -> Result<Vec<(AclPolicySetting, &EndpointPolicy)>> {
+    policies
+        .iter()
+        .filter(|p| p.policy_type == EndpointPolicyType::ACL)
+        .map(|policy| {
+            let mut setting: AclPolicySetting = policy.settings_as()?;
+            setting.extra_fields.clear();
+            Ok((setting, policy))
+        })
+        .collect()
+}
+
+/// Add and then remove the policies of `diff` on an endpoint.
+///
+/// Adding first means the endpoint is never left without its rules: for a
+/// moment it has both the old and the new ones, instead of neither.
+#[cfg(windows)]
+pub fn apply(endpoint_id: &str, diff: &AclDiff) -> Result<()> {
+    if !diff.add.is_empty() {
+        modify_policies(endpoint_id, RequestType::Add, diff.add.clone())
+            .context("failed to add ACL policies")?;
+    }
+    if !diff.remove.is_empty() {
+        modify_policies(endpoint_id, RequestType::Remove, diff.remove.clone())
+            .context("failed to remove ACL policies")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefix(s: &str) -> IpPrefix {
+        s.parse().unwrap()
+    }
+
+    fn tcp_in() -> AclRule {
+        AclRule::allow(AclDirection::In).protocol(PROTOCOL_TCP)
+    }
+
+    #[test]
+    fn covers() {
+        let any = AclRule::allow(AclDirection::In);
+        let web = tcp_in()
+            .local_address(prefix("10.0.0.0/8"))
+            .local_ports(PortRange::new(80, 443).unwrap());
+        let https = tcp_in()
+            .local_address(prefix("10.1.0.0/16"))
+            .local_ports(PortRange::single(443));
+
+        assert!(any.covers(&web));
+        assert!(web.covers(&https));
+        assert!(!https.covers(&web));
+        // an empty list matches anything, so it is only covered by another
+        assert!(!web.covers(&tcp_in()));
+        assert!(!https.covers(&https.clone().local_ports(PortRange::single(8443))));
+        assert!(!tcp_in().covers(&AclRule::allow(AclDirection::In)));
+        assert!(!any.covers(&AclRule::allow(AclDirection::Out)));
+        // the action does not matter, only which packets match
+        assert!(AclRule::block(AclDirection::In).covers(&web));
+    }
+
+    #[test]
+    fn compile_reports_shadowed_rules() {
+        let rules = [
+            tcp_in().local_ports(PortRange::new(1, 1024).unwrap()),
+            AclRule::block(AclDirection::In)
+                .protocol(PROTOCOL_TCP)
+                .local_ports(PortRange::single(22)),
+            tcp_in().local_ports(PortRange::single(80)),
+        ];
+        let compiled = AclCompiler::default().compile(&rules).unwrap();
+
+        assert_eq!(
+            compiled.shadowed,
+            vec![
+                Shadowed {
+                    rule: 1,
+                    by: 0,
+                    conflicting: true
+                },
+                Shadowed {
+                    rule: 2,
+                    by: 0,
+                    conflicting: false
+                },
+            ]
+        );
+        let priorities: Vec<Option<u16>> = compiled
+            .policies
+            .iter()
+            .map(|p| p.settings_as::<AclPolicySetting>().unwrap().priority)
+            .collect();
+        assert_eq!(priorities, vec![Some(100), Some(101), Some(102)]);
+    }
+
+    #[test]
+    fn compile_fails_past_the_highest_priority() {
+        let rules = vec![AclRule::allow(AclDirection::In); 3];
+        let compiler = AclCompiler {
+            first_priority: u16::MAX - 10,
+            step: 6,
+            ..Default::default()
+        };
+        assert!(compiler.compile(&rules[..2]).is_ok());
+        assert!(compiler.compile(&rules).is_err());
+
+        let compiler = AclCompiler {
+            step: 0,
+            ..Default::default()
+        };
+        assert!(compiler.compile(&rules).is_err());
+    }
+
+    #[test]
+    fn compile_rejects_ports_without_tcp_or_udp() {
+        let rule = AclRule::allow(AclDirection::In).local_ports(PortRange::single(53));
+        assert!(AclCompiler::default().compile(&[rule]).is_err());
+    }
+
+    fn compile(rules: &[AclRule]) -> Vec<EndpointPolicy> {
+        AclCompiler::default().compile(rules).unwrap().policies
+    }
+
+    #[test]
+    fn diff_ignores_extra_fields_and_other_policies() {
+        let desired = compile(&[tcp_in(), AclRule::block(AclDirection::In)]);
+
+        // what HNS returns: the same ACLs with fields it adds, and other policies
+        let mut current = desired.clone();
+        for policy in &mut current {
+            let settings = policy.settings.as_mut().unwrap().as_object_mut().unwrap();
+            settings.insert("Id".to_string(), "7d1f3c2a".into());
+        }
+        current.push(
+            EndpointPolicy::new(
+                EndpointPolicyType::OutBoundNAT,
+                &OutboundNatPolicySetting::default(),
+            )
+            .unwrap(),
+        );
+
+        let diff = diff(&desired, &current).unwrap();
+        assert!(diff.is_empty(), "{:?}", diff);
+    }
+
+    #[test]
+    fn diff_adds_and_removes_changed_rules() {
+        let current = compile(&[tcp_in(), AclRule::block(AclDirection::In)]);
+        let desired = compile(&[
+            tcp_in().local_ports(PortRange::single(80)),
+            AclRule::block(AclDirection::In),
+        ]);
+
+        let diff = diff(&desired, &current).unwrap();
+        assert_eq!(diff.add, desired[..1]);
+        assert_eq!(diff.remove, current[..1]);
+    }
+}
@@ -0,0 +1,253 @@
+//! The host's network adapters, for networks that attach to one.
+
+use crate::prefix::IpPrefix;
+use anyhow::{bail, Result};
+use std::fmt;
+use std::net::IpAddr;
+#[cfg(windows)]
+use std::net::{Ipv4Addr, Ipv6Addr};
+#[cfg(windows)]
+use windows::Win32::Foundation::{ERROR_BUFFER_OVERFLOW, ERROR_NO_DATA, NO_ERROR, WIN32_ERROR};
+#[cfg(windows)]
+use windows::Win32::NetworkManagement::IpHelper::{
+    GetAdaptersAddresses, GAA_FLAG_SKIP_ANYCAST, GAA_FLAG_SKIP_DNS_SERVER, GAA_FLAG_SKIP_MULTICAST,
+    IP_ADAPTER_ADDRESSES_LH,
+};
+#[cfg(windows)]
+use windows::Win32::NetworkManagement::Ndis::IfOperStatusUp;
+#[cfg(windows)]
+use windows::Win32::Networking::WinSock::{
+    AF_INET, AF_INET6, AF_UNSPEC, SOCKADDR_IN, SOCKADDR_IN6, SOCKET_ADDRESS,
+};
+
+/// A network adapter of the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostAdapter {
+    /// The name shown in Network Connections, which HNS expects in `NetAdapterName`.
+    pub name: String,
+    pub addresses: Vec<AdapterAddress>,
+    pub up: bool,
+}
+
+/// A unicast address assigned to an adapter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdapterAddress {
+    pub address: IpAddr,
+    /// Length of the on-link prefix the address belongs to.
+    pub prefix_len: u8,
+}
+
+impl AdapterAddress {
+    /// The subnet the address is on.
+    pub fn subnet(&self) -> Result<IpPrefix> {
+        IpPrefix::new(self.address, self.prefix_len)
+    }
+}
+
+impl HostAdapter {
+    /// The subnets the adapter is connected to.
+    pub fn subnets(&self) -> impl Iterator<Item = IpPrefix> + '_ {
+        self.addresses.iter().filter_map(|a| a.subnet().ok())
+    }
+}
+
+/// How to pick the adapter a network attaches to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdapterSelector {
+    /// The adapter with this name.
+    Name(String),
+    /// The adapter that has this address.
+    Address(IpAddr),
+    /// The adapter with an address inside this subnet.
+    Subnet(IpPrefix),
+}
+
+impl AdapterSelector {
+    pub fn matches(&self, adapter: &HostAdapter) -> bool {
+        match self {
+            AdapterSelector::Name(name) => adapter.name.eq_ignore_ascii_case(name),
+            AdapterSelector::Address(ip) => adapter.addresses.iter().any(|a| a.address == *ip),
+            AdapterSelector::Subnet(subnet) => adapter
+                .addresses
+                .iter()
+                .any(|a| subnet.contains(&a.address)),
+        }
+    }
+
+    /// The one adapter matching the selector. Adapters that are down are only
+    /// considered when selecting by name.
+    pub fn select<'a>(&self, adapters: &'a [HostAdapter]) -> Result<&'a HostAdapter> {
+        let by_name = matches!(self, AdapterSelector::Name(_));
+        let matching: Vec<&HostAdapter> = adapters
+            .iter()
+            .filter(|a| (by_name || a.up) && self.matches(a))
+            .collect();
+        match matching[..] {
+            [adapter] => Ok(adapter),
+            [] => bail!("no host adapter matches {}", self),
+            _ => {
+                let names: Vec<&str> = matching.iter().map(|a| a.name.as_str()).collect();
+                bail!("{} matches several adapters: {}", self, names.join(", "))
+            }
+        }
+    }
+}
+
+impl fmt::Display for AdapterSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdapterSelector::Name(name) => write!(f, "adapter name {}", name),
+            AdapterSelector::Address(ip) => write!(f, "address {}", ip),
+            AdapterSelector::Subnet(subnet) => write!(f, "subnet {}", subnet),
+        }
+    }
+}
+
+/// List the host's network adapters.
+#[cfg(windows)]
+pub fn host_adapters() -> Result<Vec<HostAdapter>> {
+    let flags = GAA_FLAG_SKIP_ANYCAST | GAA_FLAG_SKIP_MULTICAST | GAA_FLAG_SKIP_DNS_SERVER;
+
+    // the size needed can grow between calls if adapters are added meanwhile
+    let mut size: u32 = 16 * 1024;
+    for _ in 0..3 {
+        // u64 elements keep the buffer aligned for IP_ADAPTER_ADDRESSES_LH
+        let mut buffer = vec![0u64; (size as usize).div_ceil(8)];
+        let first = buffer.as_mut_ptr() as *mut IP_ADAPTER_ADDRESSES_LH;
+
+        let result = WIN32_ERROR(unsafe {
+            GetAdaptersAddresses(AF_UNSPEC.0 as u32, flags, None, Some(first), &mut size)
+        });
+        match result {
+            NO_ERROR => return Ok(unsafe { read_adapters(first) }),
+            ERROR_NO_DATA => return Ok(vec![]),
+            ERROR_BUFFER_OVERFLOW => continue,
+            e => return Err(windows::core::Error::from(e.to_hresult()).into()),
+        }
+    }
+    bail!("host adapters kept changing while being listed")
+}
+
+#[cfg(windows)]
+// SAFETY: `adapter` must be the list filled in by GetAdaptersAddresses
+unsafe fn read_adapters(mut adapter: *const IP_ADAPTER_ADDRESSES_LH) -> Vec<HostAdapter> {
+    let mut adapters = vec![];
+    while let Some(current) = adapter.as_ref() {
+        let mut addresses = vec![];
+        let mut unicast = current.FirstUnicastAddress;
+        while let Some(address) = unicast.as_ref() {
+            if let Some(ip) = socket_address(&address.Address) {
+                addresses.push(AdapterAddress {
+                    address: ip,
+                    prefix_len: address.OnLinkPrefixLength,
+                });
+            }
+            unicast = address.Next;
+        }
+
+        adapters.push(HostAdapter {
+            name: current.FriendlyName.to_string().unwrap_or_default(),
+            addresses,
+            up: current.OperStatus == IfOperStatusUp,
+        });
+        adapter = current.Next;
+    }
+    adapters
+}
+
+#[cfg(windows)]
+unsafe fn socket_address(address: &SOCKET_ADDRESS) -> Option<IpAddr> {
+    let sockaddr = address.lpSockaddr.as_ref()?;
+    match sockaddr.sa_family {
+        AF_INET => {
+            let sockaddr = &*(address.lpSockaddr as *const SOCKADDR_IN);
+            // S_addr is in network order, its bytes are the address octets
+            Some(Ipv4Addr::from(sockaddr.sin_addr.S_un.S_addr.to_ne_bytes()).into())
+        }
+        AF_INET6 => {
+            let sockaddr = &*(address.lpSockaddr as *const SOCKADDR_IN6);
+            Some(Ipv6Addr::from(sockaddr.sin6_addr.u.Byte).into())
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn adapter(name: &str, addresses: &[(&str, u8)], up: bool) -> HostAdapter {
+        HostAdapter {
+            name: name.to_string(),
+            addresses: addresses
+                .iter()
+                .map(|(address, prefix_len)| AdapterAddress {
+                    address: address.parse().unwrap(),
+                    prefix_len: *prefix_len,
+                })
+                .collect(),
+            up,
+        }
+    }
+
+    fn adapters() -> Vec<HostAdapter> {
+        vec![
+            adapter("Ethernet", &[("192.168.1.10", 24), ("fd00::10", 64)], true),
+            adapter("Ethernet 2", &[("192.168.2.10", 24)], true),
+            adapter("Ethernet 3", &[("192.168.2.20", 24)], false),
+            adapter("vEthernet (nat)", &[("172.20.0.1", 20)], true),
+        ]
+    }
+
+    fn select(selector: AdapterSelector) -> Result<String> {
+        selector.select(&adapters()).map(|a| a.name.clone())
+    }
+
+    #[test]
+    fn select_by_name() {
+        assert_eq!(
+            select(AdapterSelector::Name("ethernet 2".to_string())).unwrap(),
+            "Ethernet 2"
+        );
+        // adapters that are down can still be picked by name
+        assert_eq!(
+            select(AdapterSelector::Name("Ethernet 3".to_string())).unwrap(),
+            "Ethernet 3"
+        );
+        assert!(select(AdapterSelector::Name("Wi-Fi".to_string())).is_err());
+    }
+
+    #[test]
+    fn select_by_address() {
+        assert_eq!(
+            select(AdapterSelector::Address("fd00::10".parse().unwrap())).unwrap(),
+            "Ethernet"
+        );
+        // down
+        assert!(select(AdapterSelector::Address("192.168.2.20".parse().unwrap())).is_err());
+        assert!(select(AdapterSelector::Address("192.168.1.11".parse().unwrap())).is_err());
+    }
+
+    #[test]
+    fn select_by_subnet() {
+        // Ethernet 3 is on the subnet as well but down
+        assert_eq!(
+            select(AdapterSelector::Subnet("192.168.2.0/24".parse().unwrap())).unwrap(),
+            "Ethernet 2"
+        );
+        let error = select(AdapterSelector::Subnet("192.168.0.0/16".parse().unwrap()))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("Ethernet, Ethernet 2"), "{}", error);
+        assert!(select(AdapterSelector::Subnet("10.0.0.0/8".parse().unwrap())).is_err());
+    }
+
+    #[test]
+    fn subnets() {
+        let adapter = adapter("Ethernet", &[("192.168.1.10", 24), ("10.1.2.3", 33)], true);
+        assert_eq!(
+            adapter.subnets().collect::<Vec<_>>(),
+            vec!["192.168.1.0/24".parse::<IpPrefix>().unwrap()]
+        );
+    }
+}
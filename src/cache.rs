@@ -0,0 +1,675 @@
+//! An in-memory cache of HNS objects for callers that look objects up often.
+//!
+//! [`Cache`] holds the last listing of every object kind with indexes for the
+//! usual lookups. [`Informer`] keeps one up to date: it lists everything once,
+//! refreshes the affected kinds when HNS sends a notification and lists
+//! everything again periodically and after the service reconnects. HNS does
+//! not notify about endpoint creation or any load balancer change, those are
+//! only picked up by the periodic resync (or by calling [`Cache::refresh`]).
+//!
+//! Every refresh is compared against the cached objects and the differences
+//! are reported as [`CacheEvent`]s.
+//!
+//! Listings run without holding the cache's lock and can finish out of order.
+//! Each one is numbered when it starts, and a listing that started before the
+//! one the cached objects came from is dropped instead of applied.
+
+use crate::graph::{normalize, ObjectGraph, ObjectKind};
+#[cfg(windows)]
+use crate::notify::{self, NotificationType, Supervisor};
+#[cfg(windows)]
+use crate::retry::RetryPolicy;
+use crate::schema::*;
+#[cfg(windows)]
+use crate::{list, list_endpoints, list_load_balancers, list_namespaces, list_networks};
+#[cfg(windows)]
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+#[cfg(windows)]
+use std::sync::{
+    mpsc::{self, RecvTimeoutError},
+    Arc,
+};
+#[cfg(windows)]
+use std::thread::{self, JoinHandle};
+#[cfg(windows)]
+use std::time::{Duration, Instant};
+
+/// How a cached object changed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change<T> {
+    Added(T),
+    Updated { old: T, new: T },
+    Removed(T),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CacheEvent {
+    Network(Change<HostComputeNetwork>),
+    Namespace(Change<HostComputeNamespace>),
+    Endpoint(Change<HostComputeEndpoint>),
+    LoadBalancer(Change<HostComputeLoadBalancer>),
+}
+
+#[derive(Debug, Default)]
+struct Store {
+    networks: HashMap<String, HostComputeNetwork>,
+    namespaces: HashMap<String, HostComputeNamespace>,
+    endpoints: HashMap<String, HostComputeEndpoint>,
+    load_balancers: HashMap<String, HostComputeLoadBalancer>,
+
+    networks_by_name: HashMap<String, Vec<String>>,
+    endpoints_by_name: HashMap<String, Vec<String>>,
+    endpoints_by_network: HashMap<String, Vec<String>>,
+    endpoints_by_ip: HashMap<IpAddr, Vec<String>>,
+    load_balancers_by_vip: HashMap<IpAddr, Vec<String>>,
+
+    // The number of the listing the cached objects of each kind came from.
+    listed: HashMap<ObjectKind, u64>,
+}
+
+/// The last known state of every HNS object, indexed for lookups.
+#[derive(Debug, Default)]
+pub struct Cache {
+    store: RwLock<Store>,
+    listings: AtomicU64,
+}
+
+impl Cache {
+    /// An empty cache, filled by [`Cache::resync`] or [`Cache::apply`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace everything in the cache with `snapshot`, taken as newer than
+    /// any listing still running.
+    pub fn apply(&self, snapshot: ObjectGraph) -> Vec<CacheEvent> {
+        self.apply_listing(self.start_listing(), snapshot)
+    }
+
+    /// List every object kind again.
+    #[cfg(windows)]
+    pub fn resync(&self) -> Result<Vec<CacheEvent>> {
+        // listed before taking the lock, lookups are not blocked on HNS
+        let listing = self.start_listing();
+        let snapshot = ObjectGraph {
+            networks: list_networks()?,
+            namespaces: list_namespaces()?,
+            endpoints: list_endpoints()?,
+            load_balancers: list_load_balancers()?,
+        };
+        Ok(self.apply_listing(listing, snapshot))
+    }
+
+    /// List one object kind again.
+    #[cfg(windows)]
+    pub fn refresh(&self, kind: ObjectKind) -> Result<Vec<CacheEvent>> {
+        let listing = self.start_listing();
+        Ok(match kind {
+            ObjectKind::Network => {
+                let objects = list()?;
+                self.store
+                    .write()
+                    .unwrap()
+                    .replace_networks(listing, objects)
+            }
+            ObjectKind::Namespace => {
+                let objects = list()?;
+                self.store
+                    .write()
+                    .unwrap()
+                    .replace_namespaces(listing, objects)
+            }
+            ObjectKind::Endpoint => {
+                let objects = list()?;
+                self.store
+                    .write()
+                    .unwrap()
+                    .replace_endpoints(listing, objects)
+            }
+            ObjectKind::LoadBalancer => {
+                let objects = list()?;
+                self.store
+                    .write()
+                    .unwrap()
+                    .replace_load_balancers(listing, objects)
+            }
+        })
+    }
+
+    // Number a listing that is about to start.
+    fn start_listing(&self) -> u64 {
+        self.listings.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn apply_listing(&self, listing: u64, snapshot: ObjectGraph) -> Vec<CacheEvent> {
+        let mut store = self.store.write().unwrap();
+        let mut events = vec![];
+        events.extend(store.replace_networks(listing, snapshot.networks));
+        events.extend(store.replace_namespaces(listing, snapshot.namespaces));
+        events.extend(store.replace_endpoints(listing, snapshot.endpoints));
+        events.extend(store.replace_load_balancers(listing, snapshot.load_balancers));
+        events
+    }
+
+    pub fn networks(&self) -> Vec<HostComputeNetwork> {
+        let store = self.store.read().unwrap();
+        store.networks.values().cloned().collect()
+    }
+
+    pub fn network(&self, id: &str) -> Option<HostComputeNetwork> {
+        let store = self.store.read().unwrap();
+        store.networks.get(&normalize(id)).cloned()
+    }
+
+    pub fn network_by_name(&self, name: &str) -> Option<HostComputeNetwork> {
+        let store = self.store.read().unwrap();
+        lookup(&store.networks_by_name, name, &store.networks)
+            .into_iter()
+            .next()
+    }
+
+    pub fn namespaces(&self) -> Vec<HostComputeNamespace> {
+        let store = self.store.read().unwrap();
+        store.namespaces.values().cloned().collect()
+    }
+
+    pub fn namespace(&self, id: &str) -> Option<HostComputeNamespace> {
+        let store = self.store.read().unwrap();
+        store.namespaces.get(&normalize(id)).cloned()
+    }
+
+    pub fn endpoints(&self) -> Vec<HostComputeEndpoint> {
+        let store = self.store.read().unwrap();
+        store.endpoints.values().cloned().collect()
+    }
+
+    pub fn endpoint(&self, id: &str) -> Option<HostComputeEndpoint> {
+        let store = self.store.read().unwrap();
+        store.endpoints.get(&normalize(id)).cloned()
+    }
+
+    /// Endpoint names are not unique, e.g. remote endpoints on several networks.
+    pub fn endpoints_by_name(&self, name: &str) -> Vec<HostComputeEndpoint> {
+        let store = self.store.read().unwrap();
+        lookup(&store.endpoints_by_name, name, &store.endpoints)
+    }
+
+    pub fn endpoints_in_network(&self, network_id: &str) -> Vec<HostComputeEndpoint> {
+        let store = self.store.read().unwrap();
+        lookup(
+            &store.endpoints_by_network,
+            &normalize(network_id),
+            &store.endpoints,
+        )
+    }
+
+    /// Endpoints with `ip` among their IP configurations. More than one only
+    /// when networks with overlapping subnets exist.
+    pub fn endpoints_by_ip(&self, ip: &IpAddr) -> Vec<HostComputeEndpoint> {
+        let store = self.store.read().unwrap();
+        lookup(&store.endpoints_by_ip, ip, &store.endpoints)
+    }
+
+    pub fn load_balancers(&self) -> Vec<HostComputeLoadBalancer> {
+        let store = self.store.read().unwrap();
+        store.load_balancers.values().cloned().collect()
+    }
+
+    pub fn load_balancer(&self, id: &str) -> Option<HostComputeLoadBalancer> {
+        let store = self.store.read().unwrap();
+        store.load_balancers.get(&normalize(id)).cloned()
+    }
+
+    /// Load balancers with `vip` among their frontend VIPs.
+    pub fn load_balancers_by_vip(&self, vip: &IpAddr) -> Vec<HostComputeLoadBalancer> {
+        let store = self.store.read().unwrap();
+        lookup(&store.load_balancers_by_vip, vip, &store.load_balancers)
+    }
+}
+
+impl Store {
+    // Whether `listing` started after the one the cached objects of `kind`
+    // came from, in which case it becomes the current one.
+    fn is_newer(&mut self, kind: ObjectKind, listing: u64) -> bool {
+        let current = self.listed.entry(kind).or_default();
+        if listing < *current {
+            log::debug!(
+                "dropping {} listing {}, already have listing {}",
+                kind,
+                listing,
+                current
+            );
+            return false;
+        }
+        *current = listing;
+        true
+    }
+
+    fn replace_networks(
+        &mut self,
+        listing: u64,
+        objects: Vec<HostComputeNetwork>,
+    ) -> Vec<CacheEvent> {
+        if !self.is_newer(ObjectKind::Network, listing) {
+            return vec![];
+        }
+        let changes = replace(&mut self.networks, objects, |n| &n.id);
+
+        self.networks_by_name = index(&self.networks, |n| vec![n.name.clone()]);
+
+        changes.into_iter().map(CacheEvent::Network).collect()
+    }
+
+    fn replace_namespaces(
+        &mut self,
+        listing: u64,
+        objects: Vec<HostComputeNamespace>,
+    ) -> Vec<CacheEvent> {
+        if !self.is_newer(ObjectKind::Namespace, listing) {
+            return vec![];
+        }
+        let changes = replace(&mut self.namespaces, objects, |n| &n.id);
+        changes.into_iter().map(CacheEvent::Namespace).collect()
+    }
+
+    fn replace_endpoints(
+        &mut self,
+        listing: u64,
+        objects: Vec<HostComputeEndpoint>,
+    ) -> Vec<CacheEvent> {
+        if !self.is_newer(ObjectKind::Endpoint, listing) {
+            return vec![];
+        }
+        let changes = replace(&mut self.endpoints, objects, |e| &e.id);
+
+        self.endpoints_by_name = index(&self.endpoints, |e| vec![e.name.clone()]);
+        self.endpoints_by_network = index(&self.endpoints, |e| {
+            vec![normalize(&e.host_compute_network)]
+        });
+        self.endpoints_by_ip = index(&self.endpoints, |e| {
+            e.ip_configurations
+                .iter()
+                .filter_map(|c| c.ip_address.parse().ok())
+                .collect()
+        });
+
+        changes.into_iter().map(CacheEvent::Endpoint).collect()
+    }
+
+    fn replace_load_balancers(
+        &mut self,
+        listing: u64,
+        objects: Vec<HostComputeLoadBalancer>,
+    ) -> Vec<CacheEvent> {
+        if !self.is_newer(ObjectKind::LoadBalancer, listing) {
+            return vec![];
+        }
+        let changes = replace(&mut self.load_balancers, objects, |l| &l.id);
+
+        self.load_balancers_by_vip = index(&self.load_balancers, |l| {
+            l.frontend_vips
+                .iter()
+                .filter_map(|vip| vip.parse().ok())
+                .collect()
+        });
+
+        changes.into_iter().map(CacheEvent::LoadBalancer).collect()
+    }
+}
+
+// Swap the cached objects for a new listing and work out what changed.
+fn replace<T: Clone + PartialEq>(
+    cached: &mut HashMap<String, T>,
+    objects: Vec<T>,
+    id: impl Fn(&T) -> &str,
+) -> Vec<Change<T>> {
+    let mut previous = std::mem::take(cached);
+    let mut changes = vec![];
+
+    for object in objects {
+        let key = normalize(id(&object));
+        match previous.remove(&key) {
+            None => changes.push(Change::Added(object.clone())),
+            Some(old) if old != object => changes.push(Change::Updated {
+                old,
+                new: object.clone(),
+            }),
+            Some(_) => {}
+        }
+        cached.insert(key, object);
+    }
+    changes.extend(previous.into_values().map(Change::Removed));
+
+    changes
+}
+
+fn index<K: std::hash::Hash + Eq, T>(
+    objects: &HashMap<String, T>,
+    keys: impl Fn(&T) -> Vec<K>,
+) -> HashMap<K, Vec<String>> {
+    let mut index: HashMap<K, Vec<String>> = HashMap::new();
+    for (id, object) in objects {
+        for key in keys(object) {
+            index.entry(key).or_default().push(id.clone());
+        }
+    }
+    index
+}
+
+fn lookup<K, Q, T>(index: &HashMap<K, Vec<String>>, key: &Q, objects: &HashMap<String, T>) -> Vec<T>
+where
+    K: std::borrow::Borrow<Q> + std::hash::Hash + Eq,
+    Q: std::hash::Hash + Eq + ?Sized,
+    T: Clone,
+{
+    index
+        .get(key)
+        .into_iter()
+        .flatten()
+        .filter_map(|id| objects.get(id).cloned())
+        .collect()
+}
+
+/// Keeps a [`Cache`] up to date in the background.
+#[cfg(windows)]
+pub struct Informer {
+    cache: Arc<Cache>,
+    supervisor: Option<Supervisor>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[cfg(windows)]
+impl Informer {
+    /// List everything and start watching for changes.
+    ///
+    /// The returned receiver gets an `Added` event for every object of the
+    /// initial listing, followed by the changes found by later refreshes.
+    /// `policy` controls how the notification subscription is re-established
+    /// after the HNS service restarts. Its deadline also bounds how long to
+    /// wait for the first subscription, without one this waits until HNS is
+    /// reachable.
+    pub fn start(
+        resync_period: Duration,
+        policy: RetryPolicy,
+    ) -> Result<(Self, mpsc::Receiver<CacheEvent>)> {
+        let deadline = policy.deadline;
+        let (supervisor, notifications) = Supervisor::start(policy)?;
+        let (events, receiver) = mpsc::channel();
+
+        // The supervisor's first event says the subscription is in place.
+        // Listing after it misses nothing, and it stands for that event's
+        // resync, so the watcher does not list everything a second time.
+        let first = match deadline {
+            Some(deadline) => notifications.recv_timeout(deadline).ok(),
+            None => notifications.recv().ok(),
+        };
+        if first != Some(notify::Event::ResyncRequired) {
+            bail!("failed to subscribe to HNS notifications");
+        }
+
+        let cache = Arc::new(Cache::new());
+        for event in cache.resync()? {
+            let _ = events.send(event);
+        }
+
+        let worker_cache = Arc::clone(&cache);
+        let thread = thread::Builder::new()
+            .name("hcn-informer".to_string())
+            .spawn(move || watch(&worker_cache, notifications, events, resync_period))?;
+
+        Ok((
+            Self {
+                cache,
+                supervisor: Some(supervisor),
+                thread: Some(thread),
+            },
+            receiver,
+        ))
+    }
+
+    pub fn cache(&self) -> &Arc<Cache> {
+        &self.cache
+    }
+
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        // stopping the supervisor closes the notification channel, which ends
+        // the informer thread
+        if let Some(supervisor) = self.supervisor.take() {
+            supervisor.stop();
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for Informer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(windows)]
+const ALL_KINDS: [ObjectKind; 4] = [
+    ObjectKind::Network,
+    ObjectKind::Namespace,
+    ObjectKind::Endpoint,
+    ObjectKind::LoadBalancer,
+];
+
+#[cfg(windows)]
+fn watch(
+    cache: &Cache,
+    notifications: mpsc::Receiver<notify::Event>,
+    events: mpsc::Sender<CacheEvent>,
+    resync_period: Duration,
+) {
+    let mut next_resync = Instant::now() + resync_period;
+    loop {
+        let timeout = next_resync.saturating_duration_since(Instant::now());
+        let kinds: &[ObjectKind] = match notifications.recv_timeout(timeout) {
+            Ok(notify::Event::Notification(n)) => affected_kinds(n.notification_type),
+            Ok(notify::Event::ResyncRequired) | Err(RecvTimeoutError::Timeout) => {
+                next_resync = Instant::now() + resync_period;
+                &ALL_KINDS
+            }
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+
+        for kind in kinds {
+            match cache.refresh(*kind) {
+                // a consumer that stopped listening still gets a fresh cache
+                Ok(changes) => changes.into_iter().for_each(|c| {
+                    let _ = events.send(c);
+                }),
+                Err(e) => log::warn!("failed to refresh cached {}s: {:#}", kind, e),
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+fn affected_kinds(notification_type: NotificationType) -> &'static [ObjectKind] {
+    match notification_type {
+        NotificationType::NetworkCreate => &[ObjectKind::Network],
+        // deleting a network takes its endpoints with it
+        NotificationType::NetworkDelete => &ALL_KINDS,
+        NotificationType::NamespaceCreate | NotificationType::NamespaceDelete => {
+            &[ObjectKind::Namespace]
+        }
+        NotificationType::NetworkEndpointAttached | NotificationType::NetworkEndpointDetached => {
+            &[ObjectKind::Endpoint, ObjectKind::Namespace]
+        }
+        NotificationType::Unknown(_) => &ALL_KINDS,
+        // a resync follows the reconnect
+        NotificationType::ServiceDisconnect => &[],
+        NotificationType::NetworkPreCreate
+        | NotificationType::NetworkPreDelete
+        | NotificationType::GuestNetworkServiceCreate
+        | NotificationType::GuestNetworkServiceDelete
+        | NotificationType::GuestNetworkServiceStateChanged
+        | NotificationType::GuestNetworkServiceInterfaceStateChanged => &[],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(id: &str, name: &str) -> HostComputeNetwork {
+        HostComputeNetwork {
+            id: id.to_string(),
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn endpoint(id: &str, name: &str, network: &str, ip: &str) -> HostComputeEndpoint {
+        HostComputeEndpoint {
+            id: id.to_string(),
+            name: name.to_string(),
+            host_compute_network: network.to_string(),
+            ip_configurations: vec![IpConfig::new(&ip.parse().unwrap(), 24)],
+            ..Default::default()
+        }
+    }
+
+    fn load_balancer(id: &str, vip: &str) -> HostComputeLoadBalancer {
+        HostComputeLoadBalancer {
+            id: id.to_string(),
+            frontend_vips: vec![vip.to_string()],
+            ..Default::default()
+        }
+    }
+
+    fn snapshot() -> ObjectGraph {
+        ObjectGraph {
+            networks: vec![network("{AAAA}", "nat"), network("bbbb", "overlay")],
+            endpoints: vec![
+                endpoint("e1", "pod-a", "aaaa", "10.0.0.2"),
+                endpoint("e2", "remote", "AAAA", "10.0.0.3"),
+                endpoint("e3", "remote", "bbbb", "10.0.0.3"),
+            ],
+            load_balancers: vec![load_balancer("lb1", "10.96.0.10")],
+            ..Default::default()
+        }
+    }
+
+    fn ids<T>(objects: Vec<T>, id: impl Fn(&T) -> &str) -> Vec<String> {
+        let mut ids: Vec<String> = objects.iter().map(|o| id(o).to_string()).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn lookups() {
+        let cache = Cache::new();
+        cache.apply(snapshot());
+
+        // IDs match whatever their case and braces
+        assert_eq!(cache.network("{aaaa}").unwrap().name, "nat");
+        assert_eq!(cache.network("AAAA").unwrap().name, "nat");
+        assert_eq!(cache.network_by_name("overlay").unwrap().id, "bbbb");
+        assert!(cache.network_by_name("l2bridge").is_none());
+
+        assert_eq!(
+            ids(cache.endpoints_in_network("{Aaaa}"), |e| &e.id),
+            vec!["e1", "e2"]
+        );
+        assert_eq!(
+            ids(cache.endpoints_by_name("remote"), |e| &e.id),
+            vec!["e2", "e3"]
+        );
+        let shared_ip = "10.0.0.3".parse().unwrap();
+        assert_eq!(
+            ids(cache.endpoints_by_ip(&shared_ip), |e| &e.id),
+            vec!["e2", "e3"]
+        );
+        assert!(cache
+            .endpoints_by_ip(&"10.0.0.4".parse().unwrap())
+            .is_empty());
+        assert_eq!(
+            ids(
+                cache.load_balancers_by_vip(&"10.96.0.10".parse().unwrap()),
+                |l| &l.id
+            ),
+            vec!["lb1"]
+        );
+    }
+
+    #[test]
+    fn changes_are_reported() {
+        let cache = Cache::new();
+        let events = cache.apply(snapshot());
+        assert_eq!(events.len(), 6);
+        assert!(events.iter().all(|e| matches!(
+            e,
+            CacheEvent::Network(Change::Added(_))
+                | CacheEvent::Endpoint(Change::Added(_))
+                | CacheEvent::LoadBalancer(Change::Added(_))
+        )));
+
+        // nothing changed
+        assert!(cache.apply(snapshot()).is_empty());
+
+        let mut next = snapshot();
+        next.networks[1].name = "vxlan".to_string();
+        next.endpoints.remove(0);
+        let events = cache.apply(next);
+        assert_eq!(events.len(), 2);
+        assert!(events.contains(&CacheEvent::Network(Change::Updated {
+            old: network("bbbb", "overlay"),
+            new: network("bbbb", "vxlan"),
+        })));
+        assert!(
+            events.contains(&CacheEvent::Endpoint(Change::Removed(endpoint(
+                "e1", "pod-a", "aaaa", "10.0.0.2"
+            ))))
+        );
+
+        // the indexes follow
+        assert!(cache.network_by_name("overlay").is_none());
+        assert_eq!(cache.network_by_name("vxlan").unwrap().id, "bbbb");
+        assert_eq!(
+            ids(cache.endpoints_in_network("aaaa"), |e| &e.id),
+            vec!["e2"]
+        );
+    }
+
+    #[test]
+    fn listings_that_started_earlier_are_dropped() {
+        let cache = Cache::new();
+        let older = cache.start_listing();
+        let newer = cache.start_listing();
+
+        // the later listing finishes first
+        let mut current = snapshot();
+        current.networks.truncate(1);
+        assert_eq!(cache.apply_listing(newer, current).len(), 5);
+
+        assert!(cache.apply_listing(older, snapshot()).is_empty());
+        assert!(cache.network("bbbb").is_none());
+
+        // kinds are tracked separately: an older listing of one kind is
+        // dropped even if it would be the newest of another
+        let endpoints = cache.start_listing();
+        let networks = cache.start_listing();
+        let mut store = cache.store.write().unwrap();
+        assert_eq!(
+            store.replace_networks(networks, snapshot().networks).len(),
+            1
+        );
+        assert_eq!(store.replace_endpoints(endpoints, vec![]).len(), 3);
+        assert!(store.replace_networks(endpoints, vec![]).is_empty());
+        assert_eq!(store.networks.len(), 2);
+    }
+}
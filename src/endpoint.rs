@@ -0,0 +1,45 @@
+//! High level operations on HNS endpoints.
+
+use crate::api;
+use crate::modify;
+use crate::object::OwnedHandle;
+use crate::schema::*;
+use anyhow::{Context, Result};
+
+/// Validate and create an endpoint on a network, returning it as HNS reports
+/// it. The network's handle is closed again before returning.
+pub fn create(network_id: &str, endpoint: &HostComputeEndpoint) -> Result<HostComputeEndpoint> {
+    endpoint.validate()?;
+    let settings = serde_json::to_string(endpoint)?;
+    let network = OwnedHandle::<HostComputeNetwork>::open(network_id)?;
+
+    crate::create(&endpoint.id, |id| {
+        api::create_endpoint(network.as_raw(), id, &settings)
+    })
+    .with_context(|| {
+        format!(
+            "failed to create endpoint {} on network {}",
+            endpoint.name, network_id
+        )
+    })
+}
+
+/// Add, update or remove policies on an existing endpoint.
+pub fn modify_policies(
+    endpoint_id: &str,
+    request_type: RequestType,
+    policies: Vec<EndpointPolicy>,
+) -> Result<()> {
+    let request = ModifyEndpointSettingRequest {
+        resource_type: EndpointResourceType::Policy,
+        request_type,
+        settings: Some(serde_json::to_value(PolicyEndpointRequest {
+            policies,
+            ..Default::default()
+        })?),
+        extra_fields: ExtraFields::new(),
+    };
+    let request = serde_json::to_string(&request)?;
+
+    modify::<HostComputeEndpoint>(endpoint_id, &request)
+}
@@ -0,0 +1,420 @@
+//! Garbage collection of HNS objects left behind by crashed or misbehaving clients.
+//!
+//! A scan walks every namespace, endpoint and load balancer, builds the references
+//! between them and reports the objects nothing points at any more. Deleting is a
+//! separate step so the caller can decide what to keep, for example endpoints whose
+//! name still matches a live pod.
+
+#[cfg(windows)]
+use crate::delete;
+use crate::graph::{namespace_endpoint_ids, normalize};
+use crate::graph::{ObjectGraph, ObjectKind};
+use crate::schema::*;
+#[cfg(windows)]
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// Why an object was considered orphaned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OrphanReason {
+    /// The endpoint is not attached to a namespace, or its namespace no longer exists.
+    EndpointWithoutNamespace,
+    /// None of the endpoints attached to the namespace exist any more.
+    NamespaceWithoutEndpoints,
+    /// None of the load balancer's backend endpoints exist any more.
+    DanglingEndpoints { missing: Vec<String> },
+    /// Some of the load balancer's backend endpoints no longer exist. It still
+    /// serves the `remaining` ones, so it is reported but never swept.
+    StaleBackends {
+        missing: Vec<String>,
+        remaining: usize,
+    },
+}
+
+impl OrphanReason {
+    /// False for objects that are still in use and must not be deleted.
+    pub fn is_deletable(&self) -> bool {
+        !matches!(self, OrphanReason::StaleBackends { .. })
+    }
+}
+
+/// An object that nothing references any more.
+#[derive(Debug, Clone)]
+pub struct Orphan {
+    pub kind: ObjectKind,
+    pub id: String,
+    pub name: String,
+    pub reason: OrphanReason,
+    /// Best guess at who created the object: the container ID for endpoints named
+    /// `<container>_<network>` or attached containers for namespaces, and the
+    /// frontend VIP for load balancers.
+    pub owner_hint: Option<String>,
+    /// How long this collector has seen the object orphaned. HNS does not record
+    /// creation times, so this is zero the first time an object is reported.
+    pub age: Duration,
+}
+
+/// Outcome of [`Collector::sweep`].
+#[derive(Debug, Default)]
+pub struct SweepReport {
+    pub deleted: Vec<Orphan>,
+    pub kept: Vec<Orphan>,
+    pub failed: Vec<(Orphan, anyhow::Error)>,
+}
+
+/// Finds and removes orphaned objects.
+///
+/// Keep the collector around between scans: it remembers when an object was
+/// first seen orphaned, which is what [`Orphan::age`] is computed from. That
+/// lets callers skip objects that are simply mid-creation, such as a namespace
+/// whose endpoint has not been attached yet.
+#[derive(Debug, Default)]
+pub struct Collector {
+    first_seen: HashMap<(ObjectKind, String), Instant>,
+}
+
+impl Collector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Query HNS and report every orphaned namespace, endpoint and load balancer.
+    #[cfg(windows)]
+    pub fn scan(&mut self) -> Result<Vec<Orphan>> {
+        let graph = ObjectGraph::load()?;
+        Ok(self.find_orphans(&graph))
+    }
+
+    /// Report orphans in an already loaded snapshot.
+    pub fn find_orphans(&mut self, graph: &ObjectGraph) -> Vec<Orphan> {
+        let namespaces = &graph.namespaces;
+        let endpoints = &graph.endpoints;
+        let load_balancers = &graph.load_balancers;
+
+        let namespace_ids: HashSet<String> = namespaces.iter().map(|n| normalize(&n.id)).collect();
+        let endpoint_ids: HashSet<String> = endpoints.iter().map(|e| normalize(&e.id)).collect();
+
+        // endpoints referenced from the namespace side
+        let attached: HashSet<String> =
+            namespaces.iter().flat_map(namespace_endpoint_ids).collect();
+
+        let mut orphans = vec![];
+
+        for endpoint in endpoints.iter().filter(|e| !e.is_remote()) {
+            let id = normalize(&endpoint.id);
+            let in_namespace = namespace_ids.contains(&normalize(&endpoint.host_compute_namespace));
+            if !in_namespace && !attached.contains(&id) {
+                orphans.push(Orphan {
+                    kind: ObjectKind::Endpoint,
+                    id: endpoint.id.clone(),
+                    name: endpoint.name.clone(),
+                    reason: OrphanReason::EndpointWithoutNamespace,
+                    owner_hint: endpoint
+                        .name
+                        .split_once('_')
+                        .map(|(container, _)| container.to_string()),
+                    age: Duration::ZERO,
+                });
+            }
+        }
+
+        for namespace in namespaces {
+            if matches!(
+                namespace.namespace_type,
+                Some(NamespaceType::HostDefault) | Some(NamespaceType::GuestDefault)
+            ) {
+                continue;
+            }
+
+            let live = namespace_endpoint_ids(namespace)
+                .iter()
+                .any(|id| endpoint_ids.contains(id));
+            if !live {
+                orphans.push(Orphan {
+                    kind: ObjectKind::Namespace,
+                    id: namespace.id.clone(),
+                    name: String::new(),
+                    reason: OrphanReason::NamespaceWithoutEndpoints,
+                    owner_hint: namespace.containers().first().map(|c| c.to_string()),
+                    age: Duration::ZERO,
+                });
+            }
+        }
+
+        for load_balancer in load_balancers {
+            let (remaining, missing): (Vec<&String>, Vec<&String>) = load_balancer
+                .host_compute_endpoints
+                .iter()
+                .partition(|id| endpoint_ids.contains(&normalize(id)));
+            if missing.is_empty() {
+                continue;
+            }
+            let missing = missing.into_iter().cloned().collect();
+            let reason = match remaining.len() {
+                0 => OrphanReason::DanglingEndpoints { missing },
+                remaining => OrphanReason::StaleBackends { missing, remaining },
+            };
+            orphans.push(Orphan {
+                kind: ObjectKind::LoadBalancer,
+                id: load_balancer.id.clone(),
+                name: String::new(),
+                reason,
+                owner_hint: load_balancer.frontend_vips.first().cloned(),
+                age: Duration::ZERO,
+            });
+        }
+
+        self.track_age(&mut orphans);
+        orphans
+    }
+
+    /// Delete the orphans for which `should_delete` returns true. Orphans
+    /// that are still in use, see [`OrphanReason::is_deletable`], are always
+    /// kept and `should_delete` is not asked about them.
+    ///
+    /// Load balancers go first, then endpoints, then namespaces, so that nothing
+    /// is deleted while another orphan still references it.
+    #[cfg(windows)]
+    pub fn sweep<F>(&mut self, orphans: Vec<Orphan>, mut should_delete: F) -> SweepReport
+    where
+        F: FnMut(&Orphan) -> bool,
+    {
+        let mut orphans = orphans;
+        orphans.sort_by_key(|o| match o.kind {
+            ObjectKind::LoadBalancer => 0,
+            ObjectKind::Endpoint => 1,
+            ObjectKind::Namespace => 2,
+            ObjectKind::Network => 3,
+        });
+
+        let mut report = SweepReport::default();
+        for orphan in orphans {
+            if !orphan.reason.is_deletable() || !should_delete(&orphan) {
+                report.kept.push(orphan);
+                continue;
+            }
+
+            let id = orphan.id.as_str();
+            let result = match orphan.kind {
+                ObjectKind::LoadBalancer => delete::<HostComputeLoadBalancer>(id),
+                ObjectKind::Endpoint => delete::<HostComputeEndpoint>(id),
+                ObjectKind::Namespace => delete::<HostComputeNamespace>(id),
+                ObjectKind::Network => delete::<HostComputeNetwork>(id),
+            };
+
+            match result {
+                Ok(()) => {
+                    log::info!("deleted orphaned {:?} {}", orphan.kind, orphan.id);
+                    self.first_seen
+                        .remove(&(orphan.kind, normalize(&orphan.id)));
+                    report.deleted.push(orphan);
+                }
+                Err(e) => {
+                    log::warn!(
+                        "failed to delete orphaned {:?} {}: {}",
+                        orphan.kind,
+                        orphan.id,
+                        e
+                    );
+                    report.failed.push((orphan, e));
+                }
+            }
+        }
+
+        report
+    }
+
+    fn track_age(&mut self, orphans: &mut [Orphan]) {
+        let now = Instant::now();
+        let current: HashSet<(ObjectKind, String)> =
+            orphans.iter().map(|o| (o.kind, normalize(&o.id))).collect();
+
+        // objects that were fixed or deleted start over if they become orphans again
+        self.first_seen.retain(|key, _| current.contains(key));
+
+        for orphan in orphans.iter_mut() {
+            let first_seen = *self
+                .first_seen
+                .entry((orphan.kind, normalize(&orphan.id)))
+                .or_insert(now);
+            orphan.age = now - first_seen;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(id: &str, namespace: &str) -> HostComputeEndpoint {
+        HostComputeEndpoint {
+            id: id.to_string(),
+            name: format!("{}-container_nat", id),
+            host_compute_namespace: namespace.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn namespace(id: &str, endpoints: &[&str]) -> HostComputeNamespace {
+        HostComputeNamespace {
+            id: id.to_string(),
+            resources: endpoints
+                .iter()
+                .map(|e| {
+                    NamespaceResource::Endpoint(NamespaceResourceEndpoint {
+                        id: e.to_string(),
+                        ..Default::default()
+                    })
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn load_balancer(id: &str, backends: &[&str]) -> HostComputeLoadBalancer {
+        HostComputeLoadBalancer {
+            id: id.to_string(),
+            host_compute_endpoints: backends.iter().map(|b| b.to_string()).collect(),
+            frontend_vips: vec!["10.96.0.10".to_string()],
+            ..Default::default()
+        }
+    }
+
+    fn reasons(orphans: &[Orphan]) -> Vec<(&str, &OrphanReason)> {
+        orphans.iter().map(|o| (o.id.as_str(), &o.reason)).collect()
+    }
+
+    #[test]
+    fn endpoints_without_namespace() {
+        let graph = ObjectGraph {
+            namespaces: vec![namespace("ns-1", &["EP-1"])],
+            endpoints: vec![
+                // attached from the namespace side only, in another case
+                endpoint("ep-1", ""),
+                endpoint("ep-2", "ns-1"),
+                endpoint("ep-3", "ns-gone"),
+                HostComputeEndpoint {
+                    flags: Some(ENDPOINT_FLAGS_REMOTE_ENDPOINT),
+                    ..endpoint("ep-remote", "")
+                },
+            ],
+            ..Default::default()
+        };
+        let orphans = Collector::new().find_orphans(&graph);
+
+        assert_eq!(
+            reasons(&orphans),
+            vec![("ep-3", &OrphanReason::EndpointWithoutNamespace)]
+        );
+        assert_eq!(orphans[0].owner_hint.as_deref(), Some("ep-3-container"));
+    }
+
+    #[test]
+    fn namespaces_without_endpoints() {
+        let graph = ObjectGraph {
+            namespaces: vec![
+                namespace("ns-live", &["ep-1", "ep-gone"]),
+                namespace("ns-gone", &["ep-gone"]),
+                namespace("ns-empty", &[]),
+                HostComputeNamespace {
+                    namespace_type: Some(NamespaceType::HostDefault),
+                    ..namespace("ns-host", &[])
+                },
+            ],
+            endpoints: vec![endpoint("ep-1", "ns-live")],
+            ..Default::default()
+        };
+        let orphans = Collector::new().find_orphans(&graph);
+
+        assert_eq!(
+            reasons(&orphans),
+            vec![
+                ("ns-gone", &OrphanReason::NamespaceWithoutEndpoints),
+                ("ns-empty", &OrphanReason::NamespaceWithoutEndpoints),
+            ]
+        );
+    }
+
+    #[test]
+    fn load_balancers_with_missing_backends() {
+        let graph = ObjectGraph {
+            namespaces: vec![namespace("ns-1", &["ep-1", "ep-2"])],
+            endpoints: vec![endpoint("ep-1", "ns-1"), endpoint("ep-2", "ns-1")],
+            load_balancers: vec![
+                load_balancer("lb-live", &["ep-1", "{EP-2}"]),
+                load_balancer("lb-partial", &["ep-1", "ep-gone"]),
+                load_balancer("lb-dangling", &["ep-gone", "ep-gone-too"]),
+            ],
+            ..Default::default()
+        };
+        let orphans = Collector::new().find_orphans(&graph);
+
+        assert_eq!(
+            reasons(&orphans),
+            vec![
+                (
+                    "lb-partial",
+                    &OrphanReason::StaleBackends {
+                        missing: vec!["ep-gone".to_string()],
+                        remaining: 1,
+                    }
+                ),
+                (
+                    "lb-dangling",
+                    &OrphanReason::DanglingEndpoints {
+                        missing: vec!["ep-gone".to_string(), "ep-gone-too".to_string()],
+                    }
+                ),
+            ]
+        );
+        assert!(!orphans[0].reason.is_deletable());
+        assert!(orphans[1].reason.is_deletable());
+        assert_eq!(orphans[1].owner_hint.as_deref(), Some("10.96.0.10"));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn sweep_keeps_load_balancers_in_use() {
+        let graph = ObjectGraph {
+            endpoints: vec![endpoint("ep-1", "")],
+            load_balancers: vec![load_balancer("lb-partial", &["ep-1", "ep-gone"])],
+            ..Default::default()
+        };
+        let mut collector = Collector::new();
+        let orphans: Vec<Orphan> = collector
+            .find_orphans(&graph)
+            .into_iter()
+            .filter(|o| o.kind == ObjectKind::LoadBalancer)
+            .collect();
+
+        let report = collector.sweep(orphans, |_| {
+            panic!("asked to delete a load balancer in use")
+        });
+        assert_eq!(report.kept.len(), 1);
+        assert!(report.deleted.is_empty() && report.failed.is_empty());
+    }
+
+    #[test]
+    fn age_restarts_once_fixed() {
+        let orphaned = ObjectGraph {
+            endpoints: vec![endpoint("ep-1", "")],
+            ..Default::default()
+        };
+        let fixed = ObjectGraph {
+            namespaces: vec![namespace("ns-1", &["ep-1"])],
+            endpoints: vec![endpoint("ep-1", "")],
+            ..Default::default()
+        };
+        let mut collector = Collector::new();
+
+        collector.find_orphans(&orphaned);
+        let first_seen = collector.first_seen.clone();
+        assert_eq!(first_seen.len(), 1);
+        collector.find_orphans(&orphaned);
+        assert_eq!(collector.first_seen, first_seen);
+
+        assert!(collector.find_orphans(&fixed).is_empty());
+        assert!(collector.first_seen.is_empty());
+    }
+}
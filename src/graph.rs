@@ -0,0 +1,558 @@
+//! Relationships between networks, endpoints, namespaces and load balancers.
+//!
+//! HNS refuses to delete an object while something still references it, so
+//! tearing down a network means removing its load balancers, endpoints and
+//! namespaces first. [`ObjectGraph`] captures those references from a single
+//! snapshot and [`delete_cascade`] uses it to remove everything bottom-up.
+
+use crate::schema::*;
+#[cfg(windows)]
+use crate::{
+    delete, list_endpoints, list_load_balancers, list_namespaces, list_networks, load_balancer,
+    namespace,
+};
+#[cfg(any(windows, test))]
+use anyhow::Result;
+use std::collections::HashSet;
+use std::fmt;
+
+/// The kinds of object HNS manages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ObjectKind {
+    Network,
+    Namespace,
+    Endpoint,
+    LoadBalancer,
+}
+
+impl fmt::Display for ObjectKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ObjectKind::Network => "network",
+            ObjectKind::Namespace => "namespace",
+            ObjectKind::Endpoint => "endpoint",
+            ObjectKind::LoadBalancer => "load balancer",
+        })
+    }
+}
+
+/// A snapshot of every object in HNS.
+#[derive(Debug, Default)]
+pub struct ObjectGraph {
+    pub networks: Vec<HostComputeNetwork>,
+    pub namespaces: Vec<HostComputeNamespace>,
+    pub endpoints: Vec<HostComputeEndpoint>,
+    pub load_balancers: Vec<HostComputeLoadBalancer>,
+}
+
+/// Everything that has to go before a network can be deleted.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct NetworkDependencies {
+    pub network: String,
+    pub endpoints: Vec<EndpointDependencies>,
+    /// Load balancers whose backends are all on the network. These are
+    /// deleted with it.
+    pub load_balancers: Vec<String>,
+    /// Load balancers that also have backends on other networks. These only
+    /// lose the backends on this network.
+    pub shared_load_balancers: Vec<String>,
+    /// Namespaces whose endpoints all belong to the network. These are deleted
+    /// with it; namespaces shared with other networks only lose the endpoint.
+    pub namespaces: Vec<String>,
+}
+
+/// An endpoint on the network and the objects that reference it.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct EndpointDependencies {
+    pub endpoint: String,
+    pub namespaces: Vec<String>,
+    pub load_balancers: Vec<String>,
+}
+
+/// Outcome of [`delete_cascade`].
+#[derive(Debug, Default)]
+pub struct TeardownReport {
+    pub removed: Vec<(ObjectKind, String)>,
+    /// Objects kept but changed, e.g. load balancers shared with other
+    /// networks that lost the backends on this one.
+    pub modified: Vec<(ObjectKind, String)>,
+    pub failed: Vec<(ObjectKind, String, anyhow::Error)>,
+}
+
+impl TeardownReport {
+    /// True when every object, including the network itself, was removed.
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    #[cfg(any(windows, test))]
+    fn record(&mut self, kind: ObjectKind, id: &str, result: Result<()>) {
+        match result {
+            Ok(()) => {
+                log::info!("removed {:?} {}", kind, id);
+                self.removed.push((kind, id.to_string()));
+            }
+            Err(e) => {
+                log::warn!("failed to remove {:?} {}: {}", kind, id, e);
+                self.failed.push((kind, id.to_string(), e));
+            }
+        }
+    }
+}
+
+impl ObjectGraph {
+    /// Query HNS for every network, namespace, endpoint and load balancer.
+    #[cfg(windows)]
+    pub fn load() -> Result<Self> {
+        Ok(Self {
+            networks: list_networks()?,
+            namespaces: list_namespaces()?,
+            endpoints: list_endpoints()?,
+            load_balancers: list_load_balancers()?,
+        })
+    }
+
+    pub fn network(&self, id: &str) -> Option<&HostComputeNetwork> {
+        self.networks.iter().find(|n| same_id(&n.id, id))
+    }
+
+    pub fn namespace(&self, id: &str) -> Option<&HostComputeNamespace> {
+        self.namespaces.iter().find(|n| same_id(&n.id, id))
+    }
+
+    pub fn endpoint(&self, id: &str) -> Option<&HostComputeEndpoint> {
+        self.endpoints.iter().find(|e| same_id(&e.id, id))
+    }
+
+    pub fn load_balancer(&self, id: &str) -> Option<&HostComputeLoadBalancer> {
+        self.load_balancers.iter().find(|l| same_id(&l.id, id))
+    }
+
+    /// Endpoints created on the network, local and remote.
+    pub fn endpoints_in_network<'a>(
+        &'a self,
+        network_id: &'a str,
+    ) -> impl Iterator<Item = &'a HostComputeEndpoint> + 'a {
+        self.endpoints
+            .iter()
+            .filter(move |e| same_id(&e.host_compute_network, network_id))
+    }
+
+    /// Namespaces the endpoint is attached to. The endpoint and the namespace
+    /// each keep their own record of the attachment, both are consulted.
+    pub fn namespaces_of_endpoint(&self, endpoint_id: &str) -> Vec<&HostComputeNamespace> {
+        let recorded = self
+            .endpoint(endpoint_id)
+            .map(|e| e.host_compute_namespace.as_str())
+            .unwrap_or_default();
+
+        self.namespaces
+            .iter()
+            .filter(|n| {
+                same_id(&n.id, recorded)
+                    || namespace_endpoint_ids(n)
+                        .iter()
+                        .any(|id| same_id(id, endpoint_id))
+            })
+            .collect()
+    }
+
+    /// Load balancers that use the endpoint as a backend.
+    pub fn load_balancers_of_endpoint(&self, endpoint_id: &str) -> Vec<&HostComputeLoadBalancer> {
+        self.load_balancers
+            .iter()
+            .filter(|l| {
+                l.host_compute_endpoints
+                    .iter()
+                    .any(|id| same_id(id, endpoint_id))
+            })
+            .collect()
+    }
+
+    /// Compute the objects that depend on the network.
+    pub fn network_dependencies(&self, network_id: &str) -> NetworkDependencies {
+        let endpoints: Vec<EndpointDependencies> = self
+            .endpoints_in_network(network_id)
+            .map(|e| EndpointDependencies {
+                endpoint: e.id.clone(),
+                namespaces: self
+                    .namespaces_of_endpoint(&e.id)
+                    .iter()
+                    .map(|n| n.id.clone())
+                    .collect(),
+                load_balancers: self
+                    .load_balancers_of_endpoint(&e.id)
+                    .iter()
+                    .map(|l| l.id.clone())
+                    .collect(),
+            })
+            .collect();
+
+        let on_network: HashSet<String> =
+            endpoints.iter().map(|e| normalize(&e.endpoint)).collect();
+
+        let mut load_balancers: Vec<String> = vec![];
+        let mut shared_load_balancers: Vec<String> = vec![];
+        let mut namespaces: Vec<String> = vec![];
+        for endpoint in &endpoints {
+            for id in &endpoint.load_balancers {
+                let seen = load_balancers
+                    .iter()
+                    .chain(&shared_load_balancers)
+                    .any(|l| same_id(l, id));
+                if seen {
+                    continue;
+                }
+                let owned = self.load_balancer(id).is_some_and(|l| {
+                    l.host_compute_endpoints
+                        .iter()
+                        .all(|e| on_network.contains(&normalize(e)))
+                });
+                if owned {
+                    load_balancers.push(id.clone());
+                } else {
+                    shared_load_balancers.push(id.clone());
+                }
+            }
+            for id in &endpoint.namespaces {
+                if namespaces.iter().any(|n| same_id(n, id)) {
+                    continue;
+                }
+                let owned = self.namespace(id).is_some_and(|n| {
+                    namespace_endpoint_ids(n)
+                        .iter()
+                        .all(|e| on_network.contains(e))
+                });
+                if owned {
+                    namespaces.push(id.clone());
+                }
+            }
+        }
+
+        NetworkDependencies {
+            network: network_id.to_string(),
+            endpoints,
+            load_balancers,
+            shared_load_balancers,
+            namespaces,
+        }
+    }
+}
+
+/// Delete a network together with everything that references it.
+///
+/// Objects are removed bottom-up: load balancers, then endpoints (after
+/// detaching them from their namespaces and from load balancers shared with
+/// other networks), then the namespaces that only held endpoints of this
+/// network, and finally the network. Failures are collected
+/// rather than aborting, so one stuck object does not leave the rest behind.
+#[cfg(windows)]
+pub fn delete_cascade(network_id: &str) -> Result<TeardownReport> {
+    let graph = ObjectGraph::load()?;
+    Ok(teardown(&graph, network_id, &mut Hns))
+}
+
+// The HNS calls a teardown makes, replaced in tests.
+#[cfg(any(windows, test))]
+trait Teardown {
+    fn delete(&mut self, kind: ObjectKind, id: &str) -> Result<()>;
+    fn detach(&mut self, namespace: &str, endpoint: &str) -> Result<()>;
+    fn remove_backends(
+        &mut self,
+        load_balancer: &HostComputeLoadBalancer,
+        endpoints: &[String],
+    ) -> Result<()>;
+}
+
+#[cfg(windows)]
+struct Hns;
+
+#[cfg(windows)]
+impl Teardown for Hns {
+    fn delete(&mut self, kind: ObjectKind, id: &str) -> Result<()> {
+        match kind {
+            ObjectKind::Network => delete::<HostComputeNetwork>(id),
+            ObjectKind::Namespace => delete::<HostComputeNamespace>(id),
+            ObjectKind::Endpoint => delete::<HostComputeEndpoint>(id),
+            ObjectKind::LoadBalancer => delete::<HostComputeLoadBalancer>(id),
+        }
+    }
+
+    fn detach(&mut self, namespace: &str, endpoint: &str) -> Result<()> {
+        namespace::modify_endpoint(namespace, RequestType::Remove, endpoint)
+    }
+
+    fn remove_backends(
+        &mut self,
+        load_balancer: &HostComputeLoadBalancer,
+        endpoints: &[String],
+    ) -> Result<()> {
+        load_balancer::remove_backends(load_balancer, endpoints).map(drop)
+    }
+}
+
+#[cfg(any(windows, test))]
+fn teardown(graph: &ObjectGraph, network_id: &str, hns: &mut impl Teardown) -> TeardownReport {
+    let dependencies = graph.network_dependencies(network_id);
+    let mut report = TeardownReport::default();
+
+    for id in &dependencies.load_balancers {
+        let result = hns.delete(ObjectKind::LoadBalancer, id);
+        report.record(ObjectKind::LoadBalancer, id, result);
+    }
+
+    let on_network: Vec<String> = dependencies
+        .endpoints
+        .iter()
+        .map(|e| e.endpoint.clone())
+        .collect();
+    for id in &dependencies.shared_load_balancers {
+        let Some(load_balancer) = graph.load_balancer(id) else {
+            continue;
+        };
+        match hns.remove_backends(load_balancer, &on_network) {
+            Ok(()) => {
+                log::info!(
+                    "removed the backends on network {} from load balancer {}",
+                    network_id,
+                    id
+                );
+                report.modified.push((ObjectKind::LoadBalancer, id.clone()));
+            }
+            Err(e) => report
+                .failed
+                .push((ObjectKind::LoadBalancer, id.clone(), e)),
+        }
+    }
+
+    for endpoint in &dependencies.endpoints {
+        for namespace in &endpoint.namespaces {
+            if let Err(e) = hns.detach(namespace, &endpoint.endpoint) {
+                log::warn!(
+                    "failed to detach endpoint {} from namespace {}: {}",
+                    endpoint.endpoint,
+                    namespace,
+                    e
+                );
+            }
+        }
+        let result = hns.delete(ObjectKind::Endpoint, &endpoint.endpoint);
+        report.record(ObjectKind::Endpoint, &endpoint.endpoint, result);
+    }
+
+    for id in &dependencies.namespaces {
+        let result = hns.delete(ObjectKind::Namespace, id);
+        report.record(ObjectKind::Namespace, id, result);
+    }
+
+    let result = hns.delete(ObjectKind::Network, network_id);
+    report.record(ObjectKind::Network, network_id, result);
+
+    report
+}
+
+// HNS is not consistent about the case of the GUIDs it hands back.
+pub(crate) fn normalize(id: &str) -> String {
+    id.trim_matches(|c| c == '{' || c == '}')
+        .to_ascii_lowercase()
+}
+
+pub(crate) fn same_id(a: &str, b: &str) -> bool {
+    !a.is_empty() && normalize(a) == normalize(b)
+}
+
+pub(crate) fn namespace_endpoint_ids(namespace: &HostComputeNamespace) -> Vec<String> {
+    namespace.endpoints().into_iter().map(normalize).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(id: &str, network: &str) -> HostComputeEndpoint {
+        HostComputeEndpoint {
+            id: id.to_string(),
+            host_compute_network: network.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn namespace(id: &str, endpoints: &[&str]) -> HostComputeNamespace {
+        HostComputeNamespace {
+            id: id.to_string(),
+            resources: endpoints
+                .iter()
+                .map(|e| {
+                    NamespaceResource::Endpoint(NamespaceResourceEndpoint {
+                        id: e.to_string(),
+                        ..Default::default()
+                    })
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn load_balancer(id: &str, backends: &[&str]) -> HostComputeLoadBalancer {
+        HostComputeLoadBalancer {
+            id: id.to_string(),
+            host_compute_endpoints: backends.iter().map(|b| b.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn network_dependencies() {
+        let graph = ObjectGraph {
+            endpoints: vec![
+                endpoint("a1", "net-a"),
+                endpoint("a2", "{NET-A}"),
+                endpoint("b1", "net-b"),
+            ],
+            namespaces: vec![
+                namespace("ns-a", &["a1"]),
+                namespace("ns-ab", &["A2", "b1"]),
+            ],
+            load_balancers: vec![
+                load_balancer("lb-a", &["a1", "a2"]),
+                load_balancer("lb-ab", &["a2", "b1"]),
+                load_balancer("lb-b", &["b1"]),
+            ],
+            ..Default::default()
+        };
+        let dependencies = graph.network_dependencies("net-a");
+
+        let endpoints: Vec<&str> = dependencies
+            .endpoints
+            .iter()
+            .map(|e| e.endpoint.as_str())
+            .collect();
+        assert_eq!(endpoints, vec!["a1", "a2"]);
+        assert_eq!(dependencies.endpoints[1].namespaces, vec!["ns-ab"]);
+        assert_eq!(
+            dependencies.endpoints[1].load_balancers,
+            vec!["lb-a", "lb-ab"]
+        );
+        assert_eq!(dependencies.load_balancers, vec!["lb-a"]);
+        assert_eq!(dependencies.shared_load_balancers, vec!["lb-ab"]);
+        assert_eq!(dependencies.namespaces, vec!["ns-a"]);
+    }
+
+    #[test]
+    fn network_without_endpoints_has_no_dependencies() {
+        let graph = ObjectGraph {
+            endpoints: vec![endpoint("b1", "net-b")],
+            load_balancers: vec![load_balancer("lb-b", &["b1"])],
+            ..Default::default()
+        };
+        assert_eq!(
+            graph.network_dependencies("net-a"),
+            NetworkDependencies {
+                network: "net-a".to_string(),
+                ..Default::default()
+            }
+        );
+    }
+
+    // Records the calls, failing those on the given ids.
+    #[derive(Default)]
+    struct FakeHns {
+        calls: Vec<String>,
+        failing: Vec<&'static str>,
+    }
+
+    impl FakeHns {
+        fn call(&mut self, call: String, id: &str) -> Result<()> {
+            self.calls.push(call);
+            match self.failing.contains(&id) {
+                true => anyhow::bail!("{} failed", id),
+                false => Ok(()),
+            }
+        }
+    }
+
+    impl Teardown for FakeHns {
+        fn delete(&mut self, kind: ObjectKind, id: &str) -> Result<()> {
+            self.call(format!("delete {} {}", kind, id), id)
+        }
+
+        fn detach(&mut self, namespace: &str, endpoint: &str) -> Result<()> {
+            self.call(format!("detach {} from {}", endpoint, namespace), endpoint)
+        }
+
+        fn remove_backends(
+            &mut self,
+            load_balancer: &HostComputeLoadBalancer,
+            endpoints: &[String],
+        ) -> Result<()> {
+            let call = format!("remove {} from {}", endpoints.join(","), load_balancer.id);
+            self.call(call, &load_balancer.id)
+        }
+    }
+
+    fn teardown_graph() -> ObjectGraph {
+        ObjectGraph {
+            endpoints: vec![
+                endpoint("a1", "net-a"),
+                endpoint("a2", "net-a"),
+                endpoint("b1", "net-b"),
+            ],
+            namespaces: vec![
+                namespace("ns-a", &["a1"]),
+                namespace("ns-ab", &["a2", "b1"]),
+            ],
+            load_balancers: vec![
+                load_balancer("lb-a", &["a1"]),
+                load_balancer("lb-ab", &["a2", "b1"]),
+            ],
+            ..Default::default()
+        }
+    }
+
+    fn ids(objects: &[(ObjectKind, String)]) -> Vec<&str> {
+        objects.iter().map(|(_, id)| id.as_str()).collect()
+    }
+
+    #[test]
+    fn teardown_is_bottom_up() {
+        let mut hns = FakeHns::default();
+        let report = teardown(&teardown_graph(), "net-a", &mut hns);
+
+        assert_eq!(
+            hns.calls,
+            vec![
+                "delete load balancer lb-a",
+                "remove a1,a2 from lb-ab",
+                "detach a1 from ns-a",
+                "delete endpoint a1",
+                "detach a2 from ns-ab",
+                "delete endpoint a2",
+                "delete namespace ns-a",
+                "delete network net-a",
+            ]
+        );
+        assert!(report.is_complete());
+        assert_eq!(
+            ids(&report.removed),
+            vec!["lb-a", "a1", "a2", "ns-a", "net-a"]
+        );
+        assert_eq!(
+            report.modified,
+            vec![(ObjectKind::LoadBalancer, "lb-ab".to_string())]
+        );
+    }
+
+    #[test]
+    fn teardown_collects_failures() {
+        let mut hns = FakeHns {
+            failing: vec!["lb-ab", "a1"],
+            ..Default::default()
+        };
+        let report = teardown(&teardown_graph(), "net-a", &mut hns);
+
+        let failed: Vec<&str> = report.failed.iter().map(|(_, id, _)| id.as_str()).collect();
+        assert_eq!(failed, vec!["lb-ab", "a1"]);
+        assert!(report.modified.is_empty());
+        assert_eq!(ids(&report.removed), vec!["lb-a", "a2", "ns-a", "net-a"]);
+    }
+}
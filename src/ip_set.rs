@@ -0,0 +1,488 @@
+//! IP sets, network wide sets of addresses that ACLs match on by id.
+//!
+//! A nested set's members are other sets, which have to exist when it is
+//! added and as long as it refers to them. [`IpSetBatch`] collects changes to
+//! many sets and sends them in at most three modify calls that keep this
+//! order: one adding sets, one updating them and one removing them. Each call
+//! carries every set of its kind; HNS documents no limit on the size of a
+//! request, a batch can be given one with [`IpSetBatch::max_sets_per_request`].
+
+#[cfg(windows)]
+use crate::network::modify_policies;
+use crate::prefix::IpPrefix;
+use crate::schema::*;
+use anyhow::{bail, Context, Result};
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IpSetMembers {
+    Addresses(BTreeSet<IpPrefix>),
+    /// The ids of other sets.
+    Sets(BTreeSet<String>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IpSet {
+    pub id: String,
+    pub name: String,
+    pub members: IpSetMembers,
+}
+
+impl IpSet {
+    pub fn addresses(id: &str, name: &str, members: impl IntoIterator<Item = IpPrefix>) -> Self {
+        Self {
+            id: id.to_string(),
+            name: name.to_string(),
+            members: IpSetMembers::Addresses(members.into_iter().collect()),
+        }
+    }
+
+    pub fn nested<'a>(id: &str, name: &str, sets: impl IntoIterator<Item = &'a str>) -> Self {
+        Self {
+            id: id.to_string(),
+            name: name.to_string(),
+            members: IpSetMembers::Sets(sets.into_iter().map(|s| s.to_string()).collect()),
+        }
+    }
+
+    pub fn set_type(&self) -> SetPolicyType {
+        match self.members {
+            IpSetMembers::Addresses(_) => SetPolicyType::IpSet,
+            IpSetMembers::Sets(_) => SetPolicyType::NestedIpSet,
+        }
+    }
+
+    /// Parse a `SetPolicy` network policy.
+    pub fn from_policy(policy: &NetworkPolicy) -> Result<Self> {
+        if policy.network_type != NetworkPolicyType::SetPolicy {
+            bail!("{:?} policy is not a set policy", policy.network_type);
+        }
+        let setting: SetPolicySetting = policy.settings_as()?;
+        let values = setting
+            .values
+            .split(',')
+            .map(str::trim)
+            .filter(|v| !v.is_empty());
+        let members = match setting.policy_type {
+            SetPolicyType::IpSet => IpSetMembers::Addresses(
+                values
+                    .map(|v| v.parse())
+                    .collect::<Result<_>>()
+                    .with_context(|| format!("invalid members of IP set {}", setting.id))?,
+            ),
+            SetPolicyType::NestedIpSet => IpSetMembers::Sets(values.map(String::from).collect()),
+        };
+        Ok(Self {
+            id: setting.id,
+            name: setting.name,
+            members,
+        })
+    }
+
+    pub fn policy(&self) -> Result<NetworkPolicy> {
+        self.check()?;
+        let values: Vec<String> = match &self.members {
+            IpSetMembers::Addresses(addresses) => addresses.iter().map(|a| a.to_string()).collect(),
+            IpSetMembers::Sets(sets) => sets.iter().cloned().collect(),
+        };
+        let setting = SetPolicySetting {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            policy_type: self.set_type(),
+            values: values.join(","),
+            extra_fields: ExtraFields::new(),
+        };
+        Ok(NetworkPolicy::new(NetworkPolicyType::SetPolicy, &setting)?)
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.id.is_empty() {
+            bail!("IP set {} has no id", self.name);
+        }
+        if let IpSetMembers::Sets(sets) = &self.members {
+            if sets.contains(&self.id) {
+                bail!("IP set {} contains itself", self.id);
+            }
+            if sets.iter().any(|s| s.is_empty() || s.contains(',')) {
+                bail!("IP set {} has an invalid member set id", self.id);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The IP sets of a network.
+pub fn ip_sets(network: &HostComputeNetwork) -> Result<Vec<IpSet>> {
+    network
+        .policies
+        .iter()
+        .filter(|p| p.network_type == NetworkPolicyType::SetPolicy)
+        .map(IpSet::from_policy)
+        .collect()
+}
+
+#[cfg(windows)]
+pub fn add_ip_set(network_id: &str, set: &IpSet) -> Result<()> {
+    modify_policies(network_id, RequestType::Add, vec![set.policy()?])
+        .with_context(|| format!("failed to add IP set {}", set.id))
+}
+
+/// Replace the members of an existing set.
+#[cfg(windows)]
+pub fn update_ip_set(network_id: &str, set: &IpSet) -> Result<()> {
+    modify_policies(network_id, RequestType::Update, vec![set.policy()?])
+        .with_context(|| format!("failed to update IP set {}", set.id))
+}
+
+#[cfg(windows)]
+pub fn remove_ip_set(network_id: &str, set: &IpSet) -> Result<()> {
+    modify_policies(network_id, RequestType::Remove, vec![set.policy()?])
+        .with_context(|| format!("failed to remove IP set {}", set.id))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Change {
+    Add(IpSet),
+    Update(IpSet),
+    Remove(IpSet),
+}
+
+/// Changes to the IP sets of a network, applied together.
+///
+/// Changes to the same set are merged as they are made: updating a set added
+/// in the batch adds it with the new members, removing it drops the add, and
+/// only the last update of a set is sent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpSetBatch {
+    changes: BTreeMap<String, Change>,
+    max_sets_per_request: Option<usize>,
+}
+
+impl IpSetBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Split the adds, updates and removes into requests of at most `max`
+    /// sets each, still in the same order.
+    pub fn max_sets_per_request(mut self, max: usize) -> Self {
+        self.max_sets_per_request = Some(max.max(1));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// The number of sets the batch changes.
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    pub fn add(&mut self, set: IpSet) -> Result<()> {
+        set.check()?;
+        let change = match self.changes.get(&set.id) {
+            None => Change::Add(set),
+            // the set stays, only its members change
+            Some(Change::Remove(old)) if old.set_type() == set.set_type() => Change::Update(set),
+            Some(_) => bail!("IP set {} is already changed in this batch", set.id),
+        };
+        self.changes.insert(change.set().id.clone(), change);
+        Ok(())
+    }
+
+    pub fn update(&mut self, set: IpSet) -> Result<()> {
+        set.check()?;
+        let change = match self.changes.get(&set.id) {
+            None | Some(Change::Update(_)) => Change::Update(set),
+            Some(Change::Add(_)) => Change::Add(set),
+            Some(Change::Remove(_)) => bail!("IP set {} is removed in this batch", set.id),
+        };
+        self.changes.insert(change.set().id.clone(), change);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, set: IpSet) -> Result<()> {
+        set.check()?;
+        match self.changes.get(&set.id) {
+            // never sent, so there is nothing to remove
+            Some(Change::Add(_)) => {
+                self.changes.remove(&set.id);
+            }
+            None | Some(Change::Update(_)) => {
+                self.changes.insert(set.id.clone(), Change::Remove(set));
+            }
+            Some(Change::Remove(_)) => bail!("IP set {} is already removed in this batch", set.id),
+        }
+        Ok(())
+    }
+
+    /// The modify requests the batch is sent as, in order. Sets are added
+    /// before the nested sets that refer to them and removed after.
+    pub fn requests(&self) -> Result<Vec<(RequestType, Vec<NetworkPolicy>)>> {
+        let mut add = vec![];
+        let mut update = vec![];
+        let mut remove = vec![];
+        for change in self.changes.values() {
+            match change {
+                Change::Add(set) => add.push(set),
+                Change::Update(set) => update.push(set),
+                Change::Remove(set) => remove.push(set),
+            }
+        }
+        add.sort_by_key(|s| s.set_type() == SetPolicyType::NestedIpSet);
+        remove.sort_by_key(|s| s.set_type() == SetPolicyType::IpSet);
+
+        let mut requests = vec![];
+        for (request_type, sets) in [
+            (RequestType::Add, add),
+            (RequestType::Update, update),
+            (RequestType::Remove, remove),
+        ] {
+            let max = self.max_sets_per_request.unwrap_or(usize::MAX);
+            for chunk in sets.chunks(max) {
+                let policies = chunk.iter().map(|s| s.policy()).collect::<Result<_>>()?;
+                requests.push((request_type, policies));
+            }
+        }
+        Ok(requests)
+    }
+
+    #[cfg(windows)]
+    pub fn apply(&self, network_id: &str) -> Result<()> {
+        for (request_type, policies) in self.requests()? {
+            let count = policies.len();
+            let action = match request_type {
+                RequestType::Add => "add",
+                RequestType::Update => "update",
+                _ => "remove",
+            };
+            modify_policies(network_id, request_type, policies)
+                .with_context(|| format!("failed to {} {} IP sets", action, count))?;
+        }
+        Ok(())
+    }
+}
+
+impl Change {
+    fn set(&self) -> &IpSet {
+        match self {
+            Change::Add(set) | Change::Update(set) | Change::Remove(set) => set,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn pods(first: u32, count: u32) -> impl Iterator<Item = IpPrefix> {
+        (first..first + count).map(|i| IpPrefix::host(IpAddr::V4(Ipv4Addr::from(0x0af4_0000 + i))))
+    }
+
+    fn plain(id: &str) -> IpSet {
+        IpSet::addresses(id, id, pods(0, 1))
+    }
+
+    // The type and id of every set, per request.
+    fn summary(batch: &IpSetBatch) -> Vec<(RequestType, Vec<(SetPolicyType, String)>)> {
+        batch
+            .requests()
+            .unwrap()
+            .iter()
+            .map(|(request_type, policies)| {
+                let sets = policies
+                    .iter()
+                    .map(|p| IpSet::from_policy(p).map(|s| (s.set_type(), s.id)).unwrap())
+                    .collect();
+                (*request_type, sets)
+            })
+            .collect()
+    }
+
+    fn set(set_type: SetPolicyType, id: &str) -> (SetPolicyType, String) {
+        (set_type, id.to_string())
+    }
+
+    #[test]
+    fn batch() {
+        let mut batch = IpSetBatch::new();
+        // pods come and go while the batch is built, only the last state is sent
+        for count in [1000, 2500, 3000] {
+            batch
+                .update(IpSet::addresses(
+                    "ns-shop",
+                    "namespace:shop",
+                    pods(0, count),
+                ))
+                .unwrap();
+        }
+        batch
+            .add(IpSet::addresses("app-web", "app:web", pods(0, 500)))
+            .unwrap();
+        batch
+            .add(IpSet::addresses("app-db", "app:db", pods(3000, 10)))
+            .unwrap();
+        batch
+            .add(IpSet::nested(
+                "shop-apps",
+                "shop apps",
+                ["app-web", "app-db"],
+            ))
+            .unwrap();
+        batch
+            .remove(IpSet::nested("old-apps", "old apps", ["app-old"]))
+            .unwrap();
+        batch
+            .remove(IpSet::addresses("app-old", "app:old", []))
+            .unwrap();
+
+        assert_eq!(
+            summary(&batch),
+            vec![
+                (
+                    RequestType::Add,
+                    vec![
+                        set(SetPolicyType::IpSet, "app-db"),
+                        set(SetPolicyType::IpSet, "app-web"),
+                        set(SetPolicyType::NestedIpSet, "shop-apps"),
+                    ]
+                ),
+                (
+                    RequestType::Update,
+                    vec![set(SetPolicyType::IpSet, "ns-shop")]
+                ),
+                (
+                    RequestType::Remove,
+                    vec![
+                        set(SetPolicyType::NestedIpSet, "old-apps"),
+                        set(SetPolicyType::IpSet, "app-old"),
+                    ]
+                ),
+            ]
+        );
+
+        let requests = batch.requests().unwrap();
+        assert_eq!(
+            IpSet::from_policy(&requests[1].1[0]).unwrap(),
+            IpSet::addresses("ns-shop", "namespace:shop", pods(0, 3000))
+        );
+        assert_eq!(
+            serde_json::to_value(&requests[0].1[2]).unwrap(),
+            json!({
+                "Type": "SetPolicy",
+                "Settings": {
+                    "Id": "shop-apps",
+                    "Name": "shop apps",
+                    "Type": "NESTEDIPSET",
+                    "Values": "app-db,app-web",
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn add_then_remove_cancels_out() {
+        let mut batch = IpSetBatch::new();
+        batch.add(plain("app-tmp")).unwrap();
+        batch.remove(plain("app-tmp")).unwrap();
+
+        assert!(batch.is_empty());
+        assert_eq!(batch.requests().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn remove_then_add_becomes_an_update() {
+        let mut batch = IpSetBatch::new();
+        batch.remove(plain("app-web")).unwrap();
+        batch
+            .add(IpSet::addresses("app-web", "app:web", pods(5, 2)))
+            .unwrap();
+
+        assert_eq!(
+            summary(&batch),
+            vec![(
+                RequestType::Update,
+                vec![set(SetPolicyType::IpSet, "app-web")]
+            )]
+        );
+    }
+
+    #[test]
+    fn changing_the_type_is_rejected() {
+        let mut batch = IpSetBatch::new();
+        batch.remove(plain("apps")).unwrap();
+        assert!(batch
+            .add(IpSet::nested("apps", "apps", ["app-web"]))
+            .is_err());
+
+        // nor can a removed set be updated, or a set removed twice
+        assert!(batch.update(plain("apps")).is_err());
+        assert!(batch.remove(plain("apps")).is_err());
+        assert_eq!(batch.len(), 1);
+    }
+
+    #[test]
+    fn nested_sets_are_removed_first() {
+        let mut batch = IpSetBatch::new();
+        batch.remove(plain("a")).unwrap();
+        batch.remove(IpSet::nested("b", "b", ["a"])).unwrap();
+        batch.remove(plain("c")).unwrap();
+
+        assert_eq!(
+            summary(&batch),
+            vec![(
+                RequestType::Remove,
+                vec![
+                    set(SetPolicyType::NestedIpSet, "b"),
+                    set(SetPolicyType::IpSet, "a"),
+                    set(SetPolicyType::IpSet, "c"),
+                ]
+            )]
+        );
+    }
+
+    #[test]
+    fn requests_are_split_in_order() {
+        let mut batch = IpSetBatch::new().max_sets_per_request(2);
+        batch
+            .add(IpSet::nested("all", "all", ["a", "b", "c"]))
+            .unwrap();
+        for id in ["a", "b", "c"] {
+            batch.add(plain(id)).unwrap();
+        }
+        batch.update(plain("d")).unwrap();
+
+        assert_eq!(
+            summary(&batch),
+            vec![
+                (
+                    RequestType::Add,
+                    vec![
+                        set(SetPolicyType::IpSet, "a"),
+                        set(SetPolicyType::IpSet, "b")
+                    ]
+                ),
+                (
+                    RequestType::Add,
+                    vec![
+                        set(SetPolicyType::IpSet, "c"),
+                        set(SetPolicyType::NestedIpSet, "all")
+                    ]
+                ),
+                (RequestType::Update, vec![set(SetPolicyType::IpSet, "d")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_sets_are_rejected() {
+        let mut batch = IpSetBatch::new();
+        assert!(batch.add(IpSet::addresses("", "no id", [])).is_err());
+        assert!(batch.add(IpSet::nested("loop", "loop", ["loop"])).is_err());
+        assert!(batch.add(IpSet::nested("bad", "bad", ["a,b"])).is_err());
+        assert!(batch.is_empty());
+    }
+}
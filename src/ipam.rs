@@ -0,0 +1,588 @@
+//! Address allocation for networks using static IPAM.
+//!
+//! HNS leaves the choice of endpoint addresses to the caller on static IPAM
+//! networks. [`IpAllocator`] hands out free addresses from a network's subnets
+//! and, when backed by a state file, remembers them across process restarts and
+//! concurrent invocations (the file is locked while it is updated).
+
+use crate::prefix::{from_bits, to_bits, IpFamily, IpPrefix};
+use crate::schema::*;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+/// Allocates endpoint addresses from the subnets of one network.
+#[derive(Debug)]
+pub struct IpAllocator {
+    pools: Vec<Pool>,
+    exclusions: Vec<RangeInclusive<IpAddr>>,
+    allocations: BTreeMap<IpAddr, String>,
+    state_file: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+struct Pool {
+    prefix: IpPrefix,
+    reserved: BTreeSet<IpAddr>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct State {
+    allocations: BTreeMap<IpAddr, String>,
+}
+
+impl IpAllocator {
+    /// Build an allocator over the subnets of `ipams`, usually a network's
+    /// `HostComputeNetwork::ipams`.
+    ///
+    /// The network address, the IPv4 broadcast address and any route next hop
+    /// inside a subnet (the gateway) are never handed out.
+    pub fn new(ipams: &[Ipam]) -> Result<Self> {
+        let mut pools = vec![];
+        for subnet in ipams.iter().flat_map(|i| i.subnets.iter()) {
+            let Some(prefix) = &subnet.ip_address_prefix else {
+                continue;
+            };
+            let prefix: IpPrefix = prefix.parse()?;
+            if pools.iter().any(|p: &Pool| p.prefix.overlaps(&prefix)) {
+                bail!("subnet {} overlaps another subnet", prefix);
+            }
+
+            let mut reserved = BTreeSet::new();
+            let host_bits = prefix.family().bits() - prefix.prefix_len();
+            if host_bits >= 2 {
+                reserved.insert(prefix.network());
+                if prefix.is_ipv4() {
+                    reserved.insert(prefix.last());
+                }
+            }
+            for route in &subnet.routes {
+                if let Some(Ok(next_hop)) = route.next_hop.as_ref().map(|h| h.parse::<IpAddr>()) {
+                    if prefix.contains(&next_hop) {
+                        reserved.insert(next_hop);
+                    }
+                }
+            }
+
+            pools.push(Pool { prefix, reserved });
+        }
+
+        if pools.is_empty() {
+            bail!("no subnets to allocate from");
+        }
+
+        Ok(Self {
+            pools,
+            exclusions: vec![],
+            allocations: BTreeMap::new(),
+            state_file: None,
+        })
+    }
+
+    /// Persist allocations in `path`, loading any allocations already recorded there.
+    pub fn with_state_file(mut self, path: impl AsRef<Path>) -> Result<Self> {
+        self.state_file = Some(path.as_ref().to_path_buf());
+        self.update(|_| Ok(()))?;
+        Ok(self)
+    }
+
+    /// Never hand out addresses in `range`, e.g. addresses managed outside of HNS.
+    pub fn exclude(&mut self, range: RangeInclusive<IpAddr>) -> Result<()> {
+        if IpFamily::of(range.start()) != IpFamily::of(range.end()) {
+            bail!(
+                "exclusion {}-{} mixes address families",
+                range.start(),
+                range.end()
+            );
+        }
+        if range.start() > range.end() {
+            bail!("exclusion {}-{} is empty", range.start(), range.end());
+        }
+        self.exclusions.push(range);
+        Ok(())
+    }
+
+    /// Record the addresses of existing endpoints as allocated, owned by the
+    /// endpoint ID, so addresses assigned before the allocator existed are skipped.
+    pub fn seed_from_endpoints(&mut self, endpoints: &[HostComputeEndpoint]) -> Result<()> {
+        let in_use: Vec<(IpAddr, String)> = endpoints
+            .iter()
+            .flat_map(|e| {
+                e.ip_configurations
+                    .iter()
+                    .filter_map(|c| c.ip_address.parse::<IpAddr>().ok())
+                    .map(|ip| (ip, e.id.clone()))
+            })
+            .filter(|(ip, _)| self.pool_of(ip).is_some())
+            .collect();
+
+        self.update(|allocations| {
+            for (ip, owner) in in_use {
+                allocations.entry(ip).or_insert(owner);
+            }
+            Ok(())
+        })
+    }
+
+    /// Allocate an address of `family` for `owner`.
+    ///
+    /// Calling this again for the same owner returns the address it already
+    /// holds, which makes retried CNI ADD calls safe.
+    pub fn allocate(&mut self, owner: &str, family: IpFamily) -> Result<IpAddr> {
+        let pools: Vec<IpPrefix> = self
+            .pools
+            .iter()
+            .map(|p| p.prefix)
+            .filter(|p| p.family() == family)
+            .collect();
+        if pools.is_empty() {
+            bail!("no {:?} subnet to allocate from", family);
+        }
+        self.allocate_from_any(owner, &pools)
+    }
+
+    /// Allocate an address for `owner` from a specific subnet.
+    pub fn allocate_from(&mut self, subnet: &IpPrefix, owner: &str) -> Result<IpAddr> {
+        if !self.pools.iter().any(|p| p.prefix == *subnet) {
+            bail!("{} is not a subnet of this network", subnet);
+        }
+        self.allocate_from_any(owner, &[*subnet])
+    }
+
+    /// Claim a specific address for `owner`.
+    pub fn reserve(&mut self, ip: IpAddr, owner: &str) -> Result<()> {
+        if !self.is_assignable(&ip) {
+            bail!("{} is not an assignable address of this network", ip);
+        }
+        self.update(|allocations| match allocations.get(&ip) {
+            Some(current) if current != owner => {
+                bail!("{} is already allocated to {}", ip, current)
+            }
+            _ => {
+                allocations.insert(ip, owner.to_string());
+                Ok(())
+            }
+        })
+    }
+
+    /// Release an address. Returns false if it was not allocated.
+    pub fn release(&mut self, ip: &IpAddr) -> Result<bool> {
+        self.update(|allocations| Ok(allocations.remove(ip).is_some()))
+    }
+
+    /// Release every address held by `owner`, e.g. on CNI DEL.
+    pub fn release_owner(&mut self, owner: &str) -> Result<Vec<IpAddr>> {
+        self.update(|allocations| {
+            let released: Vec<IpAddr> = allocations
+                .iter()
+                .filter(|(_, o)| o.as_str() == owner)
+                .map(|(ip, _)| *ip)
+                .collect();
+            for ip in &released {
+                allocations.remove(ip);
+            }
+            Ok(released)
+        })
+    }
+
+    /// Addresses currently allocated and their owners.
+    pub fn allocations(&self) -> impl Iterator<Item = (&IpAddr, &str)> {
+        self.allocations.iter().map(|(ip, o)| (ip, o.as_str()))
+    }
+
+    pub fn subnets(&self) -> impl Iterator<Item = &IpPrefix> {
+        self.pools.iter().map(|p| &p.prefix)
+    }
+
+    fn allocate_from_any(&mut self, owner: &str, subnets: &[IpPrefix]) -> Result<IpAddr> {
+        // candidates are computed from a fresh copy of the state inside the update
+        let pools: Vec<(IpPrefix, BTreeSet<IpAddr>)> = self
+            .pools
+            .iter()
+            .filter(|p| subnets.contains(&p.prefix))
+            .map(|p| (p.prefix, p.reserved.clone()))
+            .collect();
+        let exclusions = self.exclusions.clone();
+
+        self.update(|allocations| {
+            if let Some((ip, _)) = allocations
+                .iter()
+                .find(|(ip, o)| o.as_str() == owner && subnets.iter().any(|s| s.contains(ip)))
+            {
+                return Ok(*ip);
+            }
+
+            for (prefix, reserved) in &pools {
+                if let Some(ip) = first_free(prefix, reserved, &exclusions, allocations) {
+                    allocations.insert(ip, owner.to_string());
+                    log::debug!("allocated {} to {}", ip, owner);
+                    return Ok(ip);
+                }
+            }
+
+            let subnets: Vec<String> = subnets.iter().map(|s| s.to_string()).collect();
+            bail!("no free addresses in {}", subnets.join(", "))
+        })
+    }
+
+    fn pool_of(&self, ip: &IpAddr) -> Option<&Pool> {
+        self.pools.iter().find(|p| p.prefix.contains(ip))
+    }
+
+    fn is_assignable(&self, ip: &IpAddr) -> bool {
+        self.pool_of(ip).is_some_and(|p| !p.reserved.contains(ip))
+            && !self.exclusions.iter().any(|r| r.contains(ip))
+    }
+
+    // Apply `f` to the allocations. With a state file the allocations are
+    // re-read under an exclusive lock first and written back before unlocking,
+    // so concurrent allocators sharing the file never hand out the same address.
+    fn update<T>(
+        &mut self,
+        f: impl FnOnce(&mut BTreeMap<IpAddr, String>) -> Result<T>,
+    ) -> Result<T> {
+        let Some(path) = &self.state_file else {
+            return f(&mut self.allocations);
+        };
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("failed to open ipam state {}", path.display()))?;
+        file.lock()
+            .with_context(|| format!("failed to lock ipam state {}", path.display()))?;
+
+        let mut state = read_state(&mut file)
+            .with_context(|| format!("failed to read ipam state {}", path.display()))?;
+        let result = f(&mut state.allocations)?;
+        write_state(&mut file, &state)
+            .with_context(|| format!("failed to write ipam state {}", path.display()))?;
+
+        self.allocations = state.allocations;
+        Ok(result)
+    }
+}
+
+fn first_free(
+    prefix: &IpPrefix,
+    reserved: &BTreeSet<IpAddr>,
+    exclusions: &[RangeInclusive<IpAddr>],
+    allocations: &BTreeMap<IpAddr, String>,
+) -> Option<IpAddr> {
+    let family = prefix.family();
+    let last = to_bits(&prefix.last());
+    let mut candidate = to_bits(&prefix.network());
+
+    while candidate <= last {
+        let ip = from_bits(family, candidate);
+        if let Some(range) = exclusions.iter().find(|r| r.contains(&ip)) {
+            // skip the whole excluded range at once
+            candidate = to_bits(range.end()).checked_add(1)?;
+            continue;
+        }
+        if !reserved.contains(&ip) && !allocations.contains_key(&ip) {
+            return Some(ip);
+        }
+        candidate = candidate.checked_add(1)?;
+    }
+
+    None
+}
+
+fn read_state(file: &mut File) -> Result<State> {
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    if contents.trim().is_empty() {
+        return Ok(State::default());
+    }
+    Ok(serde_json::from_str(&contents)?)
+}
+
+fn write_state(file: &mut File, state: &State) -> Result<()> {
+    let contents = serde_json::to_string_pretty(state)?;
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn ipams(subnets: &[(&str, &str)]) -> Vec<Ipam> {
+        vec![Ipam {
+            subnets: subnets
+                .iter()
+                .map(|(prefix, gateway)| Subnet::new(&prefix.parse().unwrap(), &ip(gateway)))
+                .collect(),
+            ..Default::default()
+        }]
+    }
+
+    // /29: .0 network, .7 broadcast, .1 gateway, leaving .2 to .6
+    fn small() -> IpAllocator {
+        IpAllocator::new(&ipams(&[("10.0.0.0/29", "10.0.0.1")])).unwrap()
+    }
+
+    // A state file of its own for each test, removed when dropped.
+    struct StateFile(PathBuf);
+
+    impl StateFile {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("hcn-ipam-{}-{}.json", std::process::id(), name));
+            let _ = std::fs::remove_file(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for StateFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn skips_network_broadcast_and_gateway() {
+        let mut allocator = small();
+        let allocated: Vec<IpAddr> = (0..5)
+            .map(|i| {
+                allocator
+                    .allocate(&format!("pod-{}", i), IpFamily::V4)
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(
+            allocated,
+            ["10.0.0.2", "10.0.0.3", "10.0.0.4", "10.0.0.5", "10.0.0.6"].map(ip)
+        );
+        for reserved in ["10.0.0.0", "10.0.0.1", "10.0.0.7"] {
+            assert!(allocator.reserve(ip(reserved), "pod").is_err());
+        }
+    }
+
+    #[test]
+    fn exhaustion() {
+        let mut allocator = small();
+        for i in 0..5 {
+            allocator
+                .allocate(&format!("pod-{}", i), IpFamily::V4)
+                .unwrap();
+        }
+        assert!(allocator.allocate("pod-5", IpFamily::V4).is_err());
+        assert!(allocator.allocate("pod-5", IpFamily::V6).is_err());
+
+        // a released address is handed out again
+        assert!(allocator.release(&ip("10.0.0.4")).unwrap());
+        assert!(!allocator.release(&ip("10.0.0.4")).unwrap());
+        assert_eq!(
+            allocator.allocate("pod-5", IpFamily::V4).unwrap(),
+            ip("10.0.0.4")
+        );
+    }
+
+    #[test]
+    fn small_subnets_keep_every_address() {
+        let mut allocator = IpAllocator::new(&ipams(&[("10.0.0.0/31", "10.0.1.1")])).unwrap();
+        assert_eq!(
+            allocator.allocate("a", IpFamily::V4).unwrap(),
+            ip("10.0.0.0")
+        );
+        assert_eq!(
+            allocator.allocate("b", IpFamily::V4).unwrap(),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn ipv6_keeps_the_last_address() {
+        let mut allocator = IpAllocator::new(&ipams(&[("fd00::/126", "fd00::1")])).unwrap();
+        let allocated: Vec<IpAddr> = ["a", "b"]
+            .iter()
+            .map(|o| allocator.allocate(o, IpFamily::V6).unwrap())
+            .collect();
+        assert_eq!(allocated, ["fd00::2", "fd00::3"].map(ip));
+    }
+
+    #[test]
+    fn same_owner_gets_the_same_address() {
+        let mut allocator = small();
+        let first = allocator.allocate("pod", IpFamily::V4).unwrap();
+        assert_eq!(allocator.allocate("pod", IpFamily::V4).unwrap(), first);
+        assert_eq!(allocator.allocations().count(), 1);
+    }
+
+    #[test]
+    fn reserve() {
+        let mut allocator = small();
+        allocator.reserve(ip("10.0.0.2"), "pod-a").unwrap();
+        // holding it already is fine, taking it from another owner is not
+        allocator.reserve(ip("10.0.0.2"), "pod-a").unwrap();
+        assert!(allocator.reserve(ip("10.0.0.2"), "pod-b").is_err());
+        assert!(allocator.reserve(ip("10.0.1.2"), "pod-b").is_err());
+        assert_eq!(
+            allocator.allocate("pod-b", IpFamily::V4).unwrap(),
+            ip("10.0.0.3")
+        );
+    }
+
+    #[test]
+    fn release_owner() {
+        let mut allocator = IpAllocator::new(&ipams(&[
+            ("10.0.0.0/24", "10.0.0.1"),
+            ("fd00::/64", "fd00::1"),
+        ]))
+        .unwrap();
+        let v4 = allocator.allocate("pod-a", IpFamily::V4).unwrap();
+        let v6 = allocator.allocate("pod-a", IpFamily::V6).unwrap();
+        let other = allocator.allocate("pod-b", IpFamily::V4).unwrap();
+
+        assert_eq!(allocator.release_owner("pod-a").unwrap(), vec![v4, v6]);
+        assert!(allocator.release_owner("pod-a").unwrap().is_empty());
+        let left: Vec<(&IpAddr, &str)> = allocator.allocations().collect();
+        assert_eq!(left, vec![(&other, "pod-b")]);
+    }
+
+    #[test]
+    fn allocate_from() {
+        let mut allocator = IpAllocator::new(&ipams(&[
+            ("10.0.0.0/24", "10.0.0.1"),
+            ("10.0.1.0/24", "10.0.1.1"),
+        ]))
+        .unwrap();
+        let second = "10.0.1.0/24".parse().unwrap();
+        assert_eq!(
+            allocator.allocate_from(&second, "pod").unwrap(),
+            ip("10.0.1.2")
+        );
+        assert!(allocator
+            .allocate_from(&"10.0.2.0/24".parse().unwrap(), "pod")
+            .is_err());
+    }
+
+    #[test]
+    fn exclusions() {
+        let mut allocator = small();
+        allocator.exclude(ip("10.0.0.2")..=ip("10.0.0.4")).unwrap();
+        assert_eq!(
+            allocator.allocate("pod", IpFamily::V4).unwrap(),
+            ip("10.0.0.5")
+        );
+        assert!(allocator.reserve(ip("10.0.0.3"), "other").is_err());
+
+        assert!(allocator.exclude(ip("10.0.0.5")..=ip("10.0.0.2")).is_err());
+        assert!(allocator.exclude(ip("10.0.0.5")..=ip("fd00::1")).is_err());
+    }
+
+    #[test]
+    fn seeded_addresses_are_skipped() {
+        let endpoint = |id: &str, address: &str| HostComputeEndpoint {
+            id: id.to_string(),
+            ip_configurations: vec![IpConfig::new(&ip(address), 29)],
+            ..Default::default()
+        };
+        let mut allocator = small();
+        allocator
+            .seed_from_endpoints(&[
+                endpoint("ep-1", "10.0.0.2"),
+                endpoint("ep-2", "192.168.0.2"),
+            ])
+            .unwrap();
+
+        let seeded: Vec<(&IpAddr, &str)> = allocator.allocations().collect();
+        assert_eq!(seeded, vec![(&ip("10.0.0.2"), "ep-1")]);
+        assert_eq!(
+            allocator.allocate("pod", IpFamily::V4).unwrap(),
+            ip("10.0.0.3")
+        );
+    }
+
+    #[test]
+    fn invalid_subnets() {
+        assert!(IpAllocator::new(&[]).is_err());
+        assert!(IpAllocator::new(&ipams(&[
+            ("10.0.0.0/16", "10.0.0.1"),
+            ("10.0.1.0/24", "10.0.1.1")
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn state_file_round_trip() {
+        let state = StateFile::new("round-trip");
+        let mut allocator = small().with_state_file(&state.0).unwrap();
+        allocator.allocate("pod-a", IpFamily::V4).unwrap();
+        allocator.reserve(ip("10.0.0.6"), "pod-b").unwrap();
+        drop(allocator);
+
+        let mut reloaded = small().with_state_file(&state.0).unwrap();
+        let allocations: Vec<(&IpAddr, &str)> = reloaded.allocations().collect();
+        assert_eq!(
+            allocations,
+            vec![(&ip("10.0.0.2"), "pod-a"), (&ip("10.0.0.6"), "pod-b")]
+        );
+        assert_eq!(
+            reloaded.allocate("pod-c", IpFamily::V4).unwrap(),
+            ip("10.0.0.3")
+        );
+        reloaded.release_owner("pod-a").unwrap();
+
+        let reloaded = small().with_state_file(&state.0).unwrap();
+        assert_eq!(reloaded.allocations().count(), 2);
+    }
+
+    #[test]
+    fn allocators_sharing_a_state_file_never_collide() {
+        let state = StateFile::new("shared");
+        let ipams = ipams(&[("10.0.0.0/24", "10.0.0.1")]);
+        let allocated: Vec<IpAddr> = thread::scope(|s| {
+            let workers: Vec<_> = (0..4)
+                .map(|w| {
+                    let (ipams, path) = (&ipams, &state.0);
+                    s.spawn(move || {
+                        let mut allocator = IpAllocator::new(ipams)
+                            .unwrap()
+                            .with_state_file(path)
+                            .unwrap();
+                        (0..10)
+                            .map(|i| {
+                                allocator
+                                    .allocate(&format!("pod-{}-{}", w, i), IpFamily::V4)
+                                    .unwrap()
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|w| w.join().unwrap())
+                .collect()
+        });
+
+        let unique: BTreeSet<IpAddr> = allocated.iter().copied().collect();
+        assert_eq!(unique.len(), 40);
+    }
+
+    #[test]
+    fn corrupt_state_file_is_an_error() {
+        let state = StateFile::new("corrupt");
+        std::fs::write(&state.0, "not json").unwrap();
+        assert!(small().with_state_file(&state.0).is_err());
+    }
+}
@@ -0,0 +1,6 @@
+//! Translating Kubernetes objects to HNS objects, as kube-proxy and network
+//! policy controllers on Windows do. Nothing here calls HNS, so these modules
+//! build and are tested on every platform, not only on Windows.
+
+pub mod network_policy;
+pub mod service;
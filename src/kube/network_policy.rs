@@ -0,0 +1,365 @@
+//! NetworkPolicies to endpoint ACLs.
+//!
+//! A pod selected by a policy with ingress (egress) rules only accepts (sends)
+//! the traffic some rule of some such policy allows. HNS ACLs are evaluated
+//! in order, so an endpoint's ACLs are, per direction: allow traffic with the
+//! host, which kubelet probes need, then one allow per rule, then block the
+//! rest. Directions no policy restricts are allowed outright.
+
+use crate::acl::{AclCompiler, AclRule, CompiledAcls, PortRange, PROTOCOL_TCP, PROTOCOL_UDP};
+use crate::nat::Protocol;
+use crate::prefix::{IpFamily, IpPrefix};
+use crate::schema::AclDirection;
+use anyhow::Result;
+use std::net::IpAddr;
+
+/// A NetworkPolicy with its selectors already resolved.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetworkPolicy {
+    pub namespace: String,
+    pub name: String,
+    /// Rules for incoming traffic, `None` when the policy does not restrict it.
+    pub ingress: Option<Vec<PolicyRule>>,
+    /// Rules for outgoing traffic, `None` when the policy does not restrict it.
+    pub egress: Option<Vec<PolicyRule>>,
+}
+
+/// Allows traffic from (ingress) or to (egress) any of the peers on any of the
+/// ports.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PolicyRule {
+    /// Empty for any peer.
+    pub peers: Vec<Peer>,
+    /// Empty for any port and protocol.
+    pub ports: Vec<PolicyPort>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Peer {
+    /// The addresses of the pods a pod and namespace selector matched.
+    Pods(Vec<IpAddr>),
+    IpBlock {
+        cidr: IpPrefix,
+        except: Vec<IpPrefix>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PolicyPort {
+    pub protocol: Protocol,
+    /// Any port of the protocol when not set.
+    pub ports: Option<PortRange>,
+}
+
+/// How this node's endpoints are set up.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PolicyConfig {
+    /// The host's addresses on the pod network, always allowed.
+    pub host_addresses: Vec<IpPrefix>,
+    pub compiler: AclCompiler,
+}
+
+/// Compile the ACLs of an endpoint from the policies selecting its pod.
+pub fn compile(policies: &[&NetworkPolicy], config: &PolicyConfig) -> Result<CompiledAcls> {
+    let mut rules = vec![];
+    for direction in [AclDirection::In, AclDirection::Out] {
+        let restricting: Vec<&Vec<PolicyRule>> = policies
+            .iter()
+            .filter_map(|p| match direction {
+                AclDirection::In => p.ingress.as_ref(),
+                AclDirection::Out => p.egress.as_ref(),
+            })
+            .collect();
+        if restricting.is_empty() {
+            rules.push(AclRule::allow(direction));
+            continue;
+        }
+
+        for addresses in by_family(&config.host_addresses) {
+            rules.push(AclRule {
+                remote_addresses: addresses,
+                ..AclRule::allow(direction)
+            });
+        }
+        for rule in restricting.into_iter().flatten() {
+            rules.extend(allow_rules(rule, direction));
+        }
+        rules.push(AclRule::block(direction));
+    }
+
+    config.compiler.compile(&rules)
+}
+
+fn allow_rules(rule: &PolicyRule, direction: AclDirection) -> Vec<AclRule> {
+    // with peers, one rule per family of their addresses; without, any remote
+    let remotes = if rule.peers.is_empty() {
+        vec![vec![]]
+    } else {
+        let addresses: Vec<IpPrefix> = rule.peers.iter().flat_map(peer_addresses).collect();
+        // peers that resolve to no address allow nothing, rather than anything
+        by_family(&addresses)
+    };
+
+    let mut rules = vec![];
+    for remote_addresses in remotes {
+        let allow = AclRule {
+            remote_addresses,
+            ..AclRule::allow(direction)
+        };
+        if rule.ports.is_empty() {
+            rules.push(allow);
+            continue;
+        }
+        for port in &rule.ports {
+            let protocol = match port.protocol {
+                Protocol::Tcp => PROTOCOL_TCP,
+                Protocol::Udp => PROTOCOL_UDP,
+            };
+            let mut allow = allow.clone().protocol(protocol);
+            // the policy's ports are the pod's when it receives and the
+            // peer's when it sends
+            match (port.ports, direction) {
+                (Some(ports), AclDirection::In) => allow.local_ports.push(ports),
+                (Some(ports), AclDirection::Out) => allow.remote_ports.push(ports),
+                (None, _) => {}
+            }
+            rules.push(allow);
+        }
+    }
+    rules
+}
+
+fn peer_addresses(peer: &Peer) -> Vec<IpPrefix> {
+    match peer {
+        Peer::Pods(addresses) => addresses.iter().map(|a| IpPrefix::host(*a)).collect(),
+        Peer::IpBlock { cidr, except } => cidr.exclude(except),
+    }
+}
+
+// Split addresses into one list per family, IPv4 first, leaving out empty ones.
+fn by_family(addresses: &[IpPrefix]) -> Vec<Vec<IpPrefix>> {
+    [IpFamily::V4, IpFamily::V6]
+        .into_iter()
+        .map(|family| {
+            addresses
+                .iter()
+                .filter(|a| a.family() == family)
+                .copied()
+                .collect::<Vec<_>>()
+        })
+        .filter(|a| !a.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::AclPolicySetting;
+
+    fn pods(addresses: &[&str]) -> Peer {
+        Peer::Pods(addresses.iter().map(|a| a.parse().unwrap()).collect())
+    }
+
+    fn ip_block(cidr: &str, except: &[&str]) -> Peer {
+        Peer::IpBlock {
+            cidr: cidr.parse().unwrap(),
+            except: except.iter().map(|e| e.parse().unwrap()).collect(),
+        }
+    }
+
+    fn port(protocol: Protocol, port: u16) -> PolicyPort {
+        PolicyPort {
+            protocol,
+            ports: Some(PortRange::single(port)),
+        }
+    }
+
+    fn ingress(rules: Vec<PolicyRule>) -> NetworkPolicy {
+        NetworkPolicy {
+            namespace: "shop".to_string(),
+            name: "ingress".to_string(),
+            ingress: Some(rules),
+            egress: None,
+        }
+    }
+
+    fn egress(rules: Vec<PolicyRule>) -> NetworkPolicy {
+        NetworkPolicy {
+            namespace: "shop".to_string(),
+            name: "egress".to_string(),
+            ingress: None,
+            egress: Some(rules),
+        }
+    }
+
+    // One line per ACL: action, direction, protocol, remote addresses, local
+    // and remote ports. Priorities only follow the order.
+    fn acls(policies: &[&NetworkPolicy], config: &PolicyConfig) -> Vec<String> {
+        let compiled = compile(policies, config).unwrap();
+        compiled
+            .policies
+            .iter()
+            .map(|p| {
+                let s: AclPolicySetting = p.settings_as().unwrap();
+                format!(
+                    "{:?} {:?} [{}] [{}] [{}] [{}]",
+                    s.action,
+                    s.direction,
+                    s.protocols,
+                    s.remote_addresses,
+                    s.local_ports,
+                    s.remote_ports
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn golden() {
+        let allow_frontend = ingress(vec![
+            PolicyRule {
+                peers: vec![pods(&["10.244.1.5", "10.244.2.7", "fd00:244:2::7"])],
+                ports: vec![port(Protocol::Tcp, 8080)],
+            },
+            PolicyRule {
+                peers: vec![ip_block(
+                    "172.16.0.0/16",
+                    &["172.16.0.0/18", "172.16.128.0/17"],
+                )],
+                ports: vec![],
+            },
+        ]);
+        let restrict_egress = egress(vec![
+            PolicyRule {
+                peers: vec![ip_block("10.96.0.10/32", &[])],
+                ports: vec![port(Protocol::Udp, 53), port(Protocol::Tcp, 53)],
+            },
+            // a selector that matches no pods allows nothing
+            PolicyRule {
+                peers: vec![pods(&[])],
+                ports: vec![],
+            },
+        ]);
+        let config = PolicyConfig {
+            host_addresses: vec![
+                "10.244.1.1".parse().unwrap(),
+                "fd00:244:1::1".parse().unwrap(),
+            ],
+            compiler: AclCompiler {
+                first_priority: 1000,
+                step: 10,
+                ..Default::default()
+            },
+        };
+
+        let compiled = compile(&[&allow_frontend, &restrict_egress], &config).unwrap();
+        assert!(compiled.shadowed.is_empty(), "{:?}", compiled.shadowed);
+        let actual = serde_json::to_value(&compiled.policies).unwrap();
+        let golden: serde_json::Value =
+            serde_json::from_str(include_str!("../../tests/golden/network_policy.json")).unwrap();
+        assert_eq!(
+            actual,
+            golden,
+            "compiled ACLs differ from the golden file, they are now:\n{}",
+            serde_json::to_string_pretty(&actual).unwrap()
+        );
+    }
+
+    #[test]
+    fn unselected_endpoint_is_open() {
+        assert_eq!(
+            acls(&[], &PolicyConfig::default()),
+            vec!["Allow In [] [] [] []", "Allow Out [] [] [] []"]
+        );
+    }
+
+    #[test]
+    fn no_host_addresses() {
+        let policy = ingress(vec![PolicyRule {
+            peers: vec![pods(&["10.244.1.5"])],
+            ports: vec![],
+        }]);
+        assert_eq!(
+            acls(&[&policy], &PolicyConfig::default()),
+            vec![
+                "Allow In [] [10.244.1.5/32] [] []",
+                "Block In [] [] [] []",
+                "Allow Out [] [] [] []",
+            ]
+        );
+    }
+
+    #[test]
+    fn any_peer_on_some_ports() {
+        let config = PolicyConfig {
+            host_addresses: vec!["10.244.1.1".parse().unwrap()],
+            ..Default::default()
+        };
+        let ingress = ingress(vec![PolicyRule {
+            peers: vec![],
+            ports: vec![port(Protocol::Tcp, 443), port(Protocol::Udp, 53)],
+        }]);
+        let egress = egress(vec![PolicyRule {
+            peers: vec![],
+            ports: vec![port(Protocol::Udp, 53)],
+        }]);
+        assert_eq!(
+            acls(&[&ingress, &egress], &config),
+            vec![
+                "Allow In [] [10.244.1.1/32] [] []",
+                "Allow In [6] [] [443] []",
+                "Allow In [17] [] [53] []",
+                "Block In [] [] [] []",
+                "Allow Out [] [10.244.1.1/32] [] []",
+                "Allow Out [17] [] [] [53]",
+                "Block Out [] [] [] []",
+            ]
+        );
+    }
+
+    #[test]
+    fn ip_block_with_nothing_left_allows_nothing() {
+        let policy = ingress(vec![PolicyRule {
+            peers: vec![ip_block(
+                "172.16.0.0/16",
+                &["172.16.0.0/17", "172.16.0.0/12"],
+            )],
+            ports: vec![port(Protocol::Tcp, 80)],
+        }]);
+        assert_eq!(
+            acls(&[&policy], &PolicyConfig::default()),
+            vec!["Block In [] [] [] []", "Allow Out [] [] [] []"]
+        );
+    }
+
+    #[test]
+    fn ingress_only_and_egress_only() {
+        let rule = PolicyRule {
+            peers: vec![pods(&["10.244.2.7"])],
+            ports: vec![],
+        };
+        let config = PolicyConfig::default();
+
+        assert_eq!(
+            acls(&[&ingress(vec![rule.clone()])], &config),
+            vec![
+                "Allow In [] [10.244.2.7/32] [] []",
+                "Block In [] [] [] []",
+                "Allow Out [] [] [] []",
+            ]
+        );
+        assert_eq!(
+            acls(&[&egress(vec![rule])], &config),
+            vec![
+                "Allow In [] [] [] []",
+                "Allow Out [] [10.244.2.7/32] [] []",
+                "Block Out [] [] [] []",
+            ]
+        );
+        // an empty rule list restricts its direction to the host, none here
+        assert_eq!(
+            acls(&[&egress(vec![])], &config),
+            vec!["Allow In [] [] [] []", "Block Out [] [] [] []"]
+        );
+    }
+}
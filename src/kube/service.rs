@@ -0,0 +1,469 @@
+//! Services to load balancers, following kube-proxy's winkernel proxier.
+//!
+//! Every port of a Service gets one load balancer per frontend: each cluster
+//! IP, the node port (one per cluster IP family), each external IP and each
+//! load balancer ingress IP. Frontends without any backend to send traffic to
+//! get no load balancer, the way kube-proxy skips them.
+
+use crate::nat::Protocol;
+use crate::prefix::IpFamily;
+use crate::schema::*;
+use anyhow::{bail, Result};
+use std::net::IpAddr;
+
+/// The parts of a Service that decide its load balancers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Service {
+    pub namespace: String,
+    pub name: String,
+    /// One per family for dual-stack Services, none for headless ones.
+    pub cluster_ips: Vec<IpAddr>,
+    pub ports: Vec<ServicePort>,
+    pub external_ips: Vec<IpAddr>,
+    /// `status.loadBalancer.ingress` addresses.
+    pub load_balancer_ingress: Vec<IpAddr>,
+    pub session_affinity: SessionAffinity,
+    pub external_traffic_policy: TrafficPolicy,
+    /// Keep the backend address as the destination of ingress traffic, the
+    /// winkernel `preserve-destination` annotation.
+    pub preserve_destination: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServicePort {
+    pub name: String,
+    pub protocol: Protocol,
+    pub port: u16,
+    pub target_port: u16,
+    pub node_port: Option<u16>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SessionAffinity {
+    #[default]
+    None,
+    ClientIP,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TrafficPolicy {
+    #[default]
+    Cluster,
+    /// Only send external traffic to backends on the node it arrived at.
+    Local,
+}
+
+/// An endpoint backing a Service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backend {
+    /// The HNS endpoint id.
+    pub endpoint_id: String,
+    /// True when the endpoint is on this node.
+    pub local: bool,
+}
+
+/// How this node's proxy is set up.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProxyConfig {
+    /// Use Direct Server Return.
+    pub dsr: bool,
+    /// The node's source VIP for each family, required with DSR.
+    pub source_vips: Vec<IpAddr>,
+}
+
+/// What a load balancer is the frontend for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frontend {
+    ClusterIp(IpAddr),
+    NodePort(IpFamily),
+    ExternalIp(IpAddr),
+    Ingress(IpAddr),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadBalancerSpec {
+    pub frontend: Frontend,
+    /// The name of the Service port.
+    pub port_name: String,
+    pub load_balancer: HostComputeLoadBalancer,
+}
+
+/// The load balancers `service` needs on this node, in the order of its
+/// ports and, for each port, of the frontends listed in [`Frontend`].
+pub fn translate(
+    service: &Service,
+    backends: &[Backend],
+    config: &ProxyConfig,
+) -> Result<Vec<LoadBalancerSpec>> {
+    let all: Vec<&str> = backends.iter().map(|b| b.endpoint_id.as_str()).collect();
+    let local: Vec<&str> = backends
+        .iter()
+        .filter(|b| b.local)
+        .map(|b| b.endpoint_id.as_str())
+        .collect();
+    let external = match service.external_traffic_policy {
+        TrafficPolicy::Cluster => &all,
+        TrafficPolicy::Local => &local,
+    };
+    let local_dsr = config.dsr && service.external_traffic_policy == TrafficPolicy::Local;
+
+    let mut specs = vec![];
+    for port in &service.ports {
+        let mut frontends = vec![];
+        for ip in &service.cluster_ips {
+            frontends.push((
+                Frontend::ClusterIp(*ip),
+                &all,
+                config.dsr,
+                LOAD_BALANCER_PORT_MAPPING_FLAGS_NONE,
+            ));
+        }
+        if port.node_port.is_some() {
+            for ip in &service.cluster_ips {
+                frontends.push((
+                    Frontend::NodePort(IpFamily::of(ip)),
+                    external,
+                    local_dsr,
+                    LOAD_BALANCER_PORT_MAPPING_FLAGS_LOCAL_ROUTED_VIP
+                        | LOAD_BALANCER_PORT_MAPPING_FLAGS_VIP_EXTERNAL_IP,
+                ));
+            }
+        }
+        for ip in &service.external_ips {
+            frontends.push((
+                Frontend::ExternalIp(*ip),
+                external,
+                local_dsr,
+                LOAD_BALANCER_PORT_MAPPING_FLAGS_VIP_EXTERNAL_IP,
+            ));
+        }
+        for ip in &service.load_balancer_ingress {
+            let mut flags = LOAD_BALANCER_PORT_MAPPING_FLAGS_VIP_EXTERNAL_IP;
+            if service.preserve_destination {
+                flags |= LOAD_BALANCER_PORT_MAPPING_FLAGS_USE_MUX
+                    | LOAD_BALANCER_PORT_MAPPING_FLAGS_PRESERVE_DIP;
+            }
+            let dsr = local_dsr || service.preserve_destination;
+            frontends.push((Frontend::Ingress(*ip), external, dsr, flags));
+        }
+
+        for (frontend, backends, dsr, flags) in frontends {
+            if backends.is_empty() {
+                continue;
+            }
+            specs.push(LoadBalancerSpec {
+                frontend,
+                port_name: port.name.clone(),
+                load_balancer: load_balancer(
+                    service, port, frontend, backends, config, dsr, flags,
+                )?,
+            });
+        }
+    }
+    Ok(specs)
+}
+
+fn load_balancer(
+    service: &Service,
+    port: &ServicePort,
+    frontend: Frontend,
+    backends: &[&str],
+    config: &ProxyConfig,
+    dsr: bool,
+    port_mapping_flags: LoadBalancerPortMappingFlags,
+) -> Result<HostComputeLoadBalancer> {
+    let (vip, family, external_port) = match frontend {
+        Frontend::ClusterIp(ip) => (Some(ip), IpFamily::of(&ip), port.port),
+        Frontend::NodePort(family) => (None, family, port.node_port.unwrap_or_default()),
+        Frontend::ExternalIp(ip) | Frontend::Ingress(ip) => {
+            (Some(ip), IpFamily::of(&ip), port.port)
+        }
+    };
+    let source_vip = config
+        .source_vips
+        .iter()
+        .find(|v| IpFamily::of(v) == family);
+    if dsr && source_vip.is_none() {
+        bail!(
+            "service {}/{} needs an {} source VIP for DSR",
+            service.namespace,
+            service.name,
+            family
+        );
+    }
+
+    let mut flags = LOAD_BALANCER_FLAGS_NONE;
+    if dsr {
+        flags |= LOAD_BALANCER_FLAGS_DSR;
+    }
+    if family == IpFamily::V6 {
+        flags |= LOAD_BALANCER_FLAGS_IPV6;
+    }
+    let distribution = match service.session_affinity {
+        SessionAffinity::None => LoadBalancerDistribution::None,
+        SessionAffinity::ClientIP => LoadBalancerDistribution::SourceIP,
+    };
+
+    let load_balancer = HostComputeLoadBalancer {
+        host_compute_endpoints: backends.iter().map(|b| b.to_string()).collect(),
+        source_vip: source_vip.map(|v| v.to_string()).unwrap_or_default(),
+        frontend_vips: vip.iter().map(|v| v.to_string()).collect(),
+        port_mappings: vec![LoadBalancerPortMapping {
+            protocol: Some(port.protocol.number()),
+            internal_port: Some(port.target_port),
+            external_port: Some(external_port),
+            distribution_type: Some(distribution),
+            flags: (port_mapping_flags != LOAD_BALANCER_PORT_MAPPING_FLAGS_NONE)
+                .then_some(port_mapping_flags),
+            ..Default::default()
+        }],
+        flags: (flags != LOAD_BALANCER_FLAGS_NONE).then_some(flags),
+        ..Default::default()
+    };
+    load_balancer.validate()?;
+    Ok(load_balancer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn backend(id: &str, local: bool) -> Backend {
+        Backend {
+            endpoint_id: id.to_string(),
+            local,
+        }
+    }
+
+    fn http(node_port: Option<u16>) -> ServicePort {
+        ServicePort {
+            name: "http".to_string(),
+            protocol: Protocol::Tcp,
+            port: 80,
+            target_port: 8080,
+            node_port,
+        }
+    }
+
+    fn service(cluster_ips: &[&str], node_port: Option<u16>) -> Service {
+        Service {
+            namespace: "default".to_string(),
+            name: "web".to_string(),
+            cluster_ips: cluster_ips.iter().map(|ip| ip.parse().unwrap()).collect(),
+            ports: vec![http(node_port)],
+            ..Default::default()
+        }
+    }
+
+    fn backends() -> Vec<Backend> {
+        vec![backend("local-ep", true), backend("remote-ep", false)]
+    }
+
+    fn dsr() -> ProxyConfig {
+        ProxyConfig {
+            dsr: true,
+            source_vips: vec![
+                "10.244.1.2".parse().unwrap(),
+                "fd00:244:1::2".parse().unwrap(),
+            ],
+        }
+    }
+
+    fn frontends(specs: &[LoadBalancerSpec]) -> Vec<Frontend> {
+        specs.iter().map(|s| s.frontend).collect()
+    }
+
+    fn json(spec: &LoadBalancerSpec) -> Value {
+        serde_json::to_value(&spec.load_balancer).unwrap()
+    }
+
+    #[test]
+    fn cluster_ip() {
+        let service = service(&["10.96.0.10"], None);
+        let specs = translate(&service, &backends(), &ProxyConfig::default()).unwrap();
+
+        assert_eq!(
+            frontends(&specs),
+            vec![Frontend::ClusterIp("10.96.0.10".parse().unwrap())]
+        );
+        assert_eq!(specs[0].port_name, "http");
+        assert_eq!(
+            json(&specs[0]),
+            json!({
+                "HostComputeEndpoints": ["local-ep", "remote-ep"],
+                "FrontendVIPs": ["10.96.0.10"],
+                "PortMappings": [{"Protocol": 6, "InternalPort": 8080, "ExternalPort": 80, "DistributionType": 0}],
+                "SchemaVersion": {"Major": 2, "Minor": 2},
+            })
+        );
+    }
+
+    #[test]
+    fn headless_service_has_no_load_balancers() {
+        let service = service(&[], Some(30080));
+        assert!(translate(&service, &backends(), &dsr()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn node_port_per_cluster_ip_family() {
+        let service = service(&["10.96.0.10", "fd00:10:96::a"], Some(30080));
+        let specs = translate(&service, &backends(), &ProxyConfig::default()).unwrap();
+
+        assert_eq!(
+            frontends(&specs),
+            vec![
+                Frontend::ClusterIp("10.96.0.10".parse().unwrap()),
+                Frontend::ClusterIp("fd00:10:96::a".parse().unwrap()),
+                Frontend::NodePort(IpFamily::V4),
+                Frontend::NodePort(IpFamily::V6),
+            ]
+        );
+        assert_eq!(
+            json(&specs[2]),
+            json!({
+                "HostComputeEndpoints": ["local-ep", "remote-ep"],
+                "PortMappings": [{"Protocol": 6, "InternalPort": 8080, "ExternalPort": 30080, "DistributionType": 0, "Flags": 18}],
+                "SchemaVersion": {"Major": 2, "Minor": 2},
+            })
+        );
+        assert!(specs[3].load_balancer.is_ipv6());
+        assert!(specs[3].load_balancer.frontend_vips.is_empty());
+    }
+
+    #[test]
+    fn external_ip() {
+        let service = Service {
+            external_ips: vec!["192.0.2.10".parse().unwrap()],
+            ..service(&["10.96.0.10"], None)
+        };
+        let specs = translate(&service, &backends(), &ProxyConfig::default()).unwrap();
+
+        assert_eq!(
+            specs[1].frontend,
+            Frontend::ExternalIp("192.0.2.10".parse().unwrap())
+        );
+        assert_eq!(
+            json(&specs[1]),
+            json!({
+                "HostComputeEndpoints": ["local-ep", "remote-ep"],
+                "FrontendVIPs": ["192.0.2.10"],
+                "PortMappings": [{"Protocol": 6, "InternalPort": 8080, "ExternalPort": 80, "DistributionType": 0, "Flags": 16}],
+                "SchemaVersion": {"Major": 2, "Minor": 2},
+            })
+        );
+    }
+
+    #[test]
+    fn ingress() {
+        let service = Service {
+            load_balancer_ingress: vec!["203.0.113.7".parse().unwrap()],
+            ..service(&["10.96.0.10"], None)
+        };
+        let specs = translate(&service, &backends(), &dsr()).unwrap();
+        assert_eq!(
+            specs[1].frontend,
+            Frontend::Ingress("203.0.113.7".parse().unwrap())
+        );
+        // DSR only applies to external traffic with the Local policy
+        assert!(specs[0].load_balancer.is_dsr());
+        assert!(!specs[1].load_balancer.is_dsr());
+        assert_eq!(
+            specs[1].load_balancer.port_mappings[0].flags,
+            Some(LOAD_BALANCER_PORT_MAPPING_FLAGS_VIP_EXTERNAL_IP)
+        );
+    }
+
+    #[test]
+    fn ingress_preserving_destination() {
+        let service = Service {
+            load_balancer_ingress: vec!["203.0.113.7".parse().unwrap()],
+            preserve_destination: true,
+            ..service(&["10.96.0.10"], None)
+        };
+        let specs = translate(&service, &backends(), &dsr()).unwrap();
+        let ingress = &specs[1].load_balancer;
+
+        assert!(ingress.is_dsr());
+        assert_eq!(
+            ingress.port_mappings[0].flags,
+            Some(
+                LOAD_BALANCER_PORT_MAPPING_FLAGS_VIP_EXTERNAL_IP
+                    | LOAD_BALANCER_PORT_MAPPING_FLAGS_USE_MUX
+                    | LOAD_BALANCER_PORT_MAPPING_FLAGS_PRESERVE_DIP
+            )
+        );
+    }
+
+    #[test]
+    fn session_affinity() {
+        let mut service = service(&["10.96.0.10"], Some(30080));
+        service.session_affinity = SessionAffinity::ClientIP;
+        let specs = translate(&service, &backends(), &ProxyConfig::default()).unwrap();
+
+        for spec in &specs {
+            assert_eq!(
+                spec.load_balancer.port_mappings[0].distribution_type,
+                Some(LoadBalancerDistribution::SourceIP),
+                "{:?}",
+                spec.frontend
+            );
+        }
+    }
+
+    #[test]
+    fn local_policy_sends_external_traffic_to_local_backends() {
+        let service = Service {
+            external_ips: vec!["192.0.2.10".parse().unwrap()],
+            load_balancer_ingress: vec!["203.0.113.7".parse().unwrap()],
+            external_traffic_policy: TrafficPolicy::Local,
+            ..service(&["10.96.0.10", "fd00:10:96::a"], Some(30080))
+        };
+        let specs = translate(&service, &backends(), &dsr()).unwrap();
+
+        assert_eq!(specs.len(), 6);
+        for spec in &specs {
+            let endpoints = &spec.load_balancer.host_compute_endpoints;
+            match spec.frontend {
+                Frontend::ClusterIp(_) => assert_eq!(endpoints, &["local-ep", "remote-ep"]),
+                _ => assert_eq!(endpoints, &["local-ep"], "{:?}", spec.frontend),
+            }
+            assert!(spec.load_balancer.is_dsr());
+        }
+        assert_eq!(
+            json(&specs[3]),
+            json!({
+                "HostComputeEndpoints": ["local-ep"],
+                "SourceVIP": "fd00:244:1::2",
+                "PortMappings": [{"Protocol": 6, "InternalPort": 8080, "ExternalPort": 30080, "DistributionType": 0, "Flags": 18}],
+                "Flags": 3,
+                "SchemaVersion": {"Major": 2, "Minor": 2},
+            })
+        );
+    }
+
+    #[test]
+    fn local_policy_without_local_backends() {
+        let service = Service {
+            load_balancer_ingress: vec!["203.0.113.7".parse().unwrap()],
+            external_traffic_policy: TrafficPolicy::Local,
+            ..service(&["10.96.0.10"], Some(30080))
+        };
+        let specs = translate(&service, &[backend("remote-ep", false)], &dsr()).unwrap();
+
+        assert_eq!(
+            frontends(&specs),
+            vec![Frontend::ClusterIp("10.96.0.10".parse().unwrap())]
+        );
+    }
+
+    #[test]
+    fn dsr_needs_a_source_vip_of_the_family() {
+        let service = service(&["fd00:10:96::a"], None);
+        let config = ProxyConfig {
+            dsr: true,
+            source_vips: vec!["10.244.1.2".parse().unwrap()],
+        };
+        assert!(translate(&service, &backends(), &config).is_err());
+    }
+}
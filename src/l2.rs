@@ -0,0 +1,300 @@
+//! L2Bridge and L2Tunnel networks.
+//!
+//! Both attach endpoints to a host adapter. On an L2Bridge network endpoints
+//! share the adapter's L2 segment and traffic leaves with the endpoint's MAC
+//! rewritten to the host's; on an L2Tunnel network all traffic is sent up to
+//! the physical switch. HNS needs the adapter by name, [`L2NetworkBuilder`]
+//! finds it from an [`AdapterSelector`].
+//!
+//! Only L2Bridge subnets can be tagged with a VLAN, HNS refuses a VLAN policy
+//! on an L2Tunnel network, whose traffic the switch already sees untouched.
+
+#[cfg(windows)]
+use crate::adapter;
+use crate::adapter::{AdapterSelector, HostAdapter};
+use crate::mac::MacAddress;
+use crate::network::check_gateway;
+use crate::prefix::IpPrefix;
+use crate::schema::*;
+use anyhow::{bail, Result};
+use std::net::IpAddr;
+
+#[derive(Debug, Clone)]
+pub struct L2NetworkBuilder {
+    name: String,
+    network_type: NetworkType,
+    adapter: Option<AdapterSelector>,
+    host_adapters: Option<Vec<HostAdapter>>,
+    dr_mac: Option<MacAddress>,
+    vlan: Option<u16>,
+    subnets: Vec<(IpPrefix, IpAddr)>,
+}
+
+/// The VLAN IDs that can be assigned, 0 and 4095 are reserved.
+const VLAN_IDS: std::ops::RangeInclusive<u16> = 1..=4094;
+
+impl L2NetworkBuilder {
+    pub fn l2bridge(name: &str) -> Self {
+        Self::new(name, NetworkType::L2Bridge)
+    }
+
+    pub fn l2tunnel(name: &str) -> Self {
+        Self::new(name, NetworkType::L2Tunnel)
+    }
+
+    fn new(name: &str, network_type: NetworkType) -> Self {
+        Self {
+            name: name.to_string(),
+            network_type,
+            adapter: None,
+            host_adapters: None,
+            dr_mac: None,
+            vlan: None,
+            subnets: vec![],
+        }
+    }
+
+    /// The host adapter to attach to.
+    pub fn adapter(mut self, selector: AdapterSelector) -> Self {
+        self.adapter = Some(selector);
+        self
+    }
+
+    /// Select the adapter among these instead of the host's current adapters.
+    pub fn host_adapters(mut self, adapters: Vec<HostAdapter>) -> Self {
+        self.host_adapters = Some(adapters);
+        self
+    }
+
+    /// The MAC of the distributed router, used as the endpoints' gateway MAC.
+    pub fn dr_mac(mut self, mac: MacAddress) -> Self {
+        self.dr_mac = Some(mac);
+        self
+    }
+
+    /// Tag the traffic of every subnet with this VLAN, L2Bridge only.
+    pub fn vlan(mut self, id: u16) -> Self {
+        self.vlan = Some(id);
+        self
+    }
+
+    /// A subnet for endpoints, routed through `gateway`.
+    pub fn subnet(mut self, prefix: IpPrefix, gateway: IpAddr) -> Self {
+        self.subnets.push((prefix, gateway));
+        self
+    }
+
+    pub fn build(self) -> Result<HostComputeNetwork> {
+        let Some(selector) = &self.adapter else {
+            bail!(
+                "{:?} network {} needs an adapter",
+                self.network_type,
+                self.name
+            );
+        };
+        if self.subnets.is_empty() {
+            bail!(
+                "{:?} network {} needs at least one subnet",
+                self.network_type,
+                self.name
+            );
+        }
+        for (prefix, gateway) in &self.subnets {
+            check_gateway(prefix, gateway)?;
+        }
+        if let Some(vlan) = self.vlan {
+            if self.network_type != NetworkType::L2Bridge {
+                bail!(
+                    "{:?} network {} cannot have a VLAN",
+                    self.network_type,
+                    self.name
+                );
+            }
+            if !VLAN_IDS.contains(&vlan) {
+                bail!(
+                    "VLAN {} is outside {}-{}",
+                    vlan,
+                    VLAN_IDS.start(),
+                    VLAN_IDS.end()
+                );
+            }
+        }
+
+        let adapter_name = match &self.host_adapters {
+            Some(adapters) => selector.select(adapters)?.name.clone(),
+            #[cfg(windows)]
+            None => selector.select(&adapter::host_adapters()?)?.name.clone(),
+            #[cfg(not(windows))]
+            None => bail!("the host adapters have to be given off Windows"),
+        };
+
+        let vlan = self.vlan.map(|id| {
+            serde_json::json!({
+                "Type": "VLAN",
+                "Settings": VlanPolicySetting {
+                    isolation_id: id.into(),
+                    ..Default::default()
+                },
+            })
+        });
+        let subnets = self
+            .subnets
+            .iter()
+            .map(|(prefix, gateway)| Subnet {
+                policies: vlan.iter().cloned().collect(),
+                ..Subnet::new(prefix, gateway)
+            })
+            .collect();
+
+        let mut policies = vec![NetworkPolicy::new(
+            NetworkPolicyType::NetAdapterName,
+            &NetAdapterNamePolicySetting {
+                network_adapter_name: adapter_name,
+                ..Default::default()
+            },
+        )?];
+        if let Some(dr_mac) = self.dr_mac {
+            policies.push(NetworkPolicy::new(
+                NetworkPolicyType::DrMacAddress,
+                &DrMacAddressPolicySetting {
+                    address: dr_mac.to_string(),
+                    ..Default::default()
+                },
+            )?);
+        }
+
+        let network = HostComputeNetwork {
+            name: self.name,
+            network_type: Some(self.network_type),
+            policies,
+            ipams: vec![Ipam {
+                subnets,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        network.validate()?;
+        Ok(network)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapter::AdapterAddress;
+
+    fn adapters() -> Vec<HostAdapter> {
+        vec![HostAdapter {
+            name: "Ethernet 2".to_string(),
+            addresses: vec![AdapterAddress {
+                address: "192.168.1.10".parse().unwrap(),
+                prefix_len: 24,
+            }],
+            up: true,
+        }]
+    }
+
+    fn l2bridge() -> L2NetworkBuilder {
+        L2NetworkBuilder::l2bridge("cbr0")
+            .adapter(AdapterSelector::Subnet("192.168.1.0/24".parse().unwrap()))
+            .host_adapters(adapters())
+            .subnet(
+                "10.244.1.0/24".parse().unwrap(),
+                "10.244.1.1".parse().unwrap(),
+            )
+    }
+
+    #[test]
+    fn builds_an_l2bridge() {
+        let network = l2bridge()
+            .dr_mac("00-15-5D-01-02-03".parse().unwrap())
+            .build()
+            .unwrap();
+        assert_eq!(network.network_type, Some(NetworkType::L2Bridge));
+        assert_eq!(
+            serde_json::to_value(&network.policies).unwrap(),
+            serde_json::json!([
+                { "Type": "NetAdapterName", "Settings": { "NetworkAdapterName": "Ethernet 2" } },
+                { "Type": "DrMacAddress", "Settings": { "Address": "00-15-5D-01-02-03" } },
+            ])
+        );
+        assert!(network.ipams[0].subnets[0].policies.is_empty());
+    }
+
+    #[test]
+    fn needs_an_adapter_and_a_subnet() {
+        assert!(L2NetworkBuilder::l2bridge("cbr0")
+            .host_adapters(adapters())
+            .subnet(
+                "10.244.1.0/24".parse().unwrap(),
+                "10.244.1.1".parse().unwrap()
+            )
+            .build()
+            .is_err());
+        assert!(L2NetworkBuilder::l2bridge("cbr0")
+            .adapter(AdapterSelector::Name("Ethernet 2".to_string()))
+            .host_adapters(adapters())
+            .build()
+            .is_err());
+        // no adapter matches
+        assert!(l2bridge()
+            .adapter(AdapterSelector::Name("Ethernet 3".to_string()))
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_bad_subnets() {
+        // the gateway is the subnet's broadcast address
+        assert!(l2bridge()
+            .subnet(
+                "10.244.2.0/24".parse().unwrap(),
+                "10.244.2.255".parse().unwrap()
+            )
+            .build()
+            .is_err());
+        assert!(l2bridge()
+            .subnet(
+                "10.244.0.0/16".parse().unwrap(),
+                "10.244.0.1".parse().unwrap()
+            )
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn vlans() {
+        let network = l2bridge().vlan(100).build().unwrap();
+        assert_eq!(
+            network.ipams[0].subnets[0].policies,
+            vec![serde_json::json!({ "Type": "VLAN", "Settings": { "IsolationId": 100 } })]
+        );
+
+        assert!(l2bridge().vlan(0).build().is_err());
+        assert!(l2bridge().vlan(4094).build().is_ok());
+        assert!(l2bridge().vlan(4095).build().is_err());
+
+        let l2tunnel = L2NetworkBuilder::l2tunnel("tunnel")
+            .adapter(AdapterSelector::Name("Ethernet 2".to_string()))
+            .host_adapters(adapters())
+            .subnet(
+                "10.244.1.0/24".parse().unwrap(),
+                "10.244.1.1".parse().unwrap(),
+            );
+        assert!(l2tunnel.clone().build().is_ok());
+        assert!(l2tunnel.vlan(100).build().is_err());
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn needs_the_host_adapters_off_windows() {
+        assert!(L2NetworkBuilder::l2bridge("cbr0")
+            .adapter(AdapterSelector::Name("Ethernet 2".to_string()))
+            .subnet(
+                "10.244.1.0/24".parse().unwrap(),
+                "10.244.1.1".parse().unwrap()
+            )
+            .build()
+            .is_err());
+    }
+}
@@ -1,23 +1,386 @@
+pub mod acl;
+pub mod adapter;
+#[cfg(windows)]
 pub mod api;
+pub mod cache;
+#[cfg(windows)]
 mod cotask;
+#[cfg(windows)]
+pub mod endpoint;
+pub mod gc;
+pub mod graph;
+pub mod ip_set;
+pub mod ipam;
+pub mod kube;
+pub mod l2;
+pub mod load_balancer;
+pub mod mac;
+#[cfg(windows)]
+pub mod namespace;
+pub mod nat;
+pub mod network;
+#[cfg(feature = "tokio")]
+pub mod nonblocking;
+#[cfg(windows)]
+pub mod notify;
+#[cfg(windows)]
+pub mod object;
+pub mod outbound_nat;
+pub mod overlay;
+pub mod prefix;
+pub mod retry;
 pub mod schema;
+pub mod validate;
 
+#[cfg(windows)]
+use crate::object::{HcnObject, OwnedHandle};
+#[cfg(windows)]
 use crate::schema::*;
-use anyhow::Result;
+#[cfg(windows)]
+use anyhow::{bail, Context, Result};
+#[cfg(windows)]
 use windows::core::GUID;
 
+/// Query HNS for the properties of a single object.
+///
+/// The handle is closed whether or not the query succeeds. Properties that
+/// fail to parse are reported together with the raw JSON HNS returned.
+#[cfg(windows)]
+pub fn get<T: HcnObject>(id: &str) -> Result<T> {
+    let handle = OwnedHandle::<T>::open(id)?;
+    let properties = handle.properties();
+    handle.close()?;
+
+    properties.with_context(|| format!("failed to get {} {}", T::KIND, id))
+}
+
+#[cfg(windows)]
 pub fn get_namespace(id: &str) -> Result<HostComputeNamespace> {
-    let guid = GUID::from(id);
+    get(id)
+}
+
+#[cfg(windows)]
+pub fn get_network(id: &str) -> Result<HostComputeNetwork> {
+    get(id)
+}
+
+#[cfg(windows)]
+pub fn get_endpoint(id: &str) -> Result<HostComputeEndpoint> {
+    get(id)
+}
+
+#[cfg(windows)]
+pub fn get_load_balancer(id: &str) -> Result<HostComputeLoadBalancer> {
+    get(id)
+}
+
+/// Parse an object ID, with or without surrounding braces.
+///
+/// `GUID::from` panics on malformed input, IDs coming from users or
+/// configuration files should go through here instead.
+#[cfg(windows)]
+pub fn parse_guid(id: &str) -> Result<GUID> {
+    let trimmed = id.trim().trim_matches(|c| c == '{' || c == '}');
+    let well_formed = trimmed.len() == 36
+        && trimmed.char_indices().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        });
+    if !well_formed {
+        bail!("invalid object ID {:?}", id);
+    }
+    Ok(GUID::from(trimmed))
+}
+
+/// List every object of a kind known to HNS.
+///
+/// Objects deleted between the enumeration and their query are left out,
+/// any other failure to get one of them fails the whole list. Transient
+/// failures are retried, see [`retry`].
+#[cfg(windows)]
+pub fn list<T: HcnObject>() -> Result<Vec<T>> {
+    let query = serde_json::to_string(&HostComputeQuery::default())?;
+    let ids = retry::RetryPolicy::reads()
+        .retry(&format!("enumerate {}s", T::KIND), || T::enumerate(&query))
+        .with_context(|| format!("failed to enumerate {}s", T::KIND))?;
+    let mut objects = vec![];
+    for id in enumerated_ids(&ids)? {
+        match get(&id) {
+            Ok(object) => objects.push(object),
+            Err(e) if is_not_found(&e) => {
+                log::debug!("{} {} is gone, skipping it: {:#}", T::KIND, id, e)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(objects)
+}
+
+/// Whether an error says the object does not exist (anymore). Networks and
+/// endpoints have their own codes, namespaces and load balancers report
+/// `ERROR_NOT_FOUND`.
+#[cfg(windows)]
+pub fn is_not_found(error: &anyhow::Error) -> bool {
+    use windows::core::HRESULT;
+    use windows::Win32::Foundation::{
+        ERROR_NOT_FOUND, HCN_E_ENDPOINT_NOT_FOUND, HCN_E_NETWORK_NOT_FOUND,
+    };
+
+    error
+        .chain()
+        .filter_map(|e| e.downcast_ref::<windows::core::Error>())
+        .any(|e| {
+            let code = e.code();
+            code == HCN_E_NETWORK_NOT_FOUND
+                || code == HCN_E_ENDPOINT_NOT_FOUND
+                || code == HRESULT::from_win32(ERROR_NOT_FOUND.0)
+        })
+}
+
+/// Apply a modify request to an object. The handle is always closed.
+#[cfg(windows)]
+pub fn modify<T: HcnObject>(id: &str, settings: &str) -> Result<()> {
+    let handle = OwnedHandle::<T>::open(id)?;
+    let result = handle.modify(settings);
+    handle.close()?;
+
+    result.with_context(|| format!("failed to modify {} {}", T::KIND, id))
+}
+
+/// Create an object and query it, since HNS does not return what it creates.
+///
+/// `create` is called with the ID for the new object: `id`, or a new one when
+/// that is empty. The handle it returns is always closed. An object that
+/// cannot be queried is deleted again rather than left behind.
+#[cfg(windows)]
+pub(crate) fn create<T: HcnObject>(
+    id: &str,
+    create: impl FnOnce(&GUID) -> Result<T::Handle>,
+) -> Result<T> {
+    let guid = match id {
+        "" => GUID::new()?,
+        id => parse_guid(id)?,
+    };
+    // SAFETY: HNS just opened the handle and it is not shared
+    let handle = unsafe { OwnedHandle::<T>::from_raw(create(&guid)?) };
+    let properties = handle.properties();
+    // the object exists either way, failing to close is only logged
+    drop(handle);
+
+    properties.inspect_err(|_| {
+        if let Err(e) = T::delete(&guid) {
+            log::warn!(
+                "failed to delete {} {:?} after it could not be queried: {}",
+                T::KIND,
+                guid,
+                e
+            );
+        }
+    })
+}
+
+/// Delete an object by ID.
+#[cfg(windows)]
+pub fn delete<T: HcnObject>(id: &str) -> Result<()> {
+    T::delete(&parse_guid(id)?).with_context(|| format!("failed to delete {} {}", T::KIND, id))
+}
+
+/// List every network known to HNS.
+#[cfg(windows)]
+pub fn list_networks() -> Result<Vec<HostComputeNetwork>> {
+    list()
+}
+
+/// List every namespace known to HNS.
+#[cfg(windows)]
+pub fn list_namespaces() -> Result<Vec<HostComputeNamespace>> {
+    list()
+}
+
+/// List every endpoint known to HNS, including remote endpoints.
+#[cfg(windows)]
+pub fn list_endpoints() -> Result<Vec<HostComputeEndpoint>> {
+    list()
+}
+
+/// List every load balancer known to HNS.
+#[cfg(windows)]
+pub fn list_load_balancers() -> Result<Vec<HostComputeLoadBalancer>> {
+    list()
+}
+
+// The enumerate calls only return a JSON array of object IDs, the objects
+// themselves have to be opened and queried one by one.
+#[cfg(windows)]
+fn enumerated_ids(raw: &str) -> Result<Vec<String>> {
+    log::debug!("raw ids: {}", raw);
+    if raw.is_empty() {
+        return Ok(vec![]);
+    }
+    Ok(serde_json::from_str(raw)?)
+}
+
+#[cfg(all(test, windows))]
+mod tests {
+    use super::*;
+    use crate::graph::ObjectKind;
+    use serde::Deserialize;
+    use std::cell::RefCell;
+    use std::collections::{BTreeMap, BTreeSet};
+    use windows::core::HRESULT;
+    use windows::Win32::Foundation::{E_ACCESSDENIED, E_FAIL, HCN_E_NETWORK_NOT_FOUND};
+
+    // An HNS holding objects of one kind, per test thread.
+    #[derive(Default)]
+    struct FakeHns {
+        objects: BTreeSet<String>,
+        open_handles: usize,
+        fail_queries: bool,
+        deleted: Vec<String>,
+        // enumerated, but opening them fails with the code
+        unopenable: BTreeMap<String, HRESULT>,
+    }
+
+    thread_local! {
+        static HNS: RefCell<FakeHns> = RefCell::default();
+    }
+
+    fn hns<R>(f: impl FnOnce(&mut FakeHns) -> R) -> R {
+        HNS.with(|hns| f(&mut hns.borrow_mut()))
+    }
+
+    fn guid_string(id: &GUID) -> String {
+        format!("{:?}", id).to_ascii_lowercase()
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct FakeObject {
+        #[serde(rename = "ID")]
+        id: String,
+    }
+
+    impl HcnObject for FakeObject {
+        type Handle = GUID;
+
+        const KIND: ObjectKind = ObjectKind::Network;
+
+        fn enumerate(_query: &str) -> Result<String> {
+            let mut ids = hns(|hns| hns.objects.clone());
+            ids.extend(hns(|hns| {
+                hns.unopenable.keys().cloned().collect::<Vec<_>>()
+            }));
+            Ok(serde_json::to_string(&ids)?)
+        }
+
+        fn open(id: &GUID) -> Result<GUID> {
+            if let Some(code) = hns(|hns| hns.unopenable.get(&guid_string(id)).copied()) {
+                return Err(windows::core::Error::from_hresult(code).into());
+            }
+            hns(|hns| hns.open_handles += 1);
+            Ok(*id)
+        }
+
+        fn query(handle: GUID, _query: &str) -> Result<String> {
+            if hns(|hns| hns.fail_queries) {
+                return Err(windows::core::Error::from_hresult(E_FAIL).into());
+            }
+            Ok(format!(r#"{{"ID":"{}"}}"#, guid_string(&handle)))
+        }
+
+        fn modify(_handle: GUID, _settings: &str) -> Result<()> {
+            Ok(())
+        }
+
+        fn delete(id: &GUID) -> Result<()> {
+            hns(|hns| {
+                hns.objects.remove(&guid_string(id));
+                hns.deleted.push(guid_string(id));
+            });
+            Ok(())
+        }
+
+        fn close(_handle: GUID) -> Result<()> {
+            hns(|hns| hns.open_handles -= 1);
+            Ok(())
+        }
+    }
+
+    fn fake_create(id: &GUID) -> Result<GUID> {
+        hns(|hns| {
+            hns.objects.insert(guid_string(id));
+            hns.open_handles += 1;
+        });
+        Ok(*id)
+    }
+
+    const ID: &str = "0b1c8a2e-6d3f-4e5a-9b7c-1d2e3f4a5b6c";
+
+    #[test]
+    fn create_queries_the_new_object() {
+        let object: FakeObject = create(ID, fake_create).unwrap();
+
+        assert_eq!(object.id, ID);
+        hns(|hns| {
+            assert_eq!(hns.open_handles, 0);
+            assert!(hns.objects.contains(ID));
+        });
+    }
+
+    #[test]
+    fn create_without_an_id_makes_one() {
+        let object: FakeObject = create("", fake_create).unwrap();
+        assert!(parse_guid(&object.id).is_ok());
+    }
+
+    #[test]
+    fn create_deletes_what_cannot_be_queried() {
+        hns(|hns| hns.fail_queries = true);
+
+        assert!(create::<FakeObject>(ID, fake_create).is_err());
+        hns(|hns| {
+            assert_eq!(hns.open_handles, 0);
+            assert!(hns.objects.is_empty());
+            assert_eq!(hns.deleted, vec![ID.to_string()]);
+        });
+    }
+
+    const GONE: &str = "5f6e7d8c-9b0a-4c1d-8e2f-3a4b5c6d7e8f";
+
+    #[test]
+    fn list_skips_objects_deleted_meanwhile() {
+        hns(|hns| {
+            hns.objects.insert(ID.to_string());
+            hns.unopenable
+                .insert(GONE.to_string(), HCN_E_NETWORK_NOT_FOUND);
+        });
 
-    let namespace_handle = api::open_namespace(&guid)?;
+        let objects = list::<FakeObject>().unwrap();
+        assert_eq!(
+            objects.iter().map(|o| o.id.as_str()).collect::<Vec<_>>(),
+            vec![ID]
+        );
+        hns(|hns| assert_eq!(hns.open_handles, 0));
+    }
 
-    let query = HostComputeQuery::default();
-    let query = serde_json::to_string(&query).unwrap();
+    #[test]
+    fn list_fails_on_other_errors() {
+        hns(|hns| {
+            hns.objects.insert(ID.to_string());
+            hns.unopenable.insert(GONE.to_string(), E_ACCESSDENIED);
+        });
 
-    let name_space = api::query_namespace_properties(namespace_handle, &query)?;
-    log::debug!("raw namespace: {}", name_space);
-    let name_space: HostComputeNamespace = serde_json::from_str(&name_space).unwrap();
-    api::close_namespace(namespace_handle)?;
+        let error = list::<FakeObject>().unwrap_err();
+        assert!(!is_not_found(&error));
+        hns(|hns| assert_eq!(hns.open_handles, 0));
+    }
 
-    Ok(name_space)
+    #[test]
+    fn not_found_is_seen_through_context() {
+        let error = anyhow::Error::from(windows::core::Error::from_hresult(HRESULT::from_win32(
+            windows::Win32::Foundation::ERROR_NOT_FOUND.0,
+        )))
+        .context("failed to get load balancer");
+        assert!(is_not_found(&error));
+        assert!(!is_not_found(&anyhow::anyhow!("not found")));
+    }
 }
@@ -0,0 +1,436 @@
+//! Load balancers, with or without Direct Server Return.
+//!
+//! Without DSR replies go back through the load balancer. With DSR backends
+//! answer clients directly, which needs a source VIP: an endpoint on the
+//! backends' network whose address the load balanced traffic appears to come
+//! from. This is what kube-proxy sets up for Services on overlay networks.
+
+#[cfg(windows)]
+use crate::api;
+use crate::graph::same_id;
+use crate::nat::Protocol;
+use crate::schema::*;
+use anyhow::{bail, Context, Result};
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, Default)]
+pub struct LoadBalancerBuilder {
+    frontend_vips: Vec<IpAddr>,
+    backends: Vec<String>,
+    source_vip: Option<IpAddr>,
+    port_mappings: Vec<(Protocol, u16, u16)>,
+    dsr: bool,
+    local_only: bool,
+    port_mapping_flags: LoadBalancerPortMappingFlags,
+    distribution: LoadBalancerDistribution,
+    endpoints: Option<Vec<HostComputeEndpoint>>,
+}
+
+impl LoadBalancerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// An address clients connect to. Without any, the load balancer applies
+    /// to traffic addressed to the host.
+    pub fn frontend_vip(mut self, vip: IpAddr) -> Self {
+        self.frontend_vips.push(vip);
+        self
+    }
+
+    /// The id of an endpoint traffic is balanced across.
+    pub fn backend(mut self, endpoint_id: &str) -> Self {
+        self.backends.push(endpoint_id.to_string());
+        self
+    }
+
+    pub fn source_vip(mut self, vip: IpAddr) -> Self {
+        self.source_vip = Some(vip);
+        self
+    }
+
+    /// Balance `external_port` on the frontend VIPs to `internal_port` on the backends.
+    pub fn port_mapping(
+        mut self,
+        protocol: Protocol,
+        external_port: u16,
+        internal_port: u16,
+    ) -> Self {
+        self.port_mappings
+            .push((protocol, external_port, internal_port));
+        self
+    }
+
+    pub fn dsr(mut self, dsr: bool) -> Self {
+        self.dsr = dsr;
+        self
+    }
+
+    /// Only balance to backends on this host, dropping remote endpoints, like
+    /// a Service with `externalTrafficPolicy: Local`.
+    pub fn local_only(mut self, local_only: bool) -> Self {
+        self.local_only = local_only;
+        self
+    }
+
+    /// `LOAD_BALANCER_PORT_MAPPING_FLAGS_*` applied to every port mapping.
+    pub fn port_mapping_flags(mut self, flags: LoadBalancerPortMappingFlags) -> Self {
+        self.port_mapping_flags = flags;
+        self
+    }
+
+    /// How connections are spread across backends. `SourceIP` keeps a client
+    /// on the same backend, i.e. session affinity.
+    pub fn distribution(mut self, distribution: LoadBalancerDistribution) -> Self {
+        self.distribution = distribution;
+        self
+    }
+
+    /// Look up the source VIP and backends in these endpoints instead of the
+    /// ones HNS currently has.
+    pub fn endpoints(mut self, endpoints: Vec<HostComputeEndpoint>) -> Self {
+        self.endpoints = Some(endpoints);
+        self
+    }
+
+    pub fn build(mut self) -> Result<HostComputeLoadBalancer> {
+        if self.port_mappings.is_empty() {
+            bail!("load balancer needs at least one port mapping");
+        }
+
+        // only needed to look up backends and the source VIP
+        let endpoints = match self.endpoints.take() {
+            Some(endpoints) => endpoints,
+            #[cfg(windows)]
+            None if self.dsr || self.local_only => crate::list_endpoints()?,
+            #[cfg(not(windows))]
+            None if self.dsr || self.local_only => {
+                bail!("the endpoints have to be given to look up backends off Windows")
+            }
+            None => vec![],
+        };
+        if self.local_only {
+            let remote = |id: &String| {
+                endpoints
+                    .iter()
+                    .any(|e| same_id(&e.id, id) && e.is_remote())
+            };
+            self.backends.retain(|id| !remote(id));
+            if self.backends.is_empty() {
+                bail!("load balancer has no local backends");
+            }
+        }
+
+        let mut vips = self.frontend_vips.iter().chain(&self.source_vip);
+        let ipv6 = vips.next().is_some_and(|vip| vip.is_ipv6());
+        if let Some(vip) = vips.find(|vip| vip.is_ipv6() != ipv6) {
+            bail!("load balancer VIPs mix address families at {}", vip);
+        }
+
+        let mut flags = LOAD_BALANCER_FLAGS_NONE;
+        if self.dsr {
+            flags |= LOAD_BALANCER_FLAGS_DSR;
+        }
+        if ipv6 {
+            flags |= LOAD_BALANCER_FLAGS_IPV6;
+        }
+        let port_mapping_flags = (self.port_mapping_flags != LOAD_BALANCER_PORT_MAPPING_FLAGS_NONE)
+            .then_some(self.port_mapping_flags);
+
+        let load_balancer = HostComputeLoadBalancer {
+            host_compute_endpoints: self.backends,
+            source_vip: self.source_vip.map(|v| v.to_string()).unwrap_or_default(),
+            frontend_vips: self.frontend_vips.iter().map(|v| v.to_string()).collect(),
+            port_mappings: self
+                .port_mappings
+                .iter()
+                .map(|(protocol, external, internal)| LoadBalancerPortMapping {
+                    protocol: Some(protocol.number()),
+                    internal_port: Some(*internal),
+                    external_port: Some(*external),
+                    distribution_type: Some(self.distribution),
+                    flags: port_mapping_flags,
+                    ..Default::default()
+                })
+                .collect(),
+            flags: (flags != LOAD_BALANCER_FLAGS_NONE).then_some(flags),
+            ..Default::default()
+        };
+        load_balancer.validate()?;
+
+        if load_balancer.is_dsr() {
+            check_source_vip(&load_balancer, &endpoints)?;
+        }
+        Ok(load_balancer)
+    }
+}
+
+/// Check that the source VIP of a DSR load balancer belongs to one of
+/// `endpoints`, on the same network as every backend.
+pub fn check_source_vip(
+    load_balancer: &HostComputeLoadBalancer,
+    endpoints: &[HostComputeEndpoint],
+) -> Result<()> {
+    let source_vip: IpAddr = load_balancer
+        .source_vip
+        .parse()
+        .with_context(|| format!("invalid source VIP {}", load_balancer.source_vip))?;
+    let has_vip = |e: &&HostComputeEndpoint| {
+        e.ip_configurations
+            .iter()
+            .any(|c| c.ip_address.parse::<IpAddr>().ok() == Some(source_vip))
+    };
+    let Some(source) = endpoints.iter().find(has_vip) else {
+        bail!("no endpoint has the source VIP {}", source_vip);
+    };
+
+    for backend in &load_balancer.host_compute_endpoints {
+        let Some(endpoint) = endpoints.iter().find(|e| same_id(&e.id, backend)) else {
+            bail!("backend endpoint {} does not exist", backend);
+        };
+        if !same_id(&endpoint.host_compute_network, &source.host_compute_network) {
+            bail!(
+                "backend endpoint {} is on network {} but the source VIP {} is on {}",
+                backend,
+                endpoint.host_compute_network,
+                source_vip,
+                source.host_compute_network
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Validate and create a load balancer, returning it as HNS reports it.
+#[cfg(windows)]
+pub fn create(load_balancer: &HostComputeLoadBalancer) -> Result<HostComputeLoadBalancer> {
+    load_balancer.validate()?;
+    let settings = serde_json::to_string(load_balancer)?;
+
+    crate::create(&load_balancer.id, |id| {
+        api::create_load_balancer(id, &settings)
+    })
+    .context("failed to create load balancer")
+}
+
+/// Take endpoints out of an existing load balancer's backends, returning it
+/// as HNS reports it afterwards.
+///
+/// HNS cannot change the backends of a load balancer in place, so like
+/// hcsshim this deletes it and creates it again with the same ID.
+#[cfg(windows)]
+pub fn remove_backends(
+    load_balancer: &HostComputeLoadBalancer,
+    endpoint_ids: &[String],
+) -> Result<HostComputeLoadBalancer> {
+    recreate_without(load_balancer, endpoint_ids, &mut Hns)
+}
+
+// The HNS calls replacing a load balancer makes, replaced in tests.
+#[cfg(any(windows, test))]
+trait Recreate {
+    fn delete(&mut self, id: &str) -> Result<()>;
+    fn create(
+        &mut self,
+        load_balancer: &HostComputeLoadBalancer,
+    ) -> Result<HostComputeLoadBalancer>;
+}
+
+#[cfg(windows)]
+struct Hns;
+
+#[cfg(windows)]
+impl Recreate for Hns {
+    fn delete(&mut self, id: &str) -> Result<()> {
+        crate::delete::<HostComputeLoadBalancer>(id)
+    }
+
+    fn create(
+        &mut self,
+        load_balancer: &HostComputeLoadBalancer,
+    ) -> Result<HostComputeLoadBalancer> {
+        create(load_balancer)
+    }
+}
+
+#[cfg(any(windows, test))]
+fn recreate_without(
+    load_balancer: &HostComputeLoadBalancer,
+    endpoint_ids: &[String],
+    hns: &mut impl Recreate,
+) -> Result<HostComputeLoadBalancer> {
+    let mut remaining = load_balancer.clone();
+    remaining
+        .host_compute_endpoints
+        .retain(|backend| !endpoint_ids.iter().any(|id| same_id(id, backend)));
+    if remaining.host_compute_endpoints.is_empty() {
+        bail!(
+            "load balancer {} would have no backends left",
+            load_balancer.id
+        );
+    }
+    // only delete what can be created again
+    remaining.validate()?;
+
+    hns.delete(&load_balancer.id)?;
+    hns.create(&remaining).with_context(|| {
+        format!(
+            "failed to recreate load balancer {} without the removed backends",
+            load_balancer.id
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NETWORK: &str = "{5F0B1C9E-2D3A-4B6C-8E7F-1A2B3C4D5E6F}";
+    const OTHER_NETWORK: &str = "9A8B7C6D-5E4F-4A3B-2C1D-0E9F8A7B6C5D";
+
+    fn endpoint(id: &str, network: &str, ip: &str) -> HostComputeEndpoint {
+        HostComputeEndpoint {
+            id: id.to_string(),
+            host_compute_network: network.to_string(),
+            ip_configurations: vec![IpConfig::new(&ip.parse().unwrap(), 24)],
+            ..Default::default()
+        }
+    }
+
+    // HNS reports IDs upper case without braces, callers pass them any way.
+    fn endpoints() -> Vec<HostComputeEndpoint> {
+        let mut remote = endpoint("E3", &NETWORK[1..37], "10.244.2.5");
+        remote.flags = Some(ENDPOINT_FLAGS_REMOTE_ENDPOINT);
+        vec![
+            endpoint("SOURCE", &NETWORK.to_lowercase(), "10.244.1.2"),
+            endpoint("E1", NETWORK, "10.244.1.5"),
+            endpoint("E2", &NETWORK[1..37], "10.244.1.6"),
+            remote,
+            endpoint("E4", OTHER_NETWORK, "10.245.1.5"),
+        ]
+    }
+
+    fn dsr(backends: &[&str]) -> LoadBalancerBuilder {
+        let builder = LoadBalancerBuilder::new()
+            .frontend_vip("10.96.0.10".parse().unwrap())
+            .source_vip("10.244.1.2".parse().unwrap())
+            .port_mapping(Protocol::Tcp, 80, 8080)
+            .dsr(true)
+            .endpoints(endpoints());
+        backends.iter().fold(builder, |b, id| b.backend(id))
+    }
+
+    #[test]
+    fn source_vip_on_the_backends_network() {
+        let load_balancer = dsr(&["{e1}", "e2", "E3"]).build().unwrap();
+        assert!(load_balancer.is_dsr());
+        check_source_vip(&load_balancer, &endpoints()).unwrap();
+    }
+
+    #[test]
+    fn source_vip_errors() {
+        let error = |backends: &[&str]| dsr(backends).build().unwrap_err().to_string();
+        assert!(error(&["e1", "E4"]).contains("is on network"));
+        assert!(error(&["e1", "e5"]).contains("does not exist"));
+
+        let mut load_balancer = dsr(&["e1"]).build().unwrap();
+        load_balancer.source_vip = "10.244.1.3".to_string();
+        assert!(check_source_vip(&load_balancer, &endpoints()).is_err());
+        load_balancer.source_vip = "not an address".to_string();
+        assert!(check_source_vip(&load_balancer, &endpoints()).is_err());
+    }
+
+    #[test]
+    fn local_only_drops_remote_backends() {
+        let load_balancer = LoadBalancerBuilder::new()
+            .port_mapping(Protocol::Tcp, 80, 8080)
+            .backend("{e1}")
+            .backend("{e3}")
+            .local_only(true)
+            .endpoints(endpoints())
+            .build()
+            .unwrap();
+        assert_eq!(load_balancer.host_compute_endpoints, vec!["{e1}"]);
+
+        assert!(LoadBalancerBuilder::new()
+            .port_mapping(Protocol::Tcp, 80, 8080)
+            .backend("{e3}")
+            .local_only(true)
+            .endpoints(endpoints())
+            .build()
+            .is_err());
+    }
+
+    // Records the calls and recreates the load balancer as given.
+    #[derive(Default)]
+    struct FakeHns {
+        calls: Vec<String>,
+        fail_create: bool,
+    }
+
+    impl Recreate for FakeHns {
+        fn delete(&mut self, id: &str) -> Result<()> {
+            self.calls.push(format!("delete {}", id));
+            Ok(())
+        }
+
+        fn create(
+            &mut self,
+            load_balancer: &HostComputeLoadBalancer,
+        ) -> Result<HostComputeLoadBalancer> {
+            self.calls.push(format!(
+                "create {} {}",
+                load_balancer.id,
+                load_balancer.host_compute_endpoints.join(",")
+            ));
+            if self.fail_create {
+                bail!("HNS refused");
+            }
+            Ok(load_balancer.clone())
+        }
+    }
+
+    fn existing() -> HostComputeLoadBalancer {
+        HostComputeLoadBalancer {
+            id: "LB1".to_string(),
+            ..dsr(&["E1", "E2", "E3"]).build().unwrap()
+        }
+    }
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn remove_backends_deletes_then_recreates() {
+        let mut hns = FakeHns::default();
+        let recreated = recreate_without(&existing(), &ids(&["{e2}", "e9"]), &mut hns).unwrap();
+
+        assert_eq!(hns.calls, vec!["delete LB1", "create LB1 E1,E3"]);
+        assert_eq!(recreated.id, "LB1");
+        assert_eq!(recreated.source_vip, "10.244.1.2");
+        assert_eq!(recreated.host_compute_endpoints, vec!["E1", "E3"]);
+    }
+
+    #[test]
+    fn remove_backends_keeps_what_cannot_be_recreated() {
+        let mut hns = FakeHns::default();
+        assert!(recreate_without(&existing(), &ids(&["e1", "e2", "e3"]), &mut hns).is_err());
+
+        let mut invalid = existing();
+        invalid.source_vip = String::new();
+        assert!(recreate_without(&invalid, &ids(&["e1"]), &mut hns).is_err());
+        assert!(hns.calls.is_empty());
+    }
+
+    #[test]
+    fn remove_backends_reports_a_failed_recreate() {
+        let mut hns = FakeHns {
+            fail_create: true,
+            ..Default::default()
+        };
+        let error = recreate_without(&existing(), &ids(&["e1"]), &mut hns).unwrap_err();
+        assert!(format!("{:#}", error).contains("failed to recreate load balancer LB1"));
+        assert_eq!(hns.calls, vec!["delete LB1", "create LB1 E2,E3"]);
+    }
+}
@@ -0,0 +1,342 @@
+//! MAC addresses in the dashed format HNS uses (`00-15-5D-00-00-01`) and an
+//! allocator over a network's [`MacPool`].
+
+use crate::schema::*;
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// The prefix overlay networks put in front of an endpoint's IPv4 address to
+/// form its MAC, so that `10.244.1.5` gets `0E-2A-0A-F4-01-05`.
+pub const OVERLAY_MAC_PREFIX: [u8; 2] = [0x0E, 0x2A];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MacAddress([u8; 6]);
+
+impl MacAddress {
+    pub const fn new(octets: [u8; 6]) -> Self {
+        Self(octets)
+    }
+
+    pub fn octets(&self) -> [u8; 6] {
+        self.0
+    }
+
+    /// Derive a MAC from an IP address: the two prefix bytes followed by the
+    /// last four bytes of the address. See [`OVERLAY_MAC_PREFIX`].
+    pub fn from_ip(prefix: [u8; 2], ip: &IpAddr) -> Self {
+        let tail = match ip {
+            IpAddr::V4(ip) => ip.octets(),
+            IpAddr::V6(ip) => {
+                let octets = ip.octets();
+                [octets[12], octets[13], octets[14], octets[15]]
+            }
+        };
+        Self([prefix[0], prefix[1], tail[0], tail[1], tail[2], tail[3]])
+    }
+
+    fn to_u64(self) -> u64 {
+        self.0.iter().fold(0, |acc, b| (acc << 8) | *b as u64)
+    }
+
+    fn from_u64(value: u64) -> Self {
+        let bytes = value.to_be_bytes();
+        Self([bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7]])
+    }
+}
+
+impl FromStr for MacAddress {
+    type Err = anyhow::Error;
+
+    /// Parse a MAC separated by dashes, as HNS returns them, or colons, but
+    /// not a mix of both.
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let separator = if s.contains('-') { '-' } else { ':' };
+        let parts: Vec<&str> = s.split(separator).collect();
+        if parts.len() != 6 {
+            bail!("invalid MAC address {}", s);
+        }
+
+        let mut octets = [0u8; 6];
+        for (octet, part) in octets.iter_mut().zip(parts) {
+            // from_str_radix would also take a sign
+            if part.len() != 2 || !part.chars().all(|c| c.is_ascii_hexdigit()) {
+                bail!("invalid MAC address {}", s);
+            }
+            *octet = u8::from_str_radix(part, 16)
+                .with_context(|| format!("invalid MAC address {}", s))?;
+        }
+        Ok(Self(octets))
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02X}-{:02X}-{:02X}-{:02X}-{:02X}-{:02X}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+/// Parse the start and end of a [`MacRange`].
+pub fn parse_range(range: &MacRange) -> Result<(MacAddress, MacAddress)> {
+    let (Some(start), Some(end)) = (&range.start_mac_address, &range.end_mac_address) else {
+        bail!("MAC range is missing its start or end address");
+    };
+    let start: MacAddress = start.parse()?;
+    let end: MacAddress = end.parse()?;
+    if start > end {
+        bail!("MAC range {} - {} ends before it starts", start, end);
+    }
+    Ok((start, end))
+}
+
+/// Indexes of every pair of ranges in the pool that overlap.
+pub fn overlapping_ranges(pool: &MacPool) -> Result<Vec<(usize, usize)>> {
+    let ranges = pool
+        .ranges
+        .iter()
+        .flatten()
+        .map(parse_range)
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut overlaps = vec![];
+    for (i, (start_a, end_a)) in ranges.iter().enumerate() {
+        for (j, (start_b, end_b)) in ranges.iter().enumerate().skip(i + 1) {
+            if start_a <= end_b && start_b <= end_a {
+                overlaps.push((i, j));
+            }
+        }
+    }
+    Ok(overlaps)
+}
+
+/// Hands out MAC addresses from the ranges of a [`MacPool`], in range order.
+#[derive(Debug)]
+pub struct MacAllocator {
+    ranges: Vec<(u64, u64)>,
+    in_use: BTreeSet<MacAddress>,
+}
+
+impl MacAllocator {
+    /// Build an allocator over `pool`. Malformed or overlapping ranges are an error.
+    pub fn new(pool: &MacPool) -> Result<Self> {
+        if let Some((a, b)) = overlapping_ranges(pool)?.first() {
+            bail!("MAC ranges {} and {} overlap", a, b);
+        }
+
+        let ranges: Vec<(u64, u64)> = pool
+            .ranges
+            .iter()
+            .flatten()
+            .map(|r| parse_range(r).map(|(start, end)| (start.to_u64(), end.to_u64())))
+            .collect::<Result<_>>()?;
+        if ranges.is_empty() {
+            bail!("MAC pool has no ranges");
+        }
+
+        Ok(Self {
+            ranges,
+            in_use: BTreeSet::new(),
+        })
+    }
+
+    /// Mark the MACs of existing endpoints as used.
+    pub fn seed_from_endpoints(&mut self, endpoints: &[HostComputeEndpoint]) {
+        self.in_use.extend(
+            endpoints
+                .iter()
+                .filter_map(|e| e.mac_address.parse::<MacAddress>().ok()),
+        );
+    }
+
+    /// The pool's MACs that are not in use, in range order.
+    pub fn available(&self) -> impl Iterator<Item = MacAddress> + '_ {
+        self.ranges
+            .iter()
+            .flat_map(|(start, end)| (*start..=*end).map(MacAddress::from_u64))
+            .filter(|mac| !self.in_use.contains(mac))
+    }
+
+    pub fn allocate(&mut self) -> Result<MacAddress> {
+        let Some(mac) = self.available().next() else {
+            bail!("MAC pool is exhausted");
+        };
+        self.in_use.insert(mac);
+        Ok(mac)
+    }
+
+    /// Claim a specific MAC, e.g. one derived with [`MacAddress::from_ip`].
+    pub fn reserve(&mut self, mac: MacAddress) -> Result<()> {
+        if !self.contains(&mac) {
+            bail!("{} is outside the MAC pool", mac);
+        }
+        if !self.in_use.insert(mac) {
+            bail!("{} is already in use", mac);
+        }
+        Ok(())
+    }
+
+    /// Return a MAC to the pool. Returns false if it was not in use.
+    pub fn release(&mut self, mac: &MacAddress) -> bool {
+        self.in_use.remove(mac)
+    }
+
+    pub fn contains(&self, mac: &MacAddress) -> bool {
+        let value = mac.to_u64();
+        self.ranges
+            .iter()
+            .any(|(start, end)| (*start..=*end).contains(&value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mac(s: &str) -> MacAddress {
+        s.parse().unwrap()
+    }
+
+    fn range(start: &str, end: &str) -> MacRange {
+        MacRange {
+            start_mac_address: Some(start.to_string()),
+            end_mac_address: Some(end.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn pool(ranges: &[(&str, &str)]) -> MacPool {
+        MacPool {
+            ranges: Some(ranges.iter().map(|(s, e)| range(s, e)).collect()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parse_and_format() {
+        let expected = MacAddress::new([0x00, 0x15, 0x5d, 0x0a, 0xbc, 0xff]);
+        assert_eq!(mac("00-15-5D-0A-BC-FF"), expected);
+        assert_eq!(mac("00:15:5d:0a:bc:ff"), expected);
+        assert_eq!(mac(" 00-15-5d-0A-bc-Ff "), expected);
+        assert_eq!(expected.to_string(), "00-15-5D-0A-BC-FF");
+        assert_eq!(mac(&expected.to_string()), expected);
+    }
+
+    #[test]
+    fn malformed() {
+        for invalid in [
+            "",
+            "00-15-5D-0A-BC",
+            "00-15-5D-0A-BC-FF-01",
+            "00-15-5D-0A-BC-F",
+            "00-15-5D-0A-BC-FFF",
+            "00-15-5D-0A-BC-GG",
+            "00-15-5D-0A-BC-+F",
+            "00155D0ABCFF",
+            "00-15-5D-0A-BC-",
+            // mixed separators
+            "00-15-5D:0A:BC:FF",
+            "00:15:5D:0A:BC-FF",
+        ] {
+            assert!(invalid.parse::<MacAddress>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn from_ip() {
+        assert_eq!(
+            MacAddress::from_ip(OVERLAY_MAC_PREFIX, &"10.244.1.5".parse().unwrap()).to_string(),
+            "0E-2A-0A-F4-01-05"
+        );
+        assert_eq!(
+            MacAddress::from_ip(OVERLAY_MAC_PREFIX, &"fd00::a:b0c:d0e".parse().unwrap()),
+            MacAddress::new([0x0e, 0x2a, 0x0b, 0x0c, 0x0d, 0x0e])
+        );
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(
+            parse_range(&range("00-15-5D-00-00-00", "00-15-5D-00-00-FF")).unwrap(),
+            (mac("00-15-5D-00-00-00"), mac("00-15-5D-00-00-FF"))
+        );
+        // a single MAC is a valid range, an inverted one is not
+        assert!(parse_range(&range("00-15-5D-00-00-01", "00-15-5D-00-00-01")).is_ok());
+        assert!(parse_range(&range("00-15-5D-00-00-02", "00-15-5D-00-00-01")).is_err());
+        assert!(parse_range(&range("00-15-5D-00-00-01", "not a mac")).is_err());
+        assert!(parse_range(&MacRange {
+            start_mac_address: Some("00-15-5D-00-00-01".to_string()),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn overlaps() {
+        let ranges = pool(&[
+            ("00-15-5D-00-00-00", "00-15-5D-00-00-0F"),
+            // shares its first MAC with the end of the first range
+            ("00-15-5D-00-00-0F", "00-15-5D-00-00-1F"),
+            // right after the second, no overlap
+            ("00-15-5D-00-00-20", "00-15-5D-00-00-2F"),
+            // inside the first
+            ("00-15-5D-00-00-04", "00-15-5D-00-00-04"),
+        ]);
+        assert_eq!(overlapping_ranges(&ranges).unwrap(), vec![(0, 1), (0, 3)]);
+        assert!(MacAllocator::new(&ranges).is_err());
+        assert_eq!(overlapping_ranges(&MacPool::default()).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn allocator_exhaustion_and_reuse() {
+        let mut allocator = MacAllocator::new(&pool(&[
+            ("00-15-5D-00-00-FE", "00-15-5D-00-01-00"),
+            ("00-15-5D-00-02-00", "00-15-5D-00-02-00"),
+        ]))
+        .unwrap();
+        let allocated: Vec<String> = (0..4)
+            .map(|_| allocator.allocate().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            allocated,
+            vec![
+                "00-15-5D-00-00-FE",
+                "00-15-5D-00-00-FF",
+                "00-15-5D-00-01-00",
+                "00-15-5D-00-02-00"
+            ]
+        );
+        assert!(allocator.allocate().is_err());
+
+        assert!(allocator.release(&mac("00-15-5D-00-00-FF")));
+        assert!(!allocator.release(&mac("00-15-5D-00-00-FF")));
+        assert_eq!(allocator.allocate().unwrap(), mac("00-15-5D-00-00-FF"));
+    }
+
+    #[test]
+    fn allocator_reserve_and_seed() {
+        let mut allocator =
+            MacAllocator::new(&pool(&[("00-15-5D-00-00-00", "00-15-5D-00-00-03")])).unwrap();
+        allocator.seed_from_endpoints(&[HostComputeEndpoint {
+            mac_address: "00-15-5D-00-00-00".to_string(),
+            ..Default::default()
+        }]);
+        allocator.reserve(mac("00-15-5D-00-00-01")).unwrap();
+        assert!(allocator.reserve(mac("00-15-5D-00-00-01")).is_err());
+        assert!(allocator.reserve(mac("00-15-5D-00-00-04")).is_err());
+
+        assert_eq!(allocator.allocate().unwrap(), mac("00-15-5D-00-00-02"));
+        assert_eq!(
+            allocator.available().collect::<Vec<_>>(),
+            vec![mac("00-15-5D-00-00-03")]
+        );
+        assert!(MacAllocator::new(&MacPool::default()).is_err());
+    }
+}
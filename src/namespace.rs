@@ -0,0 +1,109 @@
+//! High level operations on HNS namespaces.
+
+use crate::schema::*;
+use crate::{api, delete, get_namespace, list_namespaces, modify};
+use anyhow::{anyhow, Result};
+
+/// A namespace and the properties HNS last reported for it.
+#[derive(Debug)]
+pub struct Namespace {
+    properties: HostComputeNamespace,
+}
+
+impl Namespace {
+    /// Create a namespace.
+    ///
+    /// `create_with_compartment` asks HNS to create the network compartment
+    /// straight away. This only works on recent Windows builds (Windows 11 and
+    /// Windows Server vNext, not 2019 or 2022).
+    pub fn create(namespace_type: NamespaceType, create_with_compartment: bool) -> Result<Self> {
+        let settings = HostComputeNamespace {
+            namespace_type: Some(namespace_type),
+            create_with_compartment: create_with_compartment.then_some(true),
+            ..Default::default()
+        };
+        let settings = serde_json::to_string(&settings)?;
+
+        Ok(Self {
+            properties: crate::create("", |id| api::create_namespace(id, &settings))?,
+        })
+    }
+
+    /// Look up an existing namespace.
+    pub fn open(id: &str) -> Result<Self> {
+        Ok(Self {
+            properties: get_namespace(id)?,
+        })
+    }
+
+    /// The host's default namespace, which holds the host's own network compartment.
+    pub fn host_default() -> Result<Self> {
+        list_namespaces()?
+            .into_iter()
+            .find(|n| n.namespace_type == Some(NamespaceType::HostDefault))
+            .map(|properties| Self { properties })
+            .ok_or_else(|| anyhow!("no HostDefault namespace found"))
+    }
+
+    pub fn id(&self) -> &str {
+        &self.properties.id
+    }
+
+    pub fn properties(&self) -> &HostComputeNamespace {
+        &self.properties
+    }
+
+    /// The network compartment backing the namespace, if it has one yet.
+    pub fn compartment_id(&self) -> Option<u32> {
+        self.properties.namespace_id
+    }
+
+    /// IDs of the endpoints attached to the namespace.
+    pub fn endpoints(&self) -> Vec<&str> {
+        self.properties.endpoints()
+    }
+
+    /// Attach an endpoint to the namespace.
+    pub fn add_endpoint(&mut self, endpoint_id: &str) -> Result<()> {
+        modify_endpoint(self.id(), RequestType::Add, endpoint_id)?;
+        self.refresh()
+    }
+
+    /// Detach an endpoint from the namespace.
+    pub fn remove_endpoint(&mut self, endpoint_id: &str) -> Result<()> {
+        modify_endpoint(self.id(), RequestType::Remove, endpoint_id)?;
+        self.refresh()
+    }
+
+    /// Query HNS for the current properties.
+    pub fn refresh(&mut self) -> Result<()> {
+        self.properties = get_namespace(&self.properties.id)?;
+        Ok(())
+    }
+
+    /// Delete the namespace.
+    pub fn delete(self) -> Result<()> {
+        delete::<HostComputeNamespace>(self.id())
+    }
+}
+
+/// Add or remove an endpoint on a namespace by ID.
+pub(crate) fn modify_endpoint(
+    namespace_id: &str,
+    request_type: RequestType,
+    endpoint_id: &str,
+) -> Result<()> {
+    let endpoint = NamespaceResourceEndpoint {
+        id: endpoint_id.to_string(),
+        ..Default::default()
+    };
+    let request = ModifyNamespaceSettingRequest {
+        resource_type: NamespaceResourceType::Endpoint,
+        request_type,
+        settings: Some(serde_json::to_value(endpoint)?),
+        extra_fields: ExtraFields::new(),
+    };
+    let request = serde_json::to_string(&request)?;
+
+    modify::<HostComputeNamespace>(namespace_id, &request)
+}
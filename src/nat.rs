@@ -0,0 +1,501 @@
+//! NAT networks and publishing endpoint ports on them.
+//!
+//! A NAT network is private to the host, so any subnet works as long as it
+//! does not hide something the host already reaches. [`NatNetworkBuilder`]
+//! picks one that is free, [`PortMapping`] publishes endpoint ports on the
+//! host the way `docker run -p` does.
+
+use crate::network::check_gateway;
+use crate::prefix::{from_bits, to_bits, IpFamily, IpPrefix};
+use crate::schema::*;
+use anyhow::{bail, Context, Result};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+
+/// Where subnets are picked from when none is given: `/20`s of
+/// `172.16.0.0/12`, then `/24`s of `192.168.0.0/16`.
+pub fn default_pools() -> Vec<(IpPrefix, u8)> {
+    let private = |a, b, len| IpPrefix::new(IpAddr::V4(Ipv4Addr::new(a, b, 0, 0)), len).unwrap();
+    vec![(private(172, 16, 12), 20), (private(192, 168, 16), 24)]
+}
+
+#[derive(Debug, Clone)]
+pub struct NatNetworkBuilder {
+    name: String,
+    subnet: Option<(IpPrefix, IpAddr)>,
+    pools: Vec<(IpPrefix, u8)>,
+    existing_networks: Option<Vec<HostComputeNetwork>>,
+    host_routes: Vec<IpPrefix>,
+}
+
+impl NatNetworkBuilder {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            subnet: None,
+            pools: default_pools(),
+            existing_networks: None,
+            host_routes: vec![],
+        }
+    }
+
+    /// Use this subnet instead of picking one. It still has to be free.
+    pub fn subnet(mut self, prefix: IpPrefix, gateway: IpAddr) -> Self {
+        self.subnet = Some((prefix, gateway));
+        self
+    }
+
+    /// Pick the subnet among the `len` long prefixes of these pools, in order,
+    /// instead of the [`default_pools`].
+    pub fn pools(mut self, pools: Vec<(IpPrefix, u8)>) -> Self {
+        self.pools = pools;
+        self
+    }
+
+    /// Check against these networks instead of the ones HNS currently has.
+    pub fn existing_networks(mut self, networks: Vec<HostComputeNetwork>) -> Self {
+        self.existing_networks = Some(networks);
+        self
+    }
+
+    /// Destinations of the host's routes, which the subnet must not overlap.
+    /// Default routes are ignored since every subnet overlaps them.
+    pub fn host_routes(mut self, routes: Vec<IpPrefix>) -> Self {
+        self.host_routes = routes;
+        self
+    }
+
+    pub fn build(self) -> Result<HostComputeNetwork> {
+        let existing = match self.existing_networks {
+            Some(networks) => networks,
+            #[cfg(windows)]
+            None => crate::list_networks()?,
+            #[cfg(not(windows))]
+            None => bail!("the existing networks have to be given off Windows"),
+        };
+        let taken = taken_prefixes(&existing, &self.host_routes);
+
+        let (prefix, gateway) = match self.subnet {
+            Some((prefix, gateway)) => {
+                if let Some((_, owner)) = taken.iter().find(|(t, _)| t.overlaps(&prefix)) {
+                    bail!("subnet {} conflicts with {}", prefix, owner);
+                }
+                (prefix, gateway)
+            }
+            None => {
+                let prefix = free_subnet(&self.pools, &taken)?;
+                (prefix, first_host(&prefix))
+            }
+        };
+        check_gateway(&prefix, &gateway)?;
+
+        let network = HostComputeNetwork {
+            name: self.name,
+            network_type: Some(NetworkType::NAT),
+            ipams: vec![Ipam {
+                subnets: vec![Subnet::new(&prefix, &gateway)],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        network.validate()?;
+        Ok(network)
+    }
+}
+
+// The prefixes in use, each with a description of what uses it.
+fn taken_prefixes(networks: &[HostComputeNetwork], routes: &[IpPrefix]) -> Vec<(IpPrefix, String)> {
+    let mut taken = vec![];
+    for network in networks {
+        for prefix in network.subnet_prefixes() {
+            taken.push((prefix, format!("network {}", network.name)));
+        }
+    }
+    for route in routes.iter().filter(|r| r.prefix_len() > 0) {
+        taken.push((*route, format!("host route {}", route)));
+    }
+    taken
+}
+
+fn free_subnet(pools: &[(IpPrefix, u8)], taken: &[(IpPrefix, String)]) -> Result<IpPrefix> {
+    for (pool, len) in pools {
+        if pool.family() == IpFamily::V4 && *len > 30 {
+            bail!("/{} subnets of {} have no room for endpoints", len, pool);
+        }
+        let free = pool
+            .subnets(*len)?
+            .find(|candidate| !taken.iter().any(|(t, _)| t.overlaps(candidate)));
+        if let Some(prefix) = free {
+            return Ok(prefix);
+        }
+    }
+    bail!("no free subnet left in the address pools")
+}
+
+fn first_host(prefix: &IpPrefix) -> IpAddr {
+    from_bits(prefix.family(), to_bits(&prefix.network()) + 1)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    /// The IP protocol number HNS expects.
+    pub fn number(&self) -> u32 {
+        match self {
+            Protocol::Tcp => 6,
+            Protocol::Udp => 17,
+        }
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "tcp" => Ok(Protocol::Tcp),
+            "udp" => Ok(Protocol::Udp),
+            _ => bail!("unsupported protocol {}", s),
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+/// An endpoint port published on the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortMapping {
+    pub protocol: Protocol,
+    /// The port on the host.
+    pub external_port: u16,
+    /// The port on the endpoint.
+    pub internal_port: u16,
+    /// The host address to publish on, all of them when not set.
+    pub vip: Option<IpAddr>,
+    pub flags: NatFlags,
+}
+
+impl PortMapping {
+    pub fn new(protocol: Protocol, external_port: u16, internal_port: u16) -> Self {
+        Self {
+            protocol,
+            external_port,
+            internal_port,
+            vip: None,
+            flags: NAT_FLAGS_NONE,
+        }
+    }
+
+    pub fn policy(&self) -> serde_json::Result<EndpointPolicy> {
+        let mut flags = self.flags;
+        if self.vip.is_some_and(|vip| vip.is_ipv6()) {
+            flags |= NAT_FLAGS_IPV6;
+        }
+        EndpointPolicy::new(
+            EndpointPolicyType::PortMapping,
+            &PortMappingPolicySetting {
+                protocol: self.protocol.number(),
+                internal_port: self.internal_port,
+                external_port: self.external_port,
+                vip: self.vip.map(|v| v.to_string()).unwrap_or_default(),
+                flags: (flags != NAT_FLAGS_NONE).then_some(flags),
+                ..Default::default()
+            },
+        )
+    }
+
+    // Both would listen on the same host port.
+    fn conflicts(&self, protocol: u32, external_port: u16, vip: Option<IpAddr>) -> bool {
+        self.protocol.number() == protocol
+            && self.external_port == external_port
+            && (self.vip.is_none() || vip.is_none() || self.vip == vip)
+    }
+}
+
+impl FromStr for PortMapping {
+    type Err = anyhow::Error;
+
+    /// Parse `[ip:]host:container[/protocol]` as `docker run -p` does, with
+    /// IPv6 addresses in brackets. The protocol defaults to TCP.
+    fn from_str(s: &str) -> Result<Self> {
+        let (spec, protocol) = match s.rsplit_once('/') {
+            Some((spec, protocol)) => (spec, protocol.parse()?),
+            None => (s, Protocol::Tcp),
+        };
+
+        let (vip, ports) = if let Some(rest) = spec.strip_prefix('[') {
+            let (vip, ports) = rest
+                .split_once("]:")
+                .with_context(|| format!("invalid port mapping {}", s))?;
+            (Some(vip), ports)
+        } else if spec.matches(':').count() == 2 {
+            let (vip, ports) = spec.split_once(':').unwrap();
+            (Some(vip), ports)
+        } else {
+            (None, spec)
+        };
+        let vip = vip
+            .map(|v| v.parse::<IpAddr>())
+            .transpose()
+            .with_context(|| format!("invalid address in port mapping {}", s))?;
+
+        let Some((external, internal)) = ports.split_once(':') else {
+            bail!("invalid port mapping {}, expected host:container", s);
+        };
+        let port = |p: &str| {
+            Some(p)
+                .filter(|p| p.bytes().all(|b| b.is_ascii_digit()))
+                .and_then(|p| p.parse::<u16>().ok())
+                .filter(|p| *p != 0)
+                .with_context(|| format!("invalid port {} in port mapping {}", p, s))
+        };
+
+        Ok(Self {
+            vip,
+            ..Self::new(protocol, port(external)?, port(internal)?)
+        })
+    }
+}
+
+/// Add PortMapping policies to an endpoint that is about to be created. The
+/// endpoint is left unchanged if any would listen on a host port already mapped.
+pub fn publish(endpoint: &mut HostComputeEndpoint, mappings: &[PortMapping]) -> Result<()> {
+    let mut published: Vec<(u32, u16, Option<IpAddr>)> = vec![];
+    for policy in &endpoint.policies {
+        if policy.policy_type == EndpointPolicyType::PortMapping {
+            let settings: PortMappingPolicySetting = policy.settings_as()?;
+            let vip = match settings.vip.as_str() {
+                "" => None,
+                vip => Some(vip.parse()?),
+            };
+            published.push((settings.protocol, settings.external_port, vip));
+        }
+    }
+
+    let mut policies = vec![];
+    for mapping in mappings {
+        if published
+            .iter()
+            .any(|(protocol, port, vip)| mapping.conflicts(*protocol, *port, *vip))
+        {
+            bail!(
+                "host port {}/{} is already published",
+                mapping.external_port,
+                mapping.protocol
+            );
+        }
+        published.push((
+            mapping.protocol.number(),
+            mapping.external_port,
+            mapping.vip,
+        ));
+        policies.push(mapping.policy()?);
+    }
+    endpoint.policies.extend(policies);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefix(s: &str) -> IpPrefix {
+        s.parse().unwrap()
+    }
+
+    fn network(name: &str, subnet: &str) -> HostComputeNetwork {
+        let subnet = prefix(subnet);
+        HostComputeNetwork {
+            name: name.to_string(),
+            network_type: Some(NetworkType::NAT),
+            ipams: vec![Ipam {
+                subnets: vec![Subnet::new(&subnet, &first_host(&subnet))],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn taken(prefixes: &[&str]) -> Vec<(IpPrefix, String)> {
+        prefixes
+            .iter()
+            .map(|p| (prefix(p), p.to_string()))
+            .collect()
+    }
+
+    fn subnet_of(network: &HostComputeNetwork) -> IpPrefix {
+        network.subnet_prefixes().next().unwrap()
+    }
+
+    #[test]
+    fn parse_port_mappings() {
+        let tcp = |external, internal| PortMapping::new(Protocol::Tcp, external, internal);
+        assert_eq!("8080:80".parse::<PortMapping>().unwrap(), tcp(8080, 80));
+        assert_eq!("8080:80/tcp".parse::<PortMapping>().unwrap(), tcp(8080, 80));
+        assert_eq!(
+            "53:5353/UDP".parse::<PortMapping>().unwrap(),
+            PortMapping::new(Protocol::Udp, 53, 5353)
+        );
+        assert_eq!(
+            "127.0.0.1:8080:80".parse::<PortMapping>().unwrap(),
+            PortMapping {
+                vip: Some("127.0.0.1".parse().unwrap()),
+                ..tcp(8080, 80)
+            }
+        );
+        assert_eq!(
+            "[fd00::1]:8080:80/udp".parse::<PortMapping>().unwrap(),
+            PortMapping {
+                vip: Some("fd00::1".parse().unwrap()),
+                ..PortMapping::new(Protocol::Udp, 8080, 80)
+            }
+        );
+    }
+
+    #[test]
+    fn reject_invalid_port_mappings() {
+        for invalid in [
+            "",
+            // missing colon
+            "8080",
+            "8080/tcp",
+            // bad ports
+            "8080:",
+            ":80",
+            "0:80",
+            "8080:0",
+            "65536:80",
+            "+80:80",
+            "http:80",
+            // bad protocols
+            "8080:80/sctp",
+            "8080:80/",
+            // bad addresses
+            "1.2.3.4:80",
+            "300.0.0.1:8080:80",
+            "fd00::1:8080:80",
+            "[fd00::1]8080:80",
+            "[fd00::1:8080:80",
+            "8080:80:80:80",
+        ] {
+            assert!(invalid.parse::<PortMapping>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn ipv6_vips_set_the_ipv6_flag() {
+        let settings = |mapping: &str| -> PortMappingPolicySetting {
+            let mapping: PortMapping = mapping.parse().unwrap();
+            mapping.policy().unwrap().settings_as().unwrap()
+        };
+        let v4 = settings("10.0.0.1:8080:80/udp");
+        assert_eq!(v4.protocol, 17);
+        assert_eq!(v4.vip, "10.0.0.1");
+        assert_eq!(v4.flags, None);
+        assert_eq!(settings("[fd00::1]:8080:80").flags, Some(NAT_FLAGS_IPV6));
+    }
+
+    #[test]
+    fn publish_rejects_conflicting_host_ports() {
+        let mapping = |s: &str| s.parse::<PortMapping>().unwrap();
+        let mut endpoint = HostComputeEndpoint::default();
+        publish(
+            &mut endpoint,
+            &[mapping("10.0.0.1:80:80"), mapping("80:80/udp")],
+        )
+        .unwrap();
+        // same port on another address
+        publish(&mut endpoint, &[mapping("10.0.0.2:80:8080")]).unwrap();
+        assert_eq!(endpoint.policies.len(), 3);
+
+        for conflict in ["80:80", "10.0.0.1:80:81", "10.0.0.3:80:80/udp"] {
+            let before = endpoint.policies.clone();
+            let result = publish(&mut endpoint, &[mapping("443:443"), mapping(conflict)]);
+            assert!(result.is_err(), "{}", conflict);
+            assert_eq!(endpoint.policies, before);
+        }
+
+        // within one call
+        assert!(publish(
+            &mut HostComputeEndpoint::default(),
+            &[mapping("443:443"), mapping("443:8443")]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn free_subnet_skips_taken_prefixes() {
+        let pools = default_pools();
+        assert_eq!(free_subnet(&pools, &[]).unwrap(), prefix("172.16.0.0/20"));
+        // a smaller prefix takes its whole /20, a larger one several
+        assert_eq!(
+            free_subnet(
+                &pools,
+                &taken(&["172.16.3.0/24", "172.16.16.0/21", "172.16.32.0/19"])
+            )
+            .unwrap(),
+            prefix("172.16.64.0/20")
+        );
+        // the first pool is covered by a route, fall back to the second
+        assert_eq!(
+            free_subnet(&pools, &taken(&["172.16.0.0/12", "192.168.0.0/24"])).unwrap(),
+            prefix("192.168.1.0/24")
+        );
+        assert!(free_subnet(&pools, &taken(&["172.16.0.0/12", "192.168.0.0/16"])).is_err());
+        assert!(free_subnet(&[(prefix("10.0.0.0/24"), 31)], &[]).is_err());
+    }
+
+    #[test]
+    fn builder_picks_a_free_subnet() {
+        let network = NatNetworkBuilder::new("nat")
+            .existing_networks(vec![network("other", "172.16.0.0/24")])
+            .host_routes(vec![prefix("0.0.0.0/0"), prefix("172.16.16.0/20")])
+            .build()
+            .unwrap();
+        assert_eq!(network.network_type, Some(NetworkType::NAT));
+        assert_eq!(subnet_of(&network), prefix("172.16.32.0/20"));
+        assert_eq!(
+            network.ipams[0].subnets[0].routes[0].next_hop.as_deref(),
+            Some("172.16.32.1")
+        );
+    }
+
+    #[test]
+    fn builder_checks_the_given_subnet() {
+        let existing = vec![network("other", "10.0.0.0/16")];
+        let build = |subnet: &str, gateway: &str| {
+            NatNetworkBuilder::new("nat")
+                .subnet(prefix(subnet), gateway.parse().unwrap())
+                .existing_networks(existing.clone())
+                .host_routes(vec![prefix("192.168.1.0/24")])
+                .build()
+        };
+
+        let network = build("10.1.0.0/24", "10.1.0.254").unwrap();
+        assert_eq!(subnet_of(&network), prefix("10.1.0.0/24"));
+
+        let error = build("10.0.128.0/24", "10.0.128.1").unwrap_err();
+        assert!(error.to_string().contains("network other"), "{}", error);
+        assert!(build("192.168.0.0/16", "192.168.0.1").is_err());
+        assert!(build("10.1.0.0/24", "10.2.0.1").is_err());
+        assert!(build("10.1.0.0/24", "10.1.0.255").is_err());
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn builder_needs_the_existing_networks_off_windows() {
+        assert!(NatNetworkBuilder::new("nat").build().is_err());
+    }
+}
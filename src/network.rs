@@ -0,0 +1,56 @@
+//! High level operations on HNS networks.
+
+use crate::prefix::{IpFamily, IpPrefix};
+#[cfg(windows)]
+use crate::schema::*;
+#[cfg(windows)]
+use crate::{api, modify};
+#[cfg(windows)]
+use anyhow::Context;
+use anyhow::{bail, Result};
+use std::net::IpAddr;
+
+/// Validate and create a network, returning it as HNS reports it.
+#[cfg(windows)]
+pub fn create(network: &HostComputeNetwork) -> Result<HostComputeNetwork> {
+    network.validate()?;
+    let settings = serde_json::to_string(network)?;
+
+    crate::create(&network.id, |id| api::create_network(id, &settings))
+        .with_context(|| format!("failed to create network {}", network.name))
+}
+
+/// Add, update or remove policies on an existing network.
+#[cfg(windows)]
+pub fn modify_policies(
+    network_id: &str,
+    request_type: RequestType,
+    policies: Vec<NetworkPolicy>,
+) -> Result<()> {
+    let request = ModifyNetworkSettingRequest {
+        resource_type: NetworkResourceType::Policy,
+        request_type,
+        settings: Some(serde_json::to_value(PolicyNetworkRequest {
+            policies,
+            ..Default::default()
+        })?),
+        extra_fields: ExtraFields::new(),
+    };
+    let request = serde_json::to_string(&request)?;
+
+    modify::<HostComputeNetwork>(network_id, &request)
+}
+
+// HNS accepts these but endpoints then have no working gateway
+pub(crate) fn check_gateway(prefix: &IpPrefix, gateway: &IpAddr) -> Result<()> {
+    if !prefix.contains(gateway) {
+        bail!("gateway {} is outside subnet {}", gateway, prefix);
+    }
+    if prefix.size() > 1 && *gateway == prefix.network() {
+        bail!("gateway {} is the network address of {}", gateway, prefix);
+    }
+    if prefix.family() == IpFamily::V4 && prefix.size() > 2 && *gateway == prefix.last() {
+        bail!("gateway {} is the broadcast address of {}", gateway, prefix);
+    }
+    Ok(())
+}
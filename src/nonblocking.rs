@@ -0,0 +1,155 @@
+//! Async versions of the high-level operations, behind the `tokio` feature.
+//!
+//! Every HNS call blocks on an RPC to the service, creating a network can take
+//! seconds. [`Pool`] runs the calls with `spawn_blocking`, on the runtime's
+//! shared blocking threads, and caps how many run at once so a burst of
+//! requests does not take over those threads or pile up inside HNS. It owns no
+//! threads of its own: other `spawn_blocking` work competes for the same ones.
+//!
+//! Each operation runs start to finish inside one blocking task, opening and
+//! closing its handle there. Dropping a future only detaches that task: it
+//! still completes, closes the handle and releases its slot, the result is
+//! thrown away.
+
+#[cfg(windows)]
+use crate::object::HcnObject;
+#[cfg(windows)]
+use crate::schema::*;
+#[cfg(windows)]
+use crate::{delete, endpoint, get, list, load_balancer, modify, network};
+use anyhow::{Context, Result};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+/// A limit on how many blocking HNS calls are in flight at once.
+///
+/// This is not a dedicated thread pool. The calls run on tokio's blocking
+/// threads through `spawn_blocking`, a semaphore only bounds how many of them
+/// a `Pool` and its clones have running.
+#[derive(Debug, Clone)]
+pub struct Pool {
+    permits: Arc<Semaphore>,
+}
+
+impl Default for Pool {
+    fn default() -> Self {
+        Self::new(4)
+    }
+}
+
+impl Pool {
+    /// A limit of `max_concurrent` HNS operations at a time.
+    pub fn new(max_concurrent: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max_concurrent.max(1))),
+        }
+    }
+
+    /// Run any blocking operation once a slot is free, e.g. `Namespace::create`.
+    pub async fn run<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let permit = Arc::clone(&self.permits).acquire_owned().await?;
+        tokio::task::spawn_blocking(move || {
+            // held until the call returns, even if the caller has gone away
+            let _permit = permit;
+            f()
+        })
+        .await
+        .context("blocking HNS call panicked")?
+    }
+
+    /// Validate and create a network, see [`network::create`].
+    #[cfg(windows)]
+    pub async fn create_network(&self, network: &HostComputeNetwork) -> Result<HostComputeNetwork> {
+        let network = network.clone();
+        self.run(move || network::create(&network)).await
+    }
+
+    /// Validate and create an endpoint on a network, see [`endpoint::create`].
+    #[cfg(windows)]
+    pub async fn create_endpoint(
+        &self,
+        network_id: &str,
+        endpoint: &HostComputeEndpoint,
+    ) -> Result<HostComputeEndpoint> {
+        let network_id = network_id.to_string();
+        let endpoint = endpoint.clone();
+        self.run(move || endpoint::create(&network_id, &endpoint))
+            .await
+    }
+
+    /// Validate and create a load balancer, see [`load_balancer::create`].
+    #[cfg(windows)]
+    pub async fn create_load_balancer(
+        &self,
+        load_balancer: &HostComputeLoadBalancer,
+    ) -> Result<HostComputeLoadBalancer> {
+        let load_balancer = load_balancer.clone();
+        self.run(move || load_balancer::create(&load_balancer))
+            .await
+    }
+
+    #[cfg(windows)]
+    pub async fn get<T: HcnObject + Send + 'static>(&self, id: &str) -> Result<T> {
+        let id = id.to_string();
+        self.run(move || get(&id)).await
+    }
+
+    #[cfg(windows)]
+    pub async fn list<T: HcnObject + Send + 'static>(&self) -> Result<Vec<T>> {
+        self.run(list).await
+    }
+
+    #[cfg(windows)]
+    pub async fn modify<T: HcnObject + 'static>(&self, id: &str, settings: &str) -> Result<()> {
+        let id = id.to_string();
+        let settings = settings.to_string();
+        self.run(move || modify::<T>(&id, &settings)).await
+    }
+
+    #[cfg(windows)]
+    pub async fn delete<T: HcnObject + 'static>(&self, id: &str) -> Result<()> {
+        let id = id.to_string();
+        self.run(move || delete::<T>(&id)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+    use tokio::time::timeout;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dropped_call_finishes_and_frees_its_slot() {
+        let pool = Pool::new(1);
+        let (started, has_started) = oneshot::channel();
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let (finished, has_finished) = oneshot::channel();
+
+        let mut call = Box::pin(pool.run(move || {
+            started.send(()).unwrap();
+            released.recv().unwrap();
+            finished.send(()).unwrap();
+            Ok(())
+        }));
+        tokio::select! {
+            _ = &mut call => panic!("the call returned before it was released"),
+            started = has_started => started.unwrap(),
+        }
+        drop(call);
+
+        // the detached call still holds the only slot
+        assert!(timeout(Duration::from_millis(50), pool.run(|| Ok(())))
+            .await
+            .is_err());
+
+        release.send(()).unwrap();
+        has_finished.await.unwrap();
+        assert_eq!(pool.run(|| Ok(1)).await.unwrap(), 1);
+    }
+}
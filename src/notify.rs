@@ -0,0 +1,503 @@
+//! Service notifications and keeping the subscription to them alive.
+//!
+//! [`Subscription`] is a safe wrapper over `api::register_service_callback`.
+//! When the HNS service restarts it sends a final `ServiceDisconnect` and the
+//! registration goes dead without any error. [`Supervisor`] watches for that,
+//! registers again with backoff and then tells consumers to resync, because
+//! whatever happened while it was disconnected was missed.
+
+use crate::api::{self, HcnCallback};
+use crate::retry::RetryPolicy;
+use anyhow::{Context, Result};
+use std::ffi::c_void;
+use std::mem::MaybeUninit;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+use windows::core::{HRESULT, PCWSTR};
+use windows::Win32::System::HostComputeNetwork::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotificationType {
+    NetworkPreCreate,
+    NetworkCreate,
+    NetworkPreDelete,
+    NetworkDelete,
+    NamespaceCreate,
+    NamespaceDelete,
+    GuestNetworkServiceCreate,
+    GuestNetworkServiceDelete,
+    NetworkEndpointAttached,
+    NetworkEndpointDetached,
+    GuestNetworkServiceStateChanged,
+    GuestNetworkServiceInterfaceStateChanged,
+    ServiceDisconnect,
+    Unknown(u32),
+}
+
+impl From<u32> for NotificationType {
+    #[allow(non_upper_case_globals)]
+    fn from(value: u32) -> Self {
+        match HCN_NOTIFICATIONS(value as i32) {
+            HcnNotificationNetworkPreCreate => NotificationType::NetworkPreCreate,
+            HcnNotificationNetworkCreate => NotificationType::NetworkCreate,
+            HcnNotificationNetworkPreDelete => NotificationType::NetworkPreDelete,
+            HcnNotificationNetworkDelete => NotificationType::NetworkDelete,
+            HcnNotificationNamespaceCreate => NotificationType::NamespaceCreate,
+            HcnNotificationNamespaceDelete => NotificationType::NamespaceDelete,
+            HcnNotificationGuestNetworkServiceCreate => NotificationType::GuestNetworkServiceCreate,
+            HcnNotificationGuestNetworkServiceDelete => NotificationType::GuestNetworkServiceDelete,
+            HcnNotificationNetworkEndpointAttached => NotificationType::NetworkEndpointAttached,
+            HcnNotificationNetworkEndpointDetached => NotificationType::NetworkEndpointDetached,
+            HcnNotificationGuestNetworkServiceStateChanged => {
+                NotificationType::GuestNetworkServiceStateChanged
+            }
+            HcnNotificationGuestNetworkServiceInterfaceStateChanged => {
+                NotificationType::GuestNetworkServiceInterfaceStateChanged
+            }
+            HcnNotificationServiceDisconnect => NotificationType::ServiceDisconnect,
+            _ => NotificationType::Unknown(value),
+        }
+    }
+}
+
+/// A notification delivered by HNS.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notification {
+    pub notification_type: NotificationType,
+    pub status: HRESULT,
+    /// JSON describing the object the notification is about, if any.
+    pub data: Option<String>,
+}
+
+type Handler = Box<dyn Fn(Notification) + Send + Sync>;
+
+/// A registered service callback, unregistered when dropped.
+pub struct Subscription {
+    callback_handle: Option<HcnCallback>,
+    handler: *mut Handler,
+}
+
+// SAFETY: the handler is only read, from HNS's callback threads, and is
+// required to be Send + Sync itself. The callback handle is an opaque RPC
+// handle that HNS accepts from any thread; it is only used to unregister, once,
+// through `&mut self`.
+unsafe impl Send for Subscription {}
+unsafe impl Sync for Subscription {}
+
+impl Subscription {
+    /// Register `handler` for service-wide notifications. It is called on a
+    /// thread owned by HNS and should hand the notification off quickly.
+    pub fn new(handler: impl Fn(Notification) + Send + Sync + 'static) -> Result<Self> {
+        let handler: *mut Handler = Box::into_raw(Box::new(Box::new(handler)));
+        let mut callback_handle = MaybeUninit::<HcnCallback>::uninit();
+
+        // SAFETY: the handler outlives the registration, it is freed in drop
+        // only after unregistering.
+        let result = unsafe {
+            api::register_service_callback(
+                Some(notification_callback),
+                handler as *const c_void,
+                callback_handle.as_mut_ptr(),
+            )
+        };
+        if let Err(e) = result {
+            // SAFETY: registration failed, so HNS holds no reference to it
+            drop(unsafe { Box::from_raw(handler) });
+            return Err(e).context("failed to register service callback");
+        }
+
+        Ok(Self {
+            // SAFETY: written by a successful registration
+            callback_handle: Some(unsafe { callback_handle.assume_init() }),
+            handler,
+        })
+    }
+
+    /// Unregister, reporting the error that dropping the subscription would only log.
+    pub fn unsubscribe(mut self) -> Result<()> {
+        self.unregister()
+    }
+
+    fn unregister(&mut self) -> Result<()> {
+        let Some(callback_handle) = self.callback_handle.take() else {
+            return Ok(());
+        };
+        let result = api::unregister_service_callback(callback_handle);
+        // Once the service has gone away unregistering can fail, but no more
+        // callbacks arrive either way. The handler is leaked rather than freed
+        // if that cannot be ruled out.
+        if result.is_ok() {
+            // SAFETY: unregistered, HNS no longer calls the callback
+            drop(unsafe { Box::from_raw(self.handler) });
+        }
+        result
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Err(e) = self.unregister() {
+            log::warn!("failed to unregister service callback: {}", e);
+        }
+    }
+}
+
+unsafe extern "system" fn notification_callback(
+    notification_type: u32,
+    context: *const c_void,
+    status: HRESULT,
+    data: PCWSTR,
+) {
+    // SAFETY: context is the handler passed at registration, alive until unregistered
+    let handler = unsafe { &*(context as *const Handler) };
+    let data = if data.is_null() {
+        None
+    } else {
+        // SAFETY: HNS passes a null-terminated string valid for the call
+        unsafe { data.to_string() }.ok()
+    };
+    let notification = Notification {
+        notification_type: notification_type.into(),
+        status,
+        data,
+    };
+
+    // unwinding into HNS is undefined behaviour
+    if catch_unwind(AssertUnwindSafe(|| handler(notification))).is_err() {
+        log::error!("service notification handler panicked");
+    }
+}
+
+/// What a [`Supervisor`] passes on to its consumer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Notification(Notification),
+    /// Notifications may have been missed, e.g. while HNS restarted. Consumers
+    /// should list everything again rather than rely on what they have seen.
+    ResyncRequired,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Health {
+    Subscribed,
+    /// Not registered, trying again. `since` is when the subscription was lost.
+    Reconnecting {
+        attempt: u32,
+        since: Instant,
+    },
+    Stopped,
+}
+
+enum Signal {
+    Notification(Notification),
+    Stop,
+}
+
+/// Keeps a service subscription alive across HNS restarts.
+pub struct Supervisor {
+    health: Arc<Mutex<Health>>,
+    signals: mpsc::Sender<Signal>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Supervisor {
+    /// Subscribe to service notifications, delivering them as [`Event`]s.
+    ///
+    /// The first event is always [`Event::ResyncRequired`], sent once the
+    /// subscription is in place, so a consumer can take its initial snapshot
+    /// knowing nothing after it will be missed. Re-registering after a
+    /// disconnect waits according to `policy`'s backoff; its attempt limit
+    /// and deadline are ignored, the supervisor keeps trying until stopped.
+    pub fn start(policy: RetryPolicy) -> Result<(Self, mpsc::Receiver<Event>)> {
+        Self::start_with(policy, Subscription::new)
+    }
+
+    // `subscribe` registers the handler, the value it returns unregisters it
+    // when dropped.
+    fn start_with<S, F>(policy: RetryPolicy, subscribe: F) -> Result<(Self, mpsc::Receiver<Event>)>
+    where
+        F: Fn(Handler) -> Result<S> + Send + 'static,
+    {
+        let (signals, signal_receiver) = mpsc::channel();
+        let (events, event_receiver) = mpsc::channel();
+        let health = Arc::new(Mutex::new(Health::Reconnecting {
+            attempt: 0,
+            since: Instant::now(),
+        }));
+
+        let worker = Worker {
+            subscribe,
+            policy,
+            health: Arc::clone(&health),
+            signals: signals.clone(),
+            signal_receiver,
+            events,
+        };
+        let thread = thread::Builder::new()
+            .name("hcn-supervisor".to_string())
+            .spawn(move || worker.run())
+            .context("failed to start supervisor thread")?;
+
+        Ok((
+            Self {
+                health,
+                signals,
+                thread: Some(thread),
+            },
+            event_receiver,
+        ))
+    }
+
+    pub fn health(&self) -> Health {
+        *self.health.lock().unwrap()
+    }
+
+    /// Unsubscribe and wait for the supervisor thread to finish.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        let _ = self.signals.send(Signal::Stop);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+struct Worker<F> {
+    subscribe: F,
+    policy: RetryPolicy,
+    health: Arc<Mutex<Health>>,
+    signals: mpsc::Sender<Signal>,
+    signal_receiver: mpsc::Receiver<Signal>,
+    events: mpsc::Sender<Event>,
+}
+
+impl<F, S> Worker<F>
+where
+    F: Fn(Handler) -> Result<S>,
+{
+    fn run(self) {
+        while let Some(subscription) = self.subscribe() {
+            self.set_health(Health::Subscribed);
+            if self.events.send(Event::ResyncRequired).is_err() {
+                break;
+            }
+
+            let disconnected = self.forward();
+            drop(subscription);
+            if !disconnected {
+                break;
+            }
+            log::warn!("HNS service disconnected, subscribing again");
+        }
+        self.set_health(Health::Stopped);
+    }
+
+    // Register with backoff until it works. None if stopped meanwhile.
+    fn subscribe(&self) -> Option<S> {
+        let since = Instant::now();
+        let mut attempt = 1;
+        loop {
+            self.set_health(Health::Reconnecting { attempt, since });
+
+            let signals = self.signals.clone();
+            let result = (self.subscribe)(Box::new(move |n| {
+                let _ = signals.send(Signal::Notification(n));
+            }));
+            match result {
+                Ok(subscription) => return Some(subscription),
+                Err(e) => {
+                    let delay = self.policy.delay(attempt);
+                    log::warn!(
+                        "subscription attempt {} failed, retrying in {:?}: {:#}",
+                        attempt,
+                        delay,
+                        e
+                    );
+                    // keep listening for a stop while waiting
+                    match self.signal_receiver.recv_timeout(delay) {
+                        Ok(Signal::Stop) | Err(RecvTimeoutError::Disconnected) => return None,
+                        Ok(Signal::Notification(_)) | Err(RecvTimeoutError::Timeout) => {}
+                    }
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    // Pass notifications on until the service disconnects (true) or the
+    // supervisor is stopped (false).
+    fn forward(&self) -> bool {
+        loop {
+            match self.signal_receiver.recv() {
+                Ok(Signal::Notification(n)) => {
+                    let disconnected = n.notification_type == NotificationType::ServiceDisconnect;
+                    if self.events.send(Event::Notification(n)).is_err() {
+                        return false;
+                    }
+                    if disconnected {
+                        return true;
+                    }
+                }
+                Ok(Signal::Stop) | Err(_) => return false,
+            }
+        }
+    }
+
+    fn set_health(&self, health: Health) {
+        *self.health.lock().unwrap() = health;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // Stands in for HNS: fails the first `failures` registrations and keeps
+    // the handler of the live one so tests can send notifications through it.
+    #[derive(Default)]
+    struct FakeHns {
+        failures: usize,
+        attempts: Vec<Instant>,
+        handler: Option<Handler>,
+        unsubscribed: usize,
+    }
+
+    struct FakeSubscription(Arc<Mutex<FakeHns>>);
+
+    impl Drop for FakeSubscription {
+        fn drop(&mut self) {
+            let mut hns = self.0.lock().unwrap();
+            hns.handler = None;
+            hns.unsubscribed += 1;
+        }
+    }
+
+    fn start(failures: usize) -> (Supervisor, mpsc::Receiver<Event>, Arc<Mutex<FakeHns>>) {
+        let hns = Arc::new(Mutex::new(FakeHns {
+            failures,
+            ..Default::default()
+        }));
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(20),
+            jitter: 0.0,
+            ..Default::default()
+        };
+        let fake = Arc::clone(&hns);
+        let (supervisor, events) = Supervisor::start_with(policy, move |handler| {
+            let mut hns = fake.lock().unwrap();
+            hns.attempts.push(Instant::now());
+            if hns.failures > 0 {
+                hns.failures -= 1;
+                anyhow::bail!("HNS is not running");
+            }
+            hns.handler = Some(handler);
+            Ok(FakeSubscription(Arc::clone(&fake)))
+        })
+        .unwrap();
+        (supervisor, events, hns)
+    }
+
+    fn notify(hns: &Mutex<FakeHns>, notification_type: NotificationType) {
+        let hns = hns.lock().unwrap();
+        let handler = hns.handler.as_ref().expect("subscribed");
+        handler(Notification {
+            notification_type,
+            status: HRESULT(0),
+            data: None,
+        });
+    }
+
+    fn next(events: &mpsc::Receiver<Event>) -> Event {
+        events.recv_timeout(Duration::from_secs(5)).unwrap()
+    }
+
+    fn notification(notification_type: NotificationType) -> Event {
+        Event::Notification(Notification {
+            notification_type,
+            status: HRESULT(0),
+            data: None,
+        })
+    }
+
+    #[test]
+    fn forwards_notifications_after_the_first_resync() {
+        let (supervisor, events, hns) = start(0);
+        assert_eq!(next(&events), Event::ResyncRequired);
+        assert_eq!(supervisor.health(), Health::Subscribed);
+
+        notify(&hns, NotificationType::NetworkCreate);
+        assert_eq!(next(&events), notification(NotificationType::NetworkCreate));
+        assert_eq!(hns.lock().unwrap().attempts.len(), 1);
+    }
+
+    #[test]
+    fn backs_off_between_failed_subscriptions() {
+        let (_supervisor, events, hns) = start(2);
+        assert_eq!(next(&events), Event::ResyncRequired);
+
+        let attempts = hns.lock().unwrap().attempts.clone();
+        assert_eq!(attempts.len(), 3);
+        assert!(attempts[1] - attempts[0] >= Duration::from_millis(20));
+        assert!(attempts[2] - attempts[1] >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn subscribes_again_after_a_disconnect() {
+        let (supervisor, events, hns) = start(0);
+        assert_eq!(next(&events), Event::ResyncRequired);
+
+        // the first registration attempt after the restart fails as well
+        hns.lock().unwrap().failures = 1;
+        notify(&hns, NotificationType::ServiceDisconnect);
+        assert_eq!(
+            next(&events),
+            notification(NotificationType::ServiceDisconnect)
+        );
+        assert_eq!(next(&events), Event::ResyncRequired);
+        assert_eq!(supervisor.health(), Health::Subscribed);
+
+        let hns = hns.lock().unwrap();
+        assert_eq!(hns.attempts.len(), 3);
+        assert_eq!(hns.unsubscribed, 1);
+    }
+
+    #[test]
+    fn stop_unsubscribes() {
+        let (supervisor, events, hns) = start(0);
+        assert_eq!(next(&events), Event::ResyncRequired);
+
+        supervisor.stop();
+        assert_eq!(hns.lock().unwrap().unsubscribed, 1);
+        assert!(hns.lock().unwrap().handler.is_none());
+        // the worker has gone and dropped its sender
+        assert!(events.recv().is_err());
+    }
+
+    #[test]
+    fn stop_interrupts_the_backoff() {
+        let (supervisor, events, hns) = start(usize::MAX);
+        while hns.lock().unwrap().attempts.is_empty() {
+            thread::yield_now();
+        }
+
+        let health = Arc::clone(&supervisor.health);
+        let stopping = Instant::now();
+        supervisor.stop();
+        assert!(stopping.elapsed() < Duration::from_secs(5));
+        assert_eq!(*health.lock().unwrap(), Health::Stopped);
+        assert!(events.recv().is_err());
+    }
+}
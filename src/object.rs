@@ -0,0 +1,387 @@
+//! A common interface over the HNS object kinds.
+//!
+//! The `api` module has a separate set of functions for each kind of object.
+//! [`HcnObject`] maps them onto one trait so code that lists, caches or deletes
+//! objects can be written once. Implementing the trait for a fake type is also
+//! the easiest way to exercise such code without HNS.
+
+use crate::graph::ObjectKind;
+use crate::retry::RetryPolicy;
+use crate::schema::*;
+use crate::{api, parse_guid};
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use std::marker::PhantomData;
+use windows::core::GUID;
+
+/// A schema type HNS can be queried for, along with the `api` calls that
+/// operate on it.
+///
+/// Creation is left out on purpose: the `create_*` calls do not share a
+/// signature, endpoints can only be created on a network.
+pub trait HcnObject: DeserializeOwned {
+    /// The `api` handle type. It has to stay valid whichever thread it is
+    /// used from. [`OwnedHandle`] is only `Send` and `Sync` for the four HNS
+    /// objects, whose handles are known to be; another implementation has to
+    /// add its own impls to share its handles between threads.
+    type Handle: Copy;
+
+    const KIND: ObjectKind;
+
+    /// Returns a JSON array of the IDs of the objects matching `query`.
+    fn enumerate(query: &str) -> Result<String>;
+    fn open(id: &GUID) -> Result<Self::Handle>;
+    fn query(handle: Self::Handle, query: &str) -> Result<String>;
+    fn modify(handle: Self::Handle, settings: &str) -> Result<()>;
+    fn delete(id: &GUID) -> Result<()>;
+    fn close(handle: Self::Handle) -> Result<()>;
+}
+
+impl HcnObject for HostComputeNetwork {
+    type Handle = api::HcnNetworkHandle;
+
+    const KIND: ObjectKind = ObjectKind::Network;
+
+    fn enumerate(query: &str) -> Result<String> {
+        api::enumerate_networks(query)
+    }
+
+    fn open(id: &GUID) -> Result<Self::Handle> {
+        api::open_network(id)
+    }
+
+    fn query(handle: Self::Handle, query: &str) -> Result<String> {
+        api::query_network_properties(handle, query)
+    }
+
+    fn modify(handle: Self::Handle, settings: &str) -> Result<()> {
+        api::modify_network(handle, settings)
+    }
+
+    fn delete(id: &GUID) -> Result<()> {
+        api::delete_network(id)
+    }
+
+    fn close(handle: Self::Handle) -> Result<()> {
+        api::close_network(handle)
+    }
+}
+
+impl HcnObject for HostComputeNamespace {
+    type Handle = api::HcnNamespaceHandle;
+
+    const KIND: ObjectKind = ObjectKind::Namespace;
+
+    fn enumerate(query: &str) -> Result<String> {
+        api::enumerate_namespaces(query)
+    }
+
+    fn open(id: &GUID) -> Result<Self::Handle> {
+        api::open_namespace(id)
+    }
+
+    fn query(handle: Self::Handle, query: &str) -> Result<String> {
+        api::query_namespace_properties(handle, query)
+    }
+
+    fn modify(handle: Self::Handle, settings: &str) -> Result<()> {
+        api::modify_namespace(handle, settings)
+    }
+
+    fn delete(id: &GUID) -> Result<()> {
+        api::delete_namespace(id)
+    }
+
+    fn close(handle: Self::Handle) -> Result<()> {
+        api::close_namespace(handle)
+    }
+}
+
+impl HcnObject for HostComputeEndpoint {
+    type Handle = api::HcnEndpointHandle;
+
+    const KIND: ObjectKind = ObjectKind::Endpoint;
+
+    fn enumerate(query: &str) -> Result<String> {
+        api::enumerate_endpoints(query)
+    }
+
+    fn open(id: &GUID) -> Result<Self::Handle> {
+        api::open_endpoint(id)
+    }
+
+    fn query(handle: Self::Handle, query: &str) -> Result<String> {
+        api::query_endpoint_properties(handle, query)
+    }
+
+    fn modify(handle: Self::Handle, settings: &str) -> Result<()> {
+        api::modify_endpoint(handle, settings)
+    }
+
+    fn delete(id: &GUID) -> Result<()> {
+        api::delete_endpoint(id)
+    }
+
+    fn close(handle: Self::Handle) -> Result<()> {
+        api::close_endpoint(handle)
+    }
+}
+
+impl HcnObject for HostComputeLoadBalancer {
+    type Handle = api::HcnLoadBalancerHandle;
+
+    const KIND: ObjectKind = ObjectKind::LoadBalancer;
+
+    fn enumerate(query: &str) -> Result<String> {
+        api::enumerate_load_balancers(query)
+    }
+
+    fn open(id: &GUID) -> Result<Self::Handle> {
+        api::open_load_balancer(id)
+    }
+
+    fn query(handle: Self::Handle, query: &str) -> Result<String> {
+        api::query_load_balancer_properties(handle, query)
+    }
+
+    fn modify(handle: Self::Handle, settings: &str) -> Result<()> {
+        api::modify_load_balancer(handle, settings)
+    }
+
+    fn delete(id: &GUID) -> Result<()> {
+        api::delete_load_balancer(id)
+    }
+
+    fn close(handle: Self::Handle) -> Result<()> {
+        api::close_load_balancer(handle)
+    }
+}
+
+/// An open handle that is closed when dropped.
+///
+/// The handle can only be closed by consuming or dropping its owner, so it is
+/// never used after being closed, even when shared between threads.
+pub struct OwnedHandle<T: HcnObject> {
+    handle: Option<T::Handle>,
+    _object: PhantomData<fn() -> T>,
+}
+
+// SAFETY: HNS handles are opaque context handles for RPC calls into the HNS
+// service, not pointers into process memory, and HNS serialises the calls made
+// on them internally, so they can be used from any thread and from several at
+// once. The raw handles are left !Send and !Sync because nothing stops them
+// being used after `close`. An OwnedHandle closes only when consumed or
+// dropped, after every use through it has finished.
+//
+// This only holds for the HNS handle types, so the impls are written for the
+// four HNS objects rather than for any `HcnObject`, whose `Handle` could be
+// anything.
+unsafe impl Send for OwnedHandle<HostComputeNetwork> {}
+unsafe impl Sync for OwnedHandle<HostComputeNetwork> {}
+unsafe impl Send for OwnedHandle<HostComputeNamespace> {}
+unsafe impl Sync for OwnedHandle<HostComputeNamespace> {}
+unsafe impl Send for OwnedHandle<HostComputeEndpoint> {}
+unsafe impl Sync for OwnedHandle<HostComputeEndpoint> {}
+unsafe impl Send for OwnedHandle<HostComputeLoadBalancer> {}
+unsafe impl Sync for OwnedHandle<HostComputeLoadBalancer> {}
+
+const _: fn() = || {
+    fn shareable<T: Send + Sync>() {}
+    shareable::<OwnedHandle<HostComputeNetwork>>();
+    shareable::<OwnedHandle<HostComputeNamespace>>();
+    shareable::<OwnedHandle<HostComputeEndpoint>>();
+    shareable::<OwnedHandle<HostComputeLoadBalancer>>();
+};
+
+// Opening and querying only read, so they are retried on transient errors.
+impl<T: HcnObject> OwnedHandle<T> {
+    pub fn open(id: &str) -> Result<Self> {
+        let guid = parse_guid(id)?;
+        let handle = RetryPolicy::reads()
+            .retry(&format!("open {} {}", T::KIND, id), || T::open(&guid))
+            .with_context(|| format!("failed to open {} {}", T::KIND, id))?;
+        Ok(Self {
+            handle: Some(handle),
+            _object: PhantomData,
+        })
+    }
+
+    /// Take ownership of a handle returned by the `api` functions.
+    ///
+    /// # Safety
+    ///
+    /// The handle must be open and must not be closed by anything else.
+    pub unsafe fn from_raw(handle: T::Handle) -> Self {
+        Self {
+            handle: Some(handle),
+            _object: PhantomData,
+        }
+    }
+
+    /// The raw handle, valid for as long as `self` is alive.
+    pub fn as_raw(&self) -> T::Handle {
+        self.handle.expect("handle is only taken when closing")
+    }
+
+    pub fn query(&self, query: &str) -> Result<String> {
+        RetryPolicy::reads().retry(&format!("query {}", T::KIND), || {
+            T::query(self.as_raw(), query)
+        })
+    }
+
+    /// Query and parse the object's properties.
+    pub fn properties(&self) -> Result<T> {
+        let query = serde_json::to_string(&HostComputeQuery::default())?;
+        let properties = self
+            .query(&query)
+            .with_context(|| format!("failed to query {}", T::KIND))?;
+        log::debug!("raw {}: {}", T::KIND, properties);
+        serde_json::from_str(&properties)
+            .with_context(|| format!("failed to parse {}: {}", T::KIND, properties))
+    }
+
+    pub fn modify(&self, settings: &str) -> Result<()> {
+        T::modify(self.as_raw(), settings)
+    }
+
+    /// Close the handle, reporting the error that dropping it would only log.
+    pub fn close(mut self) -> Result<()> {
+        match self.handle.take() {
+            Some(handle) => T::close(handle),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<T: HcnObject> Drop for OwnedHandle<T> {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            if let Err(e) = T::close(handle) {
+                log::warn!("failed to close {} handle: {}", T::KIND, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::collections::BTreeSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+
+    // A simulated HNS that records every call made on a handle that is not
+    // open. Any such call is a use-after-close race.
+    static OPEN: Mutex<BTreeSet<usize>> = Mutex::new(BTreeSet::new());
+    static NEXT_HANDLE: AtomicUsize = AtomicUsize::new(1);
+    static VIOLATIONS: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Debug, Deserialize)]
+    struct SimObject {
+        #[serde(rename = "ID")]
+        id: String,
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    struct SimHandle(usize);
+
+    // SAFETY: a SimHandle is only a number, every call checks it against OPEN.
+    unsafe impl Send for OwnedHandle<SimObject> {}
+    unsafe impl Sync for OwnedHandle<SimObject> {}
+
+    fn check_open(handle: SimHandle) {
+        if !OPEN.lock().unwrap().contains(&handle.0) {
+            VIOLATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    impl HcnObject for SimObject {
+        type Handle = SimHandle;
+
+        const KIND: ObjectKind = ObjectKind::Endpoint;
+
+        fn enumerate(_query: &str) -> Result<String> {
+            Ok("[]".to_string())
+        }
+
+        fn open(_id: &GUID) -> Result<Self::Handle> {
+            let handle = NEXT_HANDLE.fetch_add(1, Ordering::Relaxed);
+            OPEN.lock().unwrap().insert(handle);
+            Ok(SimHandle(handle))
+        }
+
+        fn query(handle: Self::Handle, _query: &str) -> Result<String> {
+            check_open(handle);
+            // give other threads a chance to close the handle mid-call
+            thread::yield_now();
+            check_open(handle);
+            Ok(format!(r#"{{"ID":"{}"}}"#, handle.0))
+        }
+
+        fn modify(handle: Self::Handle, _settings: &str) -> Result<()> {
+            check_open(handle);
+            Ok(())
+        }
+
+        fn delete(_id: &GUID) -> Result<()> {
+            Ok(())
+        }
+
+        fn close(handle: Self::Handle) -> Result<()> {
+            if !OPEN.lock().unwrap().remove(&handle.0) {
+                VIOLATIONS.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(())
+        }
+    }
+
+    const WORKERS: usize = 8;
+    const SHARERS: usize = 4;
+    const ITERATIONS: usize = 200;
+
+    fn worker(worker: usize, reaper: mpsc::Sender<Arc<OwnedHandle<SimObject>>>) {
+        for i in 0..ITERATIONS {
+            let id = format!("{:08x}-0000-0000-0000-{:012x}", worker, i);
+            let handle = Arc::new(OwnedHandle::<SimObject>::open(&id).unwrap());
+
+            thread::scope(|s| {
+                for _ in 0..SHARERS {
+                    let handle = Arc::clone(&handle);
+                    s.spawn(move || {
+                        let object = handle.properties().unwrap();
+                        assert!(!object.id.is_empty());
+                        handle.modify("{}").unwrap();
+                    });
+                }
+            });
+
+            // alternate between closing here and dropping the last reference
+            // on another thread
+            if i % 2 == 0 {
+                Arc::into_inner(handle).unwrap().close().unwrap();
+            } else {
+                reaper.send(handle).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn shared_handles_are_never_used_after_close() {
+        let (sender, receiver) = mpsc::channel();
+        let reaper = thread::spawn(move || receiver.into_iter().for_each(drop));
+
+        thread::scope(|s| {
+            for w in 0..WORKERS {
+                let sender = sender.clone();
+                s.spawn(move || worker(w, sender));
+            }
+        });
+        drop(sender);
+        reaper.join().unwrap();
+
+        assert_eq!(VIOLATIONS.load(Ordering::Relaxed), 0);
+        assert!(OPEN.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,440 @@
+//! OutBoundNAT, which gives traffic leaving pods on overlay and L2Bridge
+//! networks the node's address.
+//!
+//! Traffic to other pods, to Services and to nodes has to keep the pod's
+//! address, so their CIDRs are the policy's exceptions. When those change, for
+//! example when a Service CIDR is added, [`update_endpoints`] modifies the
+//! policy on the existing endpoints instead of recreating them.
+
+#[cfg(windows)]
+use crate::endpoint::modify_policies;
+use crate::prefix::{IpFamily, IpPrefix};
+use crate::schema::*;
+use anyhow::{bail, Context, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::IpAddr;
+
+/// The CIDRs of a cluster whose traffic must not be NATed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClusterCidrs {
+    /// The pod CIDRs, one per family on dual-stack clusters.
+    pub cluster: Vec<IpPrefix>,
+    pub service: Vec<IpPrefix>,
+    /// The subnets of the nodes' addresses.
+    pub node: Vec<IpPrefix>,
+}
+
+impl ClusterCidrs {
+    /// The exceptions for traffic of one family, merged and de-duplicated.
+    pub fn exceptions(&self, family: IpFamily) -> Vec<IpPrefix> {
+        let prefixes: Vec<IpPrefix> = self
+            .cluster
+            .iter()
+            .chain(&self.service)
+            .chain(&self.node)
+            .filter(|p| p.family() == family)
+            .copied()
+            .collect();
+        IpPrefix::merge(&prefixes)
+    }
+
+    /// An OutBoundNAT policy for each family of the pod CIDRs.
+    pub fn outbound_nat(&self) -> Vec<OutboundNat> {
+        let families: BTreeSet<IpFamily> = self.cluster.iter().map(|p| p.family()).collect();
+        families
+            .into_iter()
+            .map(|family| OutboundNat {
+                exceptions: self.exceptions(family),
+                ..OutboundNat::new(family)
+            })
+            .collect()
+    }
+}
+
+/// The OutBoundNAT policy of one address family.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboundNat {
+    pub family: IpFamily,
+    /// The address to SNAT to, the host's when not set.
+    pub vip: Option<IpAddr>,
+    /// Traffic to these keeps the endpoint's address.
+    pub exceptions: Vec<IpPrefix>,
+    /// Only SNAT traffic to these, all traffic when empty.
+    pub destinations: Vec<IpPrefix>,
+}
+
+impl OutboundNat {
+    pub fn new(family: IpFamily) -> Self {
+        Self {
+            family,
+            vip: None,
+            exceptions: vec![],
+            destinations: vec![],
+        }
+    }
+
+    /// Parse an `OutBoundNAT` endpoint policy.
+    pub fn from_policy(policy: &EndpointPolicy) -> Result<Self> {
+        if policy.policy_type != EndpointPolicyType::OutBoundNAT {
+            bail!(
+                "{:?} policy is not an OutBoundNAT policy",
+                policy.policy_type
+            );
+        }
+        let setting: OutboundNatPolicySetting = policy.settings_as()?;
+        let family = if setting.flags.unwrap_or_default() & NAT_FLAGS_IPV6 != 0 {
+            IpFamily::V6
+        } else {
+            IpFamily::V4
+        };
+        let prefixes = |values: &[String]| -> Result<Vec<IpPrefix>> {
+            values.iter().map(|v| v.parse()).collect()
+        };
+        let vip = match setting.virtual_ip.as_str() {
+            "" => None,
+            vip => Some(
+                vip.parse()
+                    .with_context(|| format!("invalid VIP {}", vip))?,
+            ),
+        };
+        Ok(Self {
+            family,
+            vip,
+            exceptions: prefixes(&setting.exceptions)?,
+            destinations: prefixes(&setting.destinations)?,
+        })
+    }
+
+    /// The endpoint policy, with the exceptions and destinations merged.
+    pub fn policy(&self) -> Result<EndpointPolicy> {
+        if let Some(vip) = self.vip.filter(|v| IpFamily::of(v) != self.family) {
+            bail!("OutBoundNAT VIP {} is not an {} address", vip, self.family);
+        }
+        let mut prefixes = self.exceptions.iter().chain(&self.destinations);
+        if let Some(prefix) = prefixes.find(|p| p.family() != self.family) {
+            bail!(
+                "OutBoundNAT prefix {} is not an {} prefix",
+                prefix,
+                self.family
+            );
+        }
+
+        let strings = |prefixes: &[IpPrefix]| -> Vec<String> {
+            IpPrefix::merge(prefixes)
+                .iter()
+                .map(|p| p.to_string())
+                .collect()
+        };
+        let setting = OutboundNatPolicySetting {
+            virtual_ip: self.vip.map(|v| v.to_string()).unwrap_or_default(),
+            exceptions: strings(&self.exceptions),
+            destinations: strings(&self.destinations),
+            flags: (self.family == IpFamily::V6).then_some(NAT_FLAGS_IPV6),
+            extra_fields: ExtraFields::new(),
+        };
+        Ok(EndpointPolicy::new(
+            EndpointPolicyType::OutBoundNAT,
+            &setting,
+        )?)
+    }
+
+    // Compare policies regardless of how their prefixes are listed.
+    fn normalized(&self) -> Self {
+        Self {
+            exceptions: IpPrefix::merge(&self.exceptions),
+            destinations: IpPrefix::merge(&self.destinations),
+            ..self.clone()
+        }
+    }
+}
+
+/// The OutBoundNAT policies of an endpoint.
+pub fn endpoint_outbound_nat(endpoint: &HostComputeEndpoint) -> Result<Vec<OutboundNat>> {
+    endpoint
+        .policies
+        .iter()
+        .filter(|p| p.policy_type == EndpointPolicyType::OutBoundNAT)
+        .map(OutboundNat::from_policy)
+        .collect()
+}
+
+/// The modify requests that change the OutBoundNAT policies of an endpoint
+/// to `nats`, at most one per family. Families the endpoint lacks are added,
+/// those that differ are updated and those no longer wanted are removed, as
+/// are policies that do not parse or repeat a family. Removals go first, so an
+/// endpoint never has two policies of one family. Empty when nothing changes.
+pub fn plan(
+    endpoint: &HostComputeEndpoint,
+    nats: &[OutboundNat],
+) -> Result<Vec<(RequestType, Vec<EndpointPolicy>)>> {
+    let mut desired = BTreeMap::new();
+    for nat in nats {
+        if desired.insert(nat.family, nat.normalized()).is_some() {
+            bail!("more than one {} OutBoundNAT policy", nat.family);
+        }
+    }
+
+    let mut current = BTreeMap::new();
+    let mut remove = vec![];
+    for policy in endpoint
+        .policies
+        .iter()
+        .filter(|p| p.policy_type == EndpointPolicyType::OutBoundNAT)
+    {
+        match OutboundNat::from_policy(policy) {
+            Ok(nat) if !current.contains_key(&nat.family) => {
+                current.insert(nat.family, (nat.normalized(), policy));
+            }
+            _ => remove.push(policy.clone()),
+        }
+    }
+
+    let mut update = vec![];
+    for (family, (nat, policy)) in &current {
+        match desired.get(family) {
+            None => remove.push((*policy).clone()),
+            Some(wanted) if wanted != nat => update.push(wanted.policy()?),
+            Some(_) => {}
+        }
+    }
+    let add = desired
+        .iter()
+        .filter(|(family, _)| !current.contains_key(*family))
+        .map(|(_, nat)| nat.policy())
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok([
+        (RequestType::Remove, remove),
+        (RequestType::Update, update),
+        (RequestType::Add, add),
+    ]
+    .into_iter()
+    .filter(|(_, policies)| !policies.is_empty())
+    .collect())
+}
+
+/// Change the OutBoundNAT policies of an existing endpoint to `nats`, as
+/// [`plan`]ned. Returns false when the endpoint already has them.
+#[cfg(windows)]
+pub fn update(endpoint: &HostComputeEndpoint, nats: &[OutboundNat]) -> Result<bool> {
+    let requests = plan(endpoint, nats)?;
+    if requests.is_empty() {
+        return Ok(false);
+    }
+    for (request_type, policies) in requests {
+        modify_policies(&endpoint.id, request_type, policies)
+            .with_context(|| format!("failed to update OutBoundNAT of endpoint {}", endpoint.id))?;
+    }
+    Ok(true)
+}
+
+/// Outcome of [`update_endpoints`], by endpoint id.
+#[derive(Debug, Default)]
+pub struct UpdateReport {
+    pub updated: Vec<String>,
+    pub unchanged: Vec<String>,
+    pub failed: Vec<(String, anyhow::Error)>,
+}
+
+/// Change the OutBoundNAT policies of the local endpoints among `endpoints`,
+/// e.g. those of a network, to `nats`. A failure does not stop the others.
+#[cfg(windows)]
+pub fn update_endpoints(endpoints: &[HostComputeEndpoint], nats: &[OutboundNat]) -> UpdateReport {
+    let mut report = UpdateReport::default();
+    for endpoint in endpoints.iter().filter(|e| !e.is_remote()) {
+        match update(endpoint, nats) {
+            Ok(true) => report.updated.push(endpoint.id.clone()),
+            Ok(false) => report.unchanged.push(endpoint.id.clone()),
+            Err(e) => report.failed.push((endpoint.id.clone(), e)),
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn prefixes(prefixes: &[&str]) -> Vec<IpPrefix> {
+        prefixes.iter().map(|p| p.parse().unwrap()).collect()
+    }
+
+    fn cidrs() -> ClusterCidrs {
+        ClusterCidrs {
+            cluster: prefixes(&["10.244.0.0/16", "fd00:244::/56"]),
+            service: prefixes(&["10.96.0.0/12", "fd00:96::/108"]),
+            // two node subnets next to each other, one listed twice, and a
+            // prefix already inside the pod CIDR
+            node: prefixes(&["10.0.0.0/24", "10.0.1.0/24", "10.0.1.0/24", "10.244.3.0/24"]),
+        }
+    }
+
+    fn endpoint(policies: Vec<EndpointPolicy>) -> HostComputeEndpoint {
+        HostComputeEndpoint {
+            id: "ep-1".to_string(),
+            policies,
+            ..Default::default()
+        }
+    }
+
+    fn policies(nats: &[OutboundNat]) -> Vec<EndpointPolicy> {
+        nats.iter().map(|n| n.policy().unwrap()).collect()
+    }
+
+    fn v4() -> OutboundNat {
+        cidrs().outbound_nat().remove(0)
+    }
+
+    fn v6() -> OutboundNat {
+        cidrs().outbound_nat().remove(1)
+    }
+
+    #[test]
+    fn exceptions_are_merged() {
+        assert_eq!(
+            cidrs().exceptions(IpFamily::V4),
+            prefixes(&["10.0.0.0/23", "10.96.0.0/12", "10.244.0.0/16"])
+        );
+        assert_eq!(
+            serde_json::to_value(policies(&cidrs().outbound_nat())).unwrap(),
+            json!([
+                {
+                    "Type": "OutBoundNAT",
+                    "Settings": {"Exceptions": ["10.0.0.0/23", "10.96.0.0/12", "10.244.0.0/16"]},
+                },
+                {
+                    "Type": "OutBoundNAT",
+                    "Settings": {"Exceptions": ["fd00:96::/108", "fd00:244::/56"], "Flags": 2},
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn policy_round_trip() {
+        let nat = OutboundNat {
+            vip: Some("10.0.0.4".parse().unwrap()),
+            destinations: prefixes(&["0.0.0.0/0"]),
+            ..v4()
+        };
+        assert_eq!(
+            OutboundNat::from_policy(&nat.policy().unwrap()).unwrap(),
+            nat
+        );
+
+        let wrong_family = OutboundNat {
+            vip: Some("fd00::4".parse().unwrap()),
+            ..v4()
+        };
+        assert!(wrong_family.policy().is_err());
+    }
+
+    #[test]
+    fn up_to_date_endpoint_is_left_alone() {
+        // the same exceptions, listed differently
+        let mut current = policies(&[v4(), v6()]);
+        current[0].settings = Some(json!({
+            "Exceptions": ["10.244.0.0/16", "10.0.1.0/24", "10.96.0.0/12", "10.0.0.0/24"],
+        }));
+        current.reverse();
+        assert_eq!(plan(&endpoint(current), &[v4(), v6()]).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn missing_family_is_added() {
+        let endpoint = endpoint(policies(&[v4()]));
+        assert_eq!(
+            plan(&endpoint, &[v4(), v6()]).unwrap(),
+            vec![(RequestType::Add, policies(&[v6()]))]
+        );
+    }
+
+    #[test]
+    fn changed_family_is_updated() {
+        let old = OutboundNat {
+            exceptions: prefixes(&["10.244.0.0/16"]),
+            ..v4()
+        };
+        let endpoint = endpoint(policies(&[old, v6()]));
+        assert_eq!(
+            plan(&endpoint, &[v4(), v6()]).unwrap(),
+            vec![(RequestType::Update, policies(&[v4()]))]
+        );
+    }
+
+    #[test]
+    fn unwanted_family_is_removed() {
+        let current = policies(&[v4(), v6()]);
+        assert_eq!(
+            plan(&endpoint(current.clone()), &[v4()]).unwrap(),
+            vec![(RequestType::Remove, current[1..].to_vec())]
+        );
+        assert_eq!(
+            plan(&endpoint(current.clone()), &[]).unwrap(),
+            vec![(RequestType::Remove, current)]
+        );
+    }
+
+    #[test]
+    fn removals_go_before_adds() {
+        let old_v6 = OutboundNat {
+            exceptions: prefixes(&["fd00:244::/56"]),
+            ..v6()
+        };
+        let endpoint = endpoint(policies(&[old_v6]));
+        assert_eq!(
+            plan(&endpoint, &[v4()]).unwrap(),
+            vec![
+                (RequestType::Remove, endpoint.policies.clone()),
+                (RequestType::Add, policies(&[v4()])),
+            ]
+        );
+    }
+
+    #[test]
+    fn broken_and_repeated_policies_are_replaced() {
+        let broken = EndpointPolicy {
+            settings: Some(json!({"Exceptions": ["not a prefix"]})),
+            ..v4().policy().unwrap()
+        };
+        let repeated = OutboundNat {
+            exceptions: prefixes(&["fd00::/8"]),
+            ..v6()
+        };
+        let endpoint = endpoint(vec![
+            broken.clone(),
+            v6().policy().unwrap(),
+            repeated.policy().unwrap(),
+        ]);
+        assert_eq!(
+            plan(&endpoint, &[v4(), v6()]).unwrap(),
+            vec![
+                (
+                    RequestType::Remove,
+                    vec![broken, repeated.policy().unwrap()]
+                ),
+                (RequestType::Add, policies(&[v4()])),
+            ]
+        );
+    }
+
+    #[test]
+    fn one_policy_per_family() {
+        assert!(plan(&endpoint(vec![]), &[v4(), v4()]).is_err());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn update_endpoints_skips_remote_and_up_to_date_endpoints() {
+        let remote = HostComputeEndpoint {
+            id: "ep-remote".to_string(),
+            flags: Some(ENDPOINT_FLAGS_REMOTE_ENDPOINT),
+            ..Default::default()
+        };
+        let nats = [v4(), v6()];
+        let report = update_endpoints(&[endpoint(policies(&nats)), remote], &nats);
+        assert_eq!(report.unchanged, vec!["ep-1".to_string()]);
+        assert!(report.updated.is_empty() && report.failed.is_empty());
+    }
+}
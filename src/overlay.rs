@@ -0,0 +1,338 @@
+//! VXLAN overlay networks.
+//!
+//! Every node of an overlay owns a subnet and reaches the subnets of the other
+//! nodes through their provider addresses (PA), the node IPs the VXLAN
+//! packets are sent between. [`OverlayNetworkBuilder`] produces the network
+//! for one node, [`add_remote_subnets`] and [`remove_remote_subnets`] keep its
+//! routes to the other nodes current as they join and leave.
+
+use crate::mac::MacAddress;
+use crate::network::check_gateway;
+#[cfg(windows)]
+use crate::network::modify_policies;
+use crate::prefix::IpPrefix;
+use crate::schema::*;
+use anyhow::{bail, Result};
+use std::net::IpAddr;
+
+/// The IANA assigned VXLAN port.
+pub const DEFAULT_VXLAN_PORT: u16 = 4789;
+
+/// HNS does not accept VXLAN network identifiers below this.
+pub const MIN_VNI: u32 = 4096;
+
+const MAX_VNI: u32 = (1 << 24) - 1;
+
+/// A subnet hosted by another node of the overlay.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteSubnet {
+    pub prefix: IpPrefix,
+    /// The other node's provider address.
+    pub provider_address: IpAddr,
+    /// The MAC of the other node's distributed router.
+    pub dr_mac: MacAddress,
+}
+
+impl RemoteSubnet {
+    fn policy(&self, vni: u32) -> serde_json::Result<NetworkPolicy> {
+        NetworkPolicy::new(
+            NetworkPolicyType::RemoteSubnetRoute,
+            &RemoteSubnetRoutePolicySetting {
+                destination_prefix: self.prefix.to_string(),
+                isolation_id: vni,
+                provider_address: self.provider_address.to_string(),
+                distributed_router_mac_address: self.dr_mac.to_string(),
+                ..Default::default()
+            },
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OverlayNetworkBuilder {
+    name: String,
+    vni: u32,
+    vxlan_port: u16,
+    provider_address: Option<IpAddr>,
+    dr_mac: Option<MacAddress>,
+    subnets: Vec<(IpPrefix, IpAddr)>,
+    remote_subnets: Vec<RemoteSubnet>,
+}
+
+impl OverlayNetworkBuilder {
+    pub fn new(name: &str, vni: u32) -> Self {
+        Self {
+            name: name.to_string(),
+            vni,
+            vxlan_port: DEFAULT_VXLAN_PORT,
+            provider_address: None,
+            dr_mac: None,
+            subnets: vec![],
+            remote_subnets: vec![],
+        }
+    }
+
+    pub fn vxlan_port(mut self, port: u16) -> Self {
+        self.vxlan_port = port;
+        self
+    }
+
+    /// This node's provider address, the IP other nodes send VXLAN traffic to.
+    pub fn provider_address(mut self, address: IpAddr) -> Self {
+        self.provider_address = Some(address);
+        self
+    }
+
+    /// The MAC of this node's distributed router.
+    pub fn dr_mac(mut self, mac: MacAddress) -> Self {
+        self.dr_mac = Some(mac);
+        self
+    }
+
+    /// A subnet for endpoints on this node, routed through `gateway`.
+    pub fn subnet(mut self, prefix: IpPrefix, gateway: IpAddr) -> Self {
+        self.subnets.push((prefix, gateway));
+        self
+    }
+
+    pub fn remote_subnet(mut self, remote: RemoteSubnet) -> Self {
+        self.remote_subnets.push(remote);
+        self
+    }
+
+    pub fn build(self) -> Result<HostComputeNetwork> {
+        check_vni(self.vni)?;
+        let Some(provider_address) = self.provider_address else {
+            bail!("overlay network {} needs a provider address", self.name);
+        };
+        if self.subnets.is_empty() {
+            bail!("overlay network {} needs at least one subnet", self.name);
+        }
+        for (prefix, gateway) in &self.subnets {
+            check_gateway(prefix, gateway)?;
+        }
+
+        for remote in &self.remote_subnets {
+            if let Some((local, _)) = self
+                .subnets
+                .iter()
+                .find(|(s, _)| s.overlaps(&remote.prefix))
+            {
+                bail!(
+                    "remote subnet {} overlaps local subnet {}",
+                    remote.prefix,
+                    local
+                );
+            }
+        }
+
+        let vsid = serde_json::json!({
+            "Type": "VSID",
+            "Settings": VsidPolicySetting {
+                isolation_id: self.vni,
+                ..Default::default()
+            },
+        });
+        let subnets = self
+            .subnets
+            .iter()
+            .map(|(prefix, gateway)| Subnet {
+                ip_address_prefix: Some(prefix.to_string()),
+                policies: vec![vsid.clone()],
+                routes: vec![Route::default_via(gateway)],
+                extra_fields: ExtraFields::new(),
+            })
+            .collect();
+
+        let mut policies = vec![
+            NetworkPolicy::new(
+                NetworkPolicyType::VxlanPort,
+                &VxlanPortPolicySetting {
+                    port: self.vxlan_port,
+                    ..Default::default()
+                },
+            )?,
+            NetworkPolicy::new(
+                NetworkPolicyType::ProviderAddress,
+                &ProviderAddressPolicySetting {
+                    provider_address: provider_address.to_string(),
+                    ..Default::default()
+                },
+            )?,
+        ];
+        if let Some(dr_mac) = self.dr_mac {
+            policies.push(NetworkPolicy::new(
+                NetworkPolicyType::DrMacAddress,
+                &DrMacAddressPolicySetting {
+                    address: dr_mac.to_string(),
+                    ..Default::default()
+                },
+            )?);
+        }
+        for remote in &self.remote_subnets {
+            policies.push(remote.policy(self.vni)?);
+        }
+
+        let network = HostComputeNetwork {
+            name: self.name,
+            network_type: Some(NetworkType::Overlay),
+            policies,
+            ipams: vec![Ipam {
+                subnets,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        network.validate()?;
+        Ok(network)
+    }
+}
+
+/// Route the subnets of nodes that joined the overlay.
+#[cfg(windows)]
+pub fn add_remote_subnets(network_id: &str, vni: u32, remotes: &[RemoteSubnet]) -> Result<()> {
+    modify_remote_subnets(network_id, vni, RequestType::Add, remotes)
+}
+
+/// Stop routing the subnets of nodes that left the overlay.
+#[cfg(windows)]
+pub fn remove_remote_subnets(network_id: &str, vni: u32, remotes: &[RemoteSubnet]) -> Result<()> {
+    modify_remote_subnets(network_id, vni, RequestType::Remove, remotes)
+}
+
+#[cfg(windows)]
+fn modify_remote_subnets(
+    network_id: &str,
+    vni: u32,
+    request_type: RequestType,
+    remotes: &[RemoteSubnet],
+) -> Result<()> {
+    check_vni(vni)?;
+    if remotes.is_empty() {
+        return Ok(());
+    }
+    let policies = remotes
+        .iter()
+        .map(|r| r.policy(vni))
+        .collect::<serde_json::Result<Vec<_>>>()?;
+    modify_policies(network_id, request_type, policies)
+}
+
+fn check_vni(vni: u32) -> Result<()> {
+    if !(MIN_VNI..=MAX_VNI).contains(&vni) {
+        bail!("VNI {} is outside {}-{}", vni, MIN_VNI, MAX_VNI);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn prefix(s: &str) -> IpPrefix {
+        s.parse().unwrap()
+    }
+
+    fn builder(vni: u32) -> OverlayNetworkBuilder {
+        OverlayNetworkBuilder::new("vxlan0", vni)
+            .provider_address(ip("192.168.1.10"))
+            .subnet(prefix("10.244.1.0/24"), ip("10.244.1.1"))
+    }
+
+    #[test]
+    fn vni_bounds() {
+        assert!(builder(MIN_VNI - 1).build().is_err());
+        assert!(builder(MIN_VNI).build().is_ok());
+        assert!(builder(MAX_VNI).build().is_ok());
+        assert!(builder(MAX_VNI + 1).build().is_err());
+        assert!(builder(0).build().is_err());
+    }
+
+    #[test]
+    fn rejects_incomplete_networks() {
+        assert!(OverlayNetworkBuilder::new("vxlan0", 4096)
+            .provider_address(ip("192.168.1.10"))
+            .build()
+            .is_err());
+        assert!(OverlayNetworkBuilder::new("vxlan0", 4096)
+            .subnet(prefix("10.244.1.0/24"), ip("10.244.1.1"))
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_bad_gateways() {
+        for gateway in ["10.244.2.1", "10.244.1.0", "10.244.1.255"] {
+            let result = OverlayNetworkBuilder::new("vxlan0", 4096)
+                .provider_address(ip("192.168.1.10"))
+                .subnet(prefix("10.244.1.0/24"), ip(gateway))
+                .build();
+            assert!(result.is_err(), "{}", gateway);
+        }
+    }
+
+    #[test]
+    fn rejects_remote_subnets_overlapping_local_ones() {
+        let remote = RemoteSubnet {
+            prefix: prefix("10.244.0.0/16"),
+            provider_address: ip("192.168.1.11"),
+            dr_mac: "0E-2A-C0-A8-01-0B".parse().unwrap(),
+        };
+        assert!(builder(4096).remote_subnet(remote).build().is_err());
+    }
+
+    #[test]
+    fn generated_network() {
+        let network = builder(4097)
+            .vxlan_port(4790)
+            .dr_mac("0E-2A-C0-A8-01-0A".parse().unwrap())
+            .remote_subnet(RemoteSubnet {
+                prefix: prefix("10.244.2.0/24"),
+                provider_address: ip("192.168.1.11"),
+                dr_mac: "0E-2A-C0-A8-01-0B".parse().unwrap(),
+            })
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&network).unwrap(),
+            serde_json::json!({
+                "Name": "vxlan0",
+                "Type": "Overlay",
+                "Policies": [
+                    { "Type": "VxlanPort", "Settings": { "Port": 4790 } },
+                    {
+                        "Type": "ProviderAddress",
+                        "Settings": { "ProviderAddress": "192.168.1.10" }
+                    },
+                    {
+                        "Type": "DrMacAddress",
+                        "Settings": { "Address": "0E-2A-C0-A8-01-0A" }
+                    },
+                    {
+                        "Type": "RemoteSubnetRoute",
+                        "Settings": {
+                            "DestinationPrefix": "10.244.2.0/24",
+                            "DistributedRouterMacAddress": "0E-2A-C0-A8-01-0B",
+                            "IsolationId": 4097,
+                            "ProviderAddress": "192.168.1.11"
+                        }
+                    }
+                ],
+                "Ipams": [{
+                    "Type": "Static",
+                    "Subnets": [{
+                        "IpAddressPrefix": "10.244.1.0/24",
+                        "Policies": [{ "Type": "VSID", "Settings": { "IsolationId": 4097 } }],
+                        "Routes": [{ "NextHop": "10.244.1.1", "DestinationPrefix": "0.0.0.0/0" }]
+                    }]
+                }],
+                "SchemaVersion": { "Major": 2, "Minor": 2 }
+            })
+        );
+    }
+}
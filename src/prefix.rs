@@ -0,0 +1,414 @@
+//! IP prefixes as used by HNS subnets, routes and policies.
+
+use anyhow::{bail, Context, Result};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// Address family of an IP address or prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    pub fn of(ip: &IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(_) => IpFamily::V4,
+            IpAddr::V6(_) => IpFamily::V6,
+        }
+    }
+
+    /// Number of bits in an address of this family.
+    pub fn bits(&self) -> u8 {
+        match self {
+            IpFamily::V4 => 32,
+            IpFamily::V6 => 128,
+        }
+    }
+}
+
+impl fmt::Display for IpFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpFamily::V4 => write!(f, "IPv4"),
+            IpFamily::V6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+/// An IPv4 or IPv6 prefix in CIDR notation, e.g. `10.0.0.0/16` or `fd00::/64`.
+///
+/// The address is always stored with the host bits cleared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct IpPrefix {
+    addr: IpAddr,
+    len: u8,
+}
+
+impl IpPrefix {
+    pub fn new(addr: IpAddr, len: u8) -> Result<Self> {
+        let family = IpFamily::of(&addr);
+        if len > family.bits() {
+            bail!("prefix length {} is too long for {}", len, addr);
+        }
+        let bits = to_bits(&addr) & mask(family, len);
+        Ok(Self {
+            addr: from_bits(family, bits),
+            len,
+        })
+    }
+
+    /// A prefix matching exactly one address.
+    pub fn host(addr: IpAddr) -> Self {
+        Self {
+            addr,
+            len: IpFamily::of(&addr).bits(),
+        }
+    }
+
+    pub fn family(&self) -> IpFamily {
+        IpFamily::of(&self.addr)
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.len
+    }
+
+    pub fn is_ipv4(&self) -> bool {
+        self.addr.is_ipv4()
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        self.addr.is_ipv6()
+    }
+
+    /// The first address of the prefix.
+    pub fn network(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// The last address of the prefix, the broadcast address for IPv4.
+    pub fn last(&self) -> IpAddr {
+        let family = self.family();
+        from_bits(
+            family,
+            to_bits(&self.addr) | (!mask(family, self.len) & all(family)),
+        )
+    }
+
+    /// Number of addresses covered, saturating at `u128::MAX` for `::/0`.
+    pub fn size(&self) -> u128 {
+        let host_bits = (self.family().bits() - self.len) as u32;
+        1u128.checked_shl(host_bits).unwrap_or(u128::MAX)
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        IpFamily::of(ip) == self.family()
+            && to_bits(ip) & mask(self.family(), self.len) == to_bits(&self.addr)
+    }
+
+    /// True when `other` lies entirely inside this prefix.
+    pub fn contains_prefix(&self, other: &IpPrefix) -> bool {
+        other.len >= self.len && self.contains(&other.addr)
+    }
+
+    pub fn overlaps(&self, other: &IpPrefix) -> bool {
+        self.contains_prefix(other) || other.contains_prefix(self)
+    }
+
+    /// The prefixes of length `len` this prefix divides into, in order.
+    pub fn subnets(&self, len: u8) -> Result<impl Iterator<Item = IpPrefix>> {
+        let family = self.family();
+        if len < self.len || len > family.bits() {
+            bail!("{} cannot be divided into /{} subnets", self, len);
+        }
+        let start = to_bits(&self.addr);
+        let host_bits = (family.bits() - len) as u32;
+        let count = 1u128
+            .checked_shl((len - self.len) as u32)
+            .unwrap_or(u128::MAX);
+        Ok((0..count).map(move |i| Self {
+            addr: from_bits(family, start | i.checked_shl(host_bits).unwrap_or(0)),
+            len,
+        }))
+    }
+
+    /// The parts of this prefix outside all of `others`, as the fewest prefixes.
+    pub fn exclude(&self, others: &[IpPrefix]) -> Vec<IpPrefix> {
+        if others.iter().any(|o| o.contains_prefix(self)) {
+            return vec![];
+        }
+        if !others.iter().any(|o| o.overlaps(self)) {
+            return vec![*self];
+        }
+        // an excluded prefix lies strictly inside, so this one can be halved
+        let family = self.family();
+        let len = self.len + 1;
+        let lower = Self {
+            addr: self.addr,
+            len,
+        };
+        let upper = Self {
+            addr: from_bits(family, to_bits(&self.addr) | 1 << (family.bits() - len)),
+            len,
+        };
+        let mut remaining = lower.exclude(others);
+        remaining.extend(upper.exclude(others));
+        remaining
+    }
+
+    /// The fewest prefixes covering the same addresses as `prefixes`, in
+    /// order: duplicates and prefixes inside others are dropped and adjacent
+    /// halves are joined.
+    pub fn merge(prefixes: &[IpPrefix]) -> Vec<IpPrefix> {
+        let mut sorted = prefixes.to_vec();
+        // a prefix sorts before the prefixes inside it
+        sorted.sort();
+
+        let mut merged: Vec<IpPrefix> = vec![];
+        for prefix in sorted {
+            if merged.last().is_some_and(|m| m.contains_prefix(&prefix)) {
+                continue;
+            }
+            merged.push(prefix);
+            while let [.., lower, upper] = merged[..] {
+                if lower.len != upper.len || lower.len == 0 {
+                    break;
+                }
+                let family = lower.family();
+                let parent = Self {
+                    addr: from_bits(family, to_bits(&lower.addr) & mask(family, lower.len - 1)),
+                    len: lower.len - 1,
+                };
+                if !parent.contains_prefix(&upper) {
+                    break;
+                }
+                merged.truncate(merged.len() - 2);
+                merged.push(parent);
+            }
+        }
+        merged
+    }
+
+    /// The default route destination of a family, `0.0.0.0/0` or `::/0`.
+    pub fn default_route(family: IpFamily) -> Self {
+        let addr = match family {
+            IpFamily::V4 => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpFamily::V6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        Self { addr, len: 0 }
+    }
+}
+
+impl FromStr for IpPrefix {
+    type Err = anyhow::Error;
+
+    /// Parse `address/length`. A bare address is a host prefix.
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('/') {
+            Some((addr, len)) => {
+                let addr: IpAddr = addr
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("invalid prefix {}", s))?;
+                let len: u8 = len
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("invalid prefix {}", s))?;
+                Self::new(addr, len)
+            }
+            None => {
+                let addr: IpAddr = s
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("invalid prefix {}", s))?;
+                Ok(Self::host(addr))
+            }
+        }
+    }
+}
+
+impl fmt::Display for IpPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+impl From<IpAddr> for IpPrefix {
+    fn from(addr: IpAddr) -> Self {
+        Self::host(addr)
+    }
+}
+
+pub(crate) fn to_bits(ip: &IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u32::from(*ip) as u128,
+        IpAddr::V6(ip) => u128::from(*ip),
+    }
+}
+
+pub(crate) fn from_bits(family: IpFamily, bits: u128) -> IpAddr {
+    match family {
+        IpFamily::V4 => IpAddr::V4(Ipv4Addr::from(bits as u32)),
+        IpFamily::V6 => IpAddr::V6(Ipv6Addr::from(bits)),
+    }
+}
+
+fn all(family: IpFamily) -> u128 {
+    match family {
+        IpFamily::V4 => u32::MAX as u128,
+        IpFamily::V6 => u128::MAX,
+    }
+}
+
+fn mask(family: IpFamily, len: u8) -> u128 {
+    let host_bits = (family.bits() - len) as u32;
+    all(family) & u128::MAX.checked_shl(host_bits).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn p(s: &str) -> IpPrefix {
+        s.parse().unwrap()
+    }
+
+    fn ps(prefixes: &[&str]) -> Vec<IpPrefix> {
+        prefixes.iter().map(|s| p(s)).collect()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(p("10.1.2.3/16").to_string(), "10.1.0.0/16");
+        assert_eq!(p("10.1.2.3").to_string(), "10.1.2.3/32");
+        assert_eq!(p("fd00::1/64").to_string(), "fd00::/64");
+        assert_eq!(p("fd00::1").prefix_len(), 128);
+        assert_eq!(p("0.0.0.0/0"), IpPrefix::default_route(IpFamily::V4));
+        assert_eq!(p("::/0"), IpPrefix::default_route(IpFamily::V6));
+        for invalid in [
+            "10.0.0.0/33",
+            "fd00::/129",
+            "10.0.0/8",
+            "10.0.0.0/",
+            "/8",
+            "",
+        ] {
+            assert!(invalid.parse::<IpPrefix>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn host_and_family() {
+        let host = IpPrefix::host(ip("10.0.0.7"));
+        assert_eq!(host.family(), IpFamily::V4);
+        assert_eq!(host.size(), 1);
+        assert_eq!(host.network(), host.last());
+        assert!(host.contains(&ip("10.0.0.7")));
+        assert!(!host.contains(&ip("10.0.0.8")));
+
+        let host = IpPrefix::host(ip("fd00::7"));
+        assert_eq!(host.family(), IpFamily::V6);
+        assert!(host.is_ipv6() && !host.is_ipv4());
+        assert_eq!(IpPrefix::from(ip("fd00::7")), host);
+    }
+
+    #[test]
+    fn bounds() {
+        assert_eq!(p("10.0.0.0/24").last(), ip("10.0.0.255"));
+        assert_eq!(p("0.0.0.0/0").last(), ip("255.255.255.255"));
+        assert_eq!(p("0.0.0.0/0").size(), 1 << 32);
+        assert_eq!(
+            p("::/0").last(),
+            ip("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff")
+        );
+        assert_eq!(p("::/0").size(), u128::MAX);
+        // an IPv4 address is never inside an IPv6 prefix
+        assert!(!p("::/0").contains(&ip("10.0.0.1")));
+        assert!(!p("0.0.0.0/0").overlaps(&p("::/0")));
+    }
+
+    #[test]
+    fn subnets() {
+        let subnets: Vec<IpPrefix> = p("10.0.0.0/23").subnets(24).unwrap().collect();
+        assert_eq!(subnets, ps(&["10.0.0.0/24", "10.0.1.0/24"]));
+        assert_eq!(p("10.0.0.0/24").subnets(24).unwrap().count(), 1);
+        assert!(p("10.0.0.0/24").subnets(23).is_err());
+        assert!(p("10.0.0.0/24").subnets(33).is_err());
+    }
+
+    #[test]
+    fn exclude() {
+        // disjoint
+        assert_eq!(
+            p("10.0.0.0/24").exclude(&ps(&["10.1.0.0/24"])),
+            ps(&["10.0.0.0/24"])
+        );
+        // itself, or something containing it
+        assert_eq!(p("10.0.0.0/24").exclude(&ps(&["10.0.0.0/24"])), vec![]);
+        assert_eq!(p("10.0.0.0/24").exclude(&ps(&["0.0.0.0/0"])), vec![]);
+        assert_eq!(p("10.0.0.1/32").exclude(&ps(&["10.0.0.1/32"])), vec![]);
+        assert_eq!(p("fd00::1/128").exclude(&ps(&["fd00::/64"])), vec![]);
+        // nested
+        assert_eq!(
+            p("10.0.0.0/24").exclude(&ps(&["10.0.0.0/26", "10.0.0.192/26"])),
+            ps(&["10.0.0.64/26", "10.0.0.128/26"])
+        );
+        assert_eq!(
+            p("10.0.0.0/30").exclude(&ps(&["10.0.0.2/32"])),
+            ps(&["10.0.0.0/31", "10.0.0.3/32"])
+        );
+        assert_eq!(
+            p("0.0.0.0/0").exclude(&ps(&["128.0.0.0/1"])),
+            ps(&["0.0.0.0/1"])
+        );
+        assert_eq!(p("::/0").exclude(&ps(&["::/1"])), ps(&["8000::/1"]));
+        // other families are not in the way
+        assert_eq!(p("10.0.0.0/8").exclude(&ps(&["::/0"])), ps(&["10.0.0.0/8"]));
+    }
+
+    #[test]
+    fn merge() {
+        assert_eq!(IpPrefix::merge(&[]), vec![]);
+        // duplicates and nested prefixes
+        assert_eq!(
+            IpPrefix::merge(&ps(&["10.0.1.0/24", "10.0.0.0/16", "10.0.0.0/16"])),
+            ps(&["10.0.0.0/16"])
+        );
+        // adjacent halves join, repeatedly
+        assert_eq!(
+            IpPrefix::merge(&ps(&["10.0.3.0/24", "10.0.2.0/24", "10.0.0.0/23"])),
+            ps(&["10.0.0.0/22"])
+        );
+        // adjacent but not halves of one prefix
+        assert_eq!(
+            IpPrefix::merge(&ps(&["10.0.1.0/24", "10.0.2.0/24"])),
+            ps(&["10.0.1.0/24", "10.0.2.0/24"])
+        );
+        // disjoint, both families, sorted
+        assert_eq!(
+            IpPrefix::merge(&ps(&["fd00::/64", "192.168.0.0/16", "10.0.0.0/8"])),
+            ps(&["10.0.0.0/8", "192.168.0.0/16", "fd00::/64"])
+        );
+        // hosts and the whole address space
+        assert_eq!(
+            IpPrefix::merge(&ps(&["10.0.0.0/32", "10.0.0.1/32"])),
+            ps(&["10.0.0.0/31"])
+        );
+        assert_eq!(
+            IpPrefix::merge(&ps(&["fd00::1/128", "fd00::/128"])),
+            ps(&["fd00::/127"])
+        );
+        assert_eq!(
+            IpPrefix::merge(&ps(&["0.0.0.0/1", "128.0.0.0/1", "10.0.0.0/8"])),
+            ps(&["0.0.0.0/0"])
+        );
+        assert_eq!(IpPrefix::merge(&ps(&["::/0", "::/0"])), ps(&["::/0"]));
+    }
+}
@@ -0,0 +1,414 @@
+//! Retrying HNS calls that fail for transient reasons.
+//!
+//! Right after boot, or while the HNS service restarts, calls fail with RPC
+//! errors or report the manager as stopped or busy. [`RetryPolicy::retry`]
+//! repeats a call with exponential backoff and jitter until it succeeds, fails
+//! with an error that will not go away by itself, or runs out of attempts or
+//! time.
+//!
+//! The crate retries the calls that only read, opening a handle, querying it
+//! and enumerating objects, with [`RetryPolicy::reads`], which rides out a
+//! short HNS restart. Writes are not retried: they are not idempotent, a
+//! create whose reply was lost may still have happened, and repeating it fails
+//! or makes a second object. Waiting longer is also the caller's call, an
+//! agent starting at boot can wait much longer than a command line tool.
+//! Callers wrap the calls they know are safe to repeat.
+
+use anyhow::{Context, Result};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+#[cfg(windows)]
+use windows::core::HRESULT;
+#[cfg(windows)]
+use windows::Win32::Foundation::{
+    ERROR_BUSY, ERROR_RETRY, ERROR_SERVICE_NOT_ACTIVE, HCN_E_MANAGER_STOPPED, RPC_E_DISCONNECTED,
+};
+
+#[cfg(windows)]
+const RPC_S_SERVER_UNAVAILABLE: HRESULT = HRESULT::from_win32(1722);
+#[cfg(windows)]
+const RPC_S_SERVER_TOO_BUSY: HRESULT = HRESULT::from_win32(1723);
+#[cfg(windows)]
+const RPC_S_CALL_FAILED: HRESULT = HRESULT::from_win32(1726);
+
+/// Whether a failed call is worth repeating.
+#[cfg(windows)]
+pub fn is_transient_hresult(code: HRESULT) -> bool {
+    code == HCN_E_MANAGER_STOPPED
+        || code == RPC_E_DISCONNECTED
+        || code == RPC_S_SERVER_UNAVAILABLE
+        || code == RPC_S_SERVER_TOO_BUSY
+        || code == RPC_S_CALL_FAILED
+        || code == HRESULT::from_win32(ERROR_BUSY.0)
+        || code == HRESULT::from_win32(ERROR_RETRY.0)
+        || code == HRESULT::from_win32(ERROR_SERVICE_NOT_ACTIVE.0)
+}
+
+/// Whether an error returned by the `api` functions is transient. Errors that
+/// did not come from Windows, e.g. JSON errors, never are.
+#[cfg(windows)]
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .filter_map(|e| e.downcast_ref::<windows::core::Error>())
+        .any(|e| is_transient_hresult(e.code()))
+}
+
+/// Source of time for [`RetryPolicy`], replaceable to test retries without waiting.
+pub trait Clock {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration);
+}
+
+/// The real clock.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first one.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: f64,
+    /// Fraction of each backoff that is randomized, between 0 and 1. A jitter
+    /// of 0.2 waits between 80% and 100% of the backoff.
+    pub jitter: f64,
+    /// Give up once the next attempt would start later than this after the first.
+    pub deadline: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 8,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            multiplier: 2.0,
+            jitter: 0.2,
+            deadline: Some(Duration::from_secs(60)),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that makes a single attempt.
+    pub fn never() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// The policy the crate applies to the calls it retries itself: a few
+    /// quick attempts, so a caller is not held up for more than a few seconds.
+    pub fn reads() -> Self {
+        Self {
+            max_attempts: 5,
+            max_backoff: Duration::from_secs(1),
+            deadline: Some(Duration::from_secs(5)),
+            ..Default::default()
+        }
+    }
+
+    /// The backoff before retry number `retry` (starting at 1), without jitter.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let exponent = retry.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let factor = self.multiplier.powi(exponent);
+        Duration::try_from_secs_f64(self.initial_backoff.as_secs_f64() * factor)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+
+    /// Run `f` until it succeeds or fails permanently. `operation` names the
+    /// call in logs and in the returned error.
+    #[cfg(windows)]
+    pub fn retry<T>(&self, operation: &str, f: impl FnMut() -> Result<T>) -> Result<T> {
+        self.retry_with_clock(&SystemClock, operation, f)
+    }
+
+    #[cfg(windows)]
+    pub fn retry_with_clock<T>(
+        &self,
+        clock: &impl Clock,
+        operation: &str,
+        f: impl FnMut() -> Result<T>,
+    ) -> Result<T> {
+        self.retry_when(clock, operation, is_transient, f)
+    }
+
+    /// Like `retry_with_clock`, with the errors worth repeating picked by
+    /// `transient` instead of `is_transient`.
+    pub fn retry_when<T>(
+        &self,
+        clock: &impl Clock,
+        operation: &str,
+        transient: impl Fn(&anyhow::Error) -> bool,
+        mut f: impl FnMut() -> Result<T>,
+    ) -> Result<T> {
+        let start = clock.now();
+        let max_attempts = self.max_attempts.max(1);
+
+        let mut attempt = 1;
+        loop {
+            let error = match f() {
+                Ok(value) => {
+                    if attempt > 1 {
+                        log::info!("{} succeeded on attempt {}", operation, attempt);
+                    }
+                    return Ok(value);
+                }
+                Err(e) => e,
+            };
+
+            if !transient(&error) {
+                return Err(error);
+            }
+            if attempt >= max_attempts {
+                return Err(error)
+                    .with_context(|| format!("{} failed after {} attempts", operation, attempt));
+            }
+
+            let delay = self.delay(attempt);
+            if let Some(deadline) = self.deadline {
+                if clock.now() + delay > start + deadline {
+                    return Err(error).with_context(|| {
+                        format!("{} did not succeed within {:?}", operation, deadline)
+                    });
+                }
+            }
+
+            log::warn!(
+                "{} failed (attempt {}/{}), retrying in {:?}: {}",
+                operation,
+                attempt,
+                max_attempts,
+                delay,
+                error
+            );
+            clock.sleep(delay);
+            attempt += 1;
+        }
+    }
+
+    /// The backoff before retry number `retry` with jitter applied.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let backoff = self.backoff(retry);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return backoff;
+        }
+        // RandomState's keys come from a random seed per thread that is
+        // incremented for every new instance, so each empty hash differs,
+        // which is all the randomness jitter needs
+        let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+        backoff.mul_f64(1.0 - jitter * random)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    #[cfg(windows)]
+    use windows::Win32::Foundation::E_FAIL;
+
+    // Time only moves when the policy sleeps.
+    struct FakeClock {
+        now: Cell<Instant>,
+        sleeps: RefCell<Vec<Duration>>,
+    }
+
+    impl FakeClock {
+        fn new() -> Self {
+            Self {
+                now: Cell::new(Instant::now()),
+                sleeps: RefCell::default(),
+            }
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.now.get()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            self.now.set(self.now.get() + duration);
+            self.sleeps.borrow_mut().push(duration);
+        }
+    }
+
+    #[cfg(windows)]
+    fn error(code: HRESULT) -> anyhow::Error {
+        windows::core::Error::from_hresult(code).into()
+    }
+
+    // Stands in for a transient HNS error where there is no Windows.
+    #[derive(Debug)]
+    struct Transient;
+
+    impl std::fmt::Display for Transient {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str("transient")
+        }
+    }
+
+    impl std::error::Error for Transient {}
+
+    fn transient(error: &anyhow::Error) -> bool {
+        error.chain().any(|e| e.is::<Transient>())
+    }
+
+    fn without_jitter() -> RetryPolicy {
+        RetryPolicy {
+            jitter: 0.0,
+            deadline: None,
+            ..Default::default()
+        }
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn backoff_grows_up_to_the_maximum() {
+        let policy = without_jitter();
+        assert_eq!(policy.backoff(1), ms(100));
+        assert_eq!(policy.backoff(2), ms(200));
+        assert_eq!(policy.backoff(3), ms(400));
+        assert_eq!(policy.backoff(7), Duration::from_secs(5));
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn jitter_shortens_the_backoff_by_at_most_its_fraction() {
+        let policy = RetryPolicy::default();
+        for _ in 0..100 {
+            let delay = policy.delay(1);
+            assert!(delay >= ms(80) && delay <= ms(100), "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn retries_transient_errors_until_they_pass() {
+        let clock = FakeClock::new();
+        let mut attempts = 0;
+        let result = without_jitter().retry_when(&clock, "test", transient, || {
+            attempts += 1;
+            match attempts {
+                1..=3 => Err(Transient.into()),
+                _ => Ok(attempts),
+            }
+        });
+
+        assert_eq!(result.unwrap(), 4);
+        assert_eq!(*clock.sleeps.borrow(), vec![ms(100), ms(200), ms(400)]);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let clock = FakeClock::new();
+        let mut attempts = 0;
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            ..without_jitter()
+        };
+        let result: Result<()> = policy.retry_when(&clock, "test", transient, || {
+            attempts += 1;
+            Err(Transient.into())
+        });
+
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn stops_before_the_deadline() {
+        let clock = FakeClock::new();
+        let mut attempts = 0;
+        let policy = RetryPolicy {
+            deadline: Some(ms(250)),
+            ..without_jitter()
+        };
+        let result: Result<()> = policy.retry_when(&clock, "test", transient, || {
+            attempts += 1;
+            Err(Transient.into())
+        });
+
+        // the third attempt would start at 300ms
+        let error = result.unwrap_err();
+        assert!(error.to_string().contains("did not succeed within"));
+        assert!(transient(&error));
+        assert_eq!(attempts, 2);
+        assert_eq!(*clock.sleeps.borrow(), vec![ms(100)]);
+    }
+
+    #[test]
+    fn does_not_retry_permanent_errors() {
+        let clock = FakeClock::new();
+        let mut attempts = 0;
+        let result: Result<()> = without_jitter().retry_when(&clock, "test", transient, || {
+            attempts += 1;
+            Err(anyhow::anyhow!("permanent"))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+        assert!(clock.sleeps.borrow().is_empty());
+    }
+
+    #[test]
+    fn reads_give_up_within_seconds() {
+        let clock = FakeClock::new();
+        let start = clock.now();
+        let result: Result<()> =
+            RetryPolicy::reads().retry_when(&clock, "test", transient, || Err(Transient.into()));
+
+        assert!(result.is_err());
+        assert!(clock.now() - start <= Duration::from_secs(5));
+        assert!(clock.sleeps.borrow().len() < 5);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn retry_uses_the_hresult_classification() {
+        let clock = FakeClock::new();
+        let mut attempts = 0;
+        let result = without_jitter().retry_with_clock(&clock, "test", || {
+            attempts += 1;
+            match attempts {
+                1 => Err(error(RPC_S_SERVER_UNAVAILABLE)),
+                2 => Err(error(E_FAIL)),
+                _ => Ok(()),
+            }
+        });
+
+        assert!(result.is_err());
+        assert_eq!(attempts, 2);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn transient_errors_are_seen_through_context() {
+        let wrapped = error(HCN_E_MANAGER_STOPPED)
+            .context("failed to open network")
+            .context("failed to create endpoint");
+        assert!(is_transient(&wrapped));
+        assert!(!is_transient(
+            &error(E_FAIL).context("failed to open network")
+        ));
+        assert!(!is_transient(&anyhow::anyhow!("manager stopped")));
+    }
+}
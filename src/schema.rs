@@ -1,9 +1,18 @@
 // see https://learn.microsoft.com/en-us/virtualization/api/hcn/hns_schema
 
+use crate::prefix::{IpFamily, IpPrefix};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
+use std::collections::BTreeSet;
+use std::net::IpAddr;
 
-#[derive(Default, Debug, Deserialize, Serialize)]
+/// Fields HNS returned that this schema does not model. Every struct keeps them
+/// and writes them back out, so a query, modify and write back cycle does not
+/// drop settings added by newer HNS builds.
+pub type ExtraFields = serde_json::Map<String, serde_json::Value>;
+
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct HostComputeNetwork {
     #[serde(rename = "ID", default, skip_serializing_if = "String::is_empty")]
@@ -26,23 +35,30 @@ pub struct HostComputeNetwork {
     pub health: Option<Health>,
     #[serde(default)]
     pub schema_version: Version,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "PascalCase")]
-
 pub struct Version {
     pub major: u32,
     pub minor: u32,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
 }
 
 impl Default for Version {
     fn default() -> Self {
-        Self { major: 2, minor: 2 }
+        Self {
+            major: 2,
+            minor: 2,
+            extra_fields: ExtraFields::new(),
+        }
     }
 }
 
-#[derive(Default, Serialize, Deserialize, Debug)]
+#[derive(Default, Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "PascalCase")]
 pub struct Health {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -50,9 +66,12 @@ pub struct Health {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub extra: Option<ExtraParams>,
+
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
 }
 
-#[derive(Default, Serialize, Deserialize, Debug)]
+#[derive(Default, Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "PascalCase")]
 pub struct ExtraParams {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -72,9 +91,12 @@ pub struct ExtraParams {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub virtual_machine: Option<String>,
+
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
 }
 
-#[derive(Default, Serialize, Deserialize, Debug)]
+#[derive(Default, Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "PascalCase")]
 pub struct Dns {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -88,11 +110,14 @@ pub struct Dns {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub options: Option<Vec<String>>,
+
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
 }
 
 pub type NetworkFlags = u32;
 
-#[derive(Debug, PartialEq, Deserialize, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(rename_all = "PascalCase")]
 pub enum NetworkType {
     NAT,
@@ -103,23 +128,161 @@ pub enum NetworkType {
     Private,
     Overlay,
 }
-#[derive(Debug, PartialEq, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct NetworkPolicy {
     #[serde(rename = "Type")]
     pub network_type: NetworkPolicyType,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub settings: Option<serde_json::Value>,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
+}
+
+impl NetworkPolicy {
+    pub fn new<T: Serialize>(
+        policy_type: NetworkPolicyType,
+        settings: &T,
+    ) -> serde_json::Result<Self> {
+        Ok(Self {
+            network_type: policy_type,
+            settings: Some(serde_json::to_value(settings)?),
+            extra_fields: ExtraFields::new(),
+        })
+    }
+
+    /// Deserialize the policy settings into one of the typed `*PolicySetting` structs.
+    pub fn settings_as<T: DeserializeOwned>(&self) -> serde_json::Result<T> {
+        serde_json::from_value(self.settings.clone().unwrap_or_default())
+    }
+}
+
+impl HostComputeNetwork {
+    /// The first policy of the given type.
+    pub fn policy(&self, policy_type: NetworkPolicyType) -> Option<&NetworkPolicy> {
+        self.policies.iter().find(|p| p.network_type == policy_type)
+    }
+
+    /// The prefixes of all subnets, skipping any that do not parse.
+    pub fn subnet_prefixes(&self) -> impl Iterator<Item = IpPrefix> + '_ {
+        self.ipams
+            .iter()
+            .flat_map(|i| i.subnets.iter())
+            .filter_map(|s| s.ip_address_prefix.as_ref()?.parse().ok())
+    }
+
+    /// The address families the network has subnets for.
+    pub fn families(&self) -> BTreeSet<IpFamily> {
+        self.subnet_prefixes().map(|p| p.family()).collect()
+    }
+
+    pub fn is_dual_stack(&self) -> bool {
+        self.families().len() == 2
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct NetAdapterNamePolicySetting {
+    pub network_adapter_name: String,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct VxlanPortPolicySetting {
+    pub port: u16,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ProviderAddressPolicySetting {
+    pub provider_address: String,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct DrMacAddressPolicySetting {
+    pub address: String,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
+}
+
+/// Routes traffic for a subnet on another overlay node through that node's
+/// provider address.
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct RemoteSubnetRoutePolicySetting {
+    pub destination_prefix: String,
+    pub isolation_id: u32,
+    pub provider_address: String,
+    pub distributed_router_mac_address: String,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
+}
+
+/// Settings of a `VSID` subnet policy, the VXLAN network identifier of an
+/// overlay subnet.
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct VsidPolicySetting {
+    pub isolation_id: u32,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
+}
+
+/// Settings of a `VLAN` subnet policy, the VLAN an L2Bridge subnet's traffic
+/// is tagged with.
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct VlanPolicySetting {
+    pub isolation_id: u32,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum SetPolicyType {
+    /// A set of addresses and prefixes.
+    #[serde(rename = "IPSET")]
+    IpSet,
+    /// A set of other sets, by id.
+    #[serde(rename = "NESTEDIPSET")]
+    NestedIpSet,
+}
+
+/// Settings of a `SetPolicy` network policy, a named set that network ACLs
+/// can match on. Values is a comma separated list of members.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SetPolicySetting {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    #[serde(rename = "Type")]
+    pub policy_type: SetPolicyType,
+    #[serde(default)]
+    pub values: String,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "PascalCase")]
 pub struct Ipam {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub r#type: Option<String>,
 
-    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub subnets: Vec<Subnet>,
+
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
 }
 
 impl Default for Ipam {
@@ -127,21 +290,37 @@ impl Default for Ipam {
         Self {
             r#type: Some("Static".to_string()),
             subnets: vec![Subnet::default()],
+            extra_fields: ExtraFields::new(),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "PascalCase")]
 pub struct Subnet {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ip_address_prefix: Option<String>,
 
-    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub policies: Vec<serde_json::Value>,
 
-    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub routes: Vec<Route>,
+
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
+}
+
+impl Subnet {
+    /// A subnet with a default route through `gateway`, for either family.
+    pub fn new(prefix: &IpPrefix, gateway: &IpAddr) -> Self {
+        Self {
+            ip_address_prefix: Some(prefix.to_string()),
+            routes: vec![Route::default_via(gateway)],
+            policies: vec![],
+            extra_fields: ExtraFields::new(),
+        }
+    }
 }
 
 impl Default for Subnet {
@@ -154,11 +333,12 @@ impl Default for Subnet {
                 ..Default::default()
             }],
             policies: vec![],
+            extra_fields: ExtraFields::new(),
         }
     }
 }
 
-#[derive(Default, Serialize, Deserialize, Debug)]
+#[derive(Default, Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "PascalCase")]
 pub struct Route {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -169,9 +349,31 @@ pub struct Route {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub metric: Option<u16>,
+
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
+}
+
+impl Route {
+    /// The default route of the gateway's family, `0.0.0.0/0` or `::/0`.
+    pub fn default_via(gateway: &IpAddr) -> Self {
+        Self {
+            next_hop: Some(gateway.to_string()),
+            destination_prefix: Some(IpPrefix::default_route(IpFamily::of(gateway)).to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// True for a route to `0.0.0.0/0` or `::/0`.
+    pub fn is_default(&self) -> bool {
+        self.destination_prefix
+            .as_ref()
+            .and_then(|d| d.parse::<IpPrefix>().ok())
+            .is_some_and(|d| d.prefix_len() == 0)
+    }
 }
 
-#[derive(Debug, PartialEq, Deserialize, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
 pub enum NetworkPolicyType {
     SourceMacAddress,
     NetAdapterName,
@@ -189,27 +391,37 @@ pub enum NetworkPolicyType {
     NetworkACL,
 }
 
-#[derive(Default, Debug, PartialEq, Deserialize, Serialize)]
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct MacRange {
     pub start_mac_address: Option<String>,
     pub end_mac_address: Option<String>,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
 }
 
-#[derive(Default, Debug, PartialEq, Deserialize, Serialize)]
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct MacPool {
     pub ranges: Option<Vec<MacRange>>,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
 }
 
-#[derive(Default, Debug, Deserialize, Serialize)]
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct HostComputeNamespace {
     #[serde(rename = "ID", default, skip_serializing_if = "String::is_empty")]
     pub id: String,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub namespace_id: Option<u32>,
-    #[serde(default, skip_serializing_if = "Option::is_none")]
+    // the field is "Type" in HNS and hcsshim, "NamespaceType" is still read
+    #[serde(
+        rename = "Type",
+        alias = "NamespaceType",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
     pub namespace_type: Option<NamespaceType>,
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub resources: Vec<NamespaceResource>,
@@ -217,9 +429,35 @@ pub struct HostComputeNamespace {
     pub create_with_compartment: Option<bool>,
     #[serde(default)]
     pub schema_version: Version,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
+impl HostComputeNamespace {
+    /// IDs of the endpoints attached to the namespace.
+    pub fn endpoints(&self) -> Vec<&str> {
+        self.resources
+            .iter()
+            .filter_map(|r| match r {
+                NamespaceResource::Endpoint(e) => Some(e.id.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// IDs of the containers attached to the namespace.
+    pub fn containers(&self) -> Vec<&str> {
+        self.resources
+            .iter()
+            .filter_map(|r| match r {
+                NamespaceResource::Container(c) => Some(c.id.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
 #[serde(rename_all = "PascalCase")]
 pub enum NamespaceType {
     Host,
@@ -228,22 +466,165 @@ pub enum NamespaceType {
     GuestDefault,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// A container or endpoint attached to a namespace. Resources of a type this
+/// schema does not know are kept whole in `Other`, so they survive a
+/// read-modify-write of the namespace.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "serde_json::Value", into = "serde_json::Value")]
+pub enum NamespaceResource {
+    Container(NamespaceResourceContainer),
+    Endpoint(NamespaceResourceEndpoint),
+    Other(serde_json::Value),
+}
+
+impl NamespaceResource {
+    /// The resource type, or `None` for a resource kept in `Other`.
+    pub fn resource_type(&self) -> Option<NamespaceResourceType> {
+        match self {
+            NamespaceResource::Container(_) => Some(NamespaceResourceType::Container),
+            NamespaceResource::Endpoint(_) => Some(NamespaceResourceType::Endpoint),
+            NamespaceResource::Other(_) => None,
+        }
+    }
+}
+
+// The wire form of the known resources.
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "Type", content = "Data")]
+enum KnownNamespaceResource {
+    Container(NamespaceResourceContainer),
+    Endpoint(NamespaceResourceEndpoint),
+}
+
+impl TryFrom<serde_json::Value> for NamespaceResource {
+    type Error = serde_json::Error;
+
+    fn try_from(value: serde_json::Value) -> Result<Self, Self::Error> {
+        let known = matches!(
+            value.get("Type").and_then(|t| t.as_str()),
+            Some("Container" | "Endpoint")
+        );
+        if !known {
+            return Ok(NamespaceResource::Other(value));
+        }
+
+        Ok(match serde_json::from_value(value)? {
+            KnownNamespaceResource::Container(c) => NamespaceResource::Container(c),
+            KnownNamespaceResource::Endpoint(e) => NamespaceResource::Endpoint(e),
+        })
+    }
+}
+
+impl From<NamespaceResource> for serde_json::Value {
+    fn from(resource: NamespaceResource) -> Self {
+        let known = match resource {
+            NamespaceResource::Container(c) => KnownNamespaceResource::Container(c),
+            NamespaceResource::Endpoint(e) => KnownNamespaceResource::Endpoint(e),
+            NamespaceResource::Other(value) => return value,
+        };
+        serde_json::to_value(known).expect("namespace resources serialize to JSON")
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "PascalCase")]
-pub struct NamespaceResource {
-    #[serde(default, skip_serializing_if = "Option::is_none")]
-    pub resource_type: Option<NamespaceResourceType>,
-    #[serde(default, skip_serializing_if = "Option::is_none")]
-    pub data: Option<serde_json::Value>,
+pub struct NamespaceResourceContainer {
+    #[serde(rename = "ID", alias = "Id")]
+    pub id: String,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct NamespaceResourceEndpoint {
+    #[serde(rename = "ID", alias = "Id")]
+    pub id: String,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
 pub enum NamespaceResourceType {
     Container,
     Endpoint,
 }
 
-#[derive(Debug, Default, Deserialize_repr, Serialize_repr, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+pub enum RequestType {
+    Add,
+    Remove,
+    Update,
+    Refresh,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ModifyNamespaceSettingRequest {
+    pub resource_type: NamespaceResourceType,
+    pub request_type: RequestType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub settings: Option<serde_json::Value>,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+pub enum NetworkResourceType {
+    DNS,
+    Extension,
+    Policy,
+    Subnet,
+    IPSubnet,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ModifyNetworkSettingRequest {
+    pub resource_type: NetworkResourceType,
+    pub request_type: RequestType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub settings: Option<serde_json::Value>,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
+}
+
+/// Settings of a `Policy` modify request on a network.
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct PolicyNetworkRequest {
+    pub policies: Vec<NetworkPolicy>,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+pub enum EndpointResourceType {
+    Port,
+    Policy,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ModifyEndpointSettingRequest {
+    pub resource_type: EndpointResourceType,
+    pub request_type: RequestType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub settings: Option<serde_json::Value>,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
+}
+
+/// Settings of a `Policy` modify request on an endpoint.
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct PolicyEndpointRequest {
+    pub policies: Vec<EndpointPolicy>,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
+}
+
+#[derive(Debug, Clone, Default, Deserialize_repr, Serialize_repr, PartialEq, Eq)]
 #[repr(u32)]
 pub enum HostComputeQueryFlags {
     #[default]
@@ -251,7 +632,7 @@ pub enum HostComputeQueryFlags {
     Detailed = 1,
 }
 
-#[derive(Default, Debug, Deserialize, Serialize)]
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct HostComputeQuery {
     #[serde(default)]
@@ -260,4 +641,437 @@ pub struct HostComputeQuery {
     pub flags: HostComputeQueryFlags,
     #[serde(default, skip_serializing_if = "String::is_empty")]
     pub filter: String,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct HostComputeEndpoint {
+    #[serde(rename = "ID", default, skip_serializing_if = "String::is_empty")]
+    pub id: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub host_compute_network: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub host_compute_namespace: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub policies: Vec<EndpointPolicy>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ip_configurations: Vec<IpConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dns: Option<Dns>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<Route>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub mac_address: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flags: Option<EndpointFlags>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health: Option<Health>,
+    #[serde(default)]
+    pub schema_version: Version,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
+}
+
+impl HostComputeEndpoint {
+    /// Remote endpoints represent containers on other hosts (overlay networks)
+    /// and are never attached to a local namespace.
+    pub fn is_remote(&self) -> bool {
+        self.flags.unwrap_or_default() & ENDPOINT_FLAGS_REMOTE_ENDPOINT != 0
+    }
+
+    /// The endpoint's address of the given family, if it has one.
+    pub fn ip_address(&self, family: IpFamily) -> Option<IpAddr> {
+        self.ip_configurations
+            .iter()
+            .filter_map(|c| c.ip_address.parse().ok())
+            .find(|ip| IpFamily::of(ip) == family)
+    }
+}
+
+pub type EndpointFlags = u32;
+
+pub const ENDPOINT_FLAGS_NONE: EndpointFlags = 0;
+pub const ENDPOINT_FLAGS_REMOTE_ENDPOINT: EndpointFlags = 1;
+pub const ENDPOINT_FLAGS_DISABLE_ICC: EndpointFlags = 2;
+pub const ENDPOINT_FLAGS_ENABLE_LOW_INTERFACE_METRIC: EndpointFlags = 4;
+pub const ENDPOINT_FLAGS_OVERRIDE_DNS_SERVER_ORDER: EndpointFlags = 8;
+pub const ENDPOINT_FLAGS_ENABLE_DHCP: EndpointFlags = 16;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct EndpointPolicy {
+    #[serde(rename = "Type")]
+    pub policy_type: EndpointPolicyType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub settings: Option<serde_json::Value>,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
+}
+
+impl EndpointPolicy {
+    pub fn new<T: Serialize>(
+        policy_type: EndpointPolicyType,
+        settings: &T,
+    ) -> serde_json::Result<Self> {
+        Ok(Self {
+            policy_type,
+            settings: Some(serde_json::to_value(settings)?),
+            extra_fields: ExtraFields::new(),
+        })
+    }
+
+    /// Deserialize the policy settings into one of the typed `*PolicySetting` structs.
+    pub fn settings_as<T: DeserializeOwned>(&self) -> serde_json::Result<T> {
+        serde_json::from_value(self.settings.clone().unwrap_or_default())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum EndpointPolicyType {
+    PortMapping,
+    ACL,
+    QOS,
+    L2Driver,
+    OutBoundNAT,
+    SDNRoute,
+    L4Proxy,
+    L4WFPPROXY,
+    PortName,
+    EncapOverhead,
+    #[serde(rename = "Iov")]
+    IOV,
+    ProviderAddress,
+    InterfaceConstraint,
+    TierAcl,
+}
+
+pub type NatFlags = u32;
+
+pub const NAT_FLAGS_NONE: NatFlags = 0;
+pub const NAT_FLAGS_LOCAL_ROUTED_VIP: NatFlags = 1;
+pub const NAT_FLAGS_IPV6: NatFlags = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum AclAction {
+    Allow,
+    Block,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum AclDirection {
+    In,
+    Out,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum AclRuleType {
+    /// Enforced in the host's firewall.
+    Host,
+    /// Enforced in the virtual switch.
+    Switch,
+}
+
+/// An ACL rule on an endpoint. Addresses and ports are comma separated lists,
+/// empty for any.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct AclPolicySetting {
+    /// An IP protocol number, any when empty.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub protocols: String,
+    pub action: AclAction,
+    pub direction: AclDirection,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub local_addresses: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub remote_addresses: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub local_ports: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub remote_ports: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rule_type: Option<AclRuleType>,
+    /// Rules with lower values are evaluated first.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u16>,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
+}
+
+/// Publishes an endpoint port on the host, as `docker run -p` does.
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct PortMappingPolicySetting {
+    pub protocol: u32,
+    pub internal_port: u16,
+    pub external_port: u16,
+    /// The host address to listen on, all addresses when empty.
+    #[serde(rename = "VIP", default, skip_serializing_if = "String::is_empty")]
+    pub vip: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flags: Option<NatFlags>,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
+}
+
+/// SNATs traffic leaving the endpoint to the host's address, except traffic
+/// to the exceptions.
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct OutboundNatPolicySetting {
+    /// The address to SNAT to, the host's when empty.
+    #[serde(
+        rename = "VirtualIP",
+        default,
+        skip_serializing_if = "String::is_empty"
+    )]
+    pub virtual_ip: String,
+    /// Prefixes and addresses traffic to which keeps the endpoint's address.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exceptions: Vec<String>,
+    /// Only SNAT traffic to these, all traffic when empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub destinations: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flags: Option<NatFlags>,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct IpConfig {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub ip_address: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix_length: Option<u8>,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
+}
+
+impl IpConfig {
+    pub fn new(ip_address: &IpAddr, prefix_length: u8) -> Self {
+        Self {
+            ip_address: ip_address.to_string(),
+            prefix_length: Some(prefix_length),
+            extra_fields: ExtraFields::new(),
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct HostComputeLoadBalancer {
+    #[serde(rename = "ID", default, skip_serializing_if = "String::is_empty")]
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub host_compute_endpoints: Vec<String>,
+    #[serde(
+        rename = "SourceVIP",
+        default,
+        skip_serializing_if = "String::is_empty"
+    )]
+    pub source_vip: String,
+    #[serde(
+        rename = "FrontendVIPs",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub frontend_vips: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub port_mappings: Vec<LoadBalancerPortMapping>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flags: Option<LoadBalancerFlags>,
+    #[serde(default)]
+    pub schema_version: Version,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
+}
+
+pub type LoadBalancerFlags = u32;
+
+pub const LOAD_BALANCER_FLAGS_NONE: LoadBalancerFlags = 0;
+/// Direct Server Return: backends reply to clients directly instead of back
+/// through the load balancer.
+pub const LOAD_BALANCER_FLAGS_DSR: LoadBalancerFlags = 1;
+/// The frontend and source VIPs are IPv6 addresses.
+pub const LOAD_BALANCER_FLAGS_IPV6: LoadBalancerFlags = 2;
+
+impl HostComputeLoadBalancer {
+    pub fn is_dsr(&self) -> bool {
+        self.flags.unwrap_or_default() & LOAD_BALANCER_FLAGS_DSR != 0
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        self.flags.unwrap_or_default() & LOAD_BALANCER_FLAGS_IPV6 != 0
+    }
+}
+
+pub type LoadBalancerPortMappingFlags = u32;
+
+pub const LOAD_BALANCER_PORT_MAPPING_FLAGS_NONE: LoadBalancerPortMappingFlags = 0;
+/// Internal load balancer, only reachable from endpoints on the host.
+pub const LOAD_BALANCER_PORT_MAPPING_FLAGS_ILB: LoadBalancerPortMappingFlags = 1;
+/// The VIP is routed locally and answered on this host.
+pub const LOAD_BALANCER_PORT_MAPPING_FLAGS_LOCAL_ROUTED_VIP: LoadBalancerPortMappingFlags = 2;
+pub const LOAD_BALANCER_PORT_MAPPING_FLAGS_USE_MUX: LoadBalancerPortMappingFlags = 4;
+/// Keep the backend's address as the destination instead of the VIP.
+pub const LOAD_BALANCER_PORT_MAPPING_FLAGS_PRESERVE_DIP: LoadBalancerPortMappingFlags = 8;
+/// The VIP is reachable from outside the cluster.
+pub const LOAD_BALANCER_PORT_MAPPING_FLAGS_VIP_EXTERNAL_IP: LoadBalancerPortMappingFlags = 16;
+
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct LoadBalancerPortMapping {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub internal_port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external_port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub distribution_type: Option<LoadBalancerDistribution>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flags: Option<LoadBalancerPortMappingFlags>,
+    #[serde(flatten)]
+    pub extra_fields: ExtraFields,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize_repr, Serialize_repr, PartialEq, Eq)]
+#[repr(u32)]
+pub enum LoadBalancerDistribution {
+    #[default]
+    None = 0,
+    SourceIPProtocol = 1,
+    SourceIP = 2,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    // The payloads are synthetic, see tests/payloads/README.md. They carry
+    // fields the schema does not model, which must come back out unchanged
+    // when the object is re-serialized.
+    fn round_trip<T: DeserializeOwned + Serialize>(payload: &str) -> T {
+        let original: Value = serde_json::from_str(payload).unwrap();
+        let parsed: T = serde_json::from_str(payload).unwrap();
+        let written = serde_json::to_value(&parsed).unwrap();
+
+        assert_eq!(without_empty_lists(original), written);
+        parsed
+    }
+
+    // The only normalization applied to the original payload: object members
+    // whose value is an empty array are dropped, at any depth, because the
+    // schema skips empty lists when serializing and HNS treats a missing list
+    // as empty. Arrays themselves keep all their elements, and nulls, empty
+    // objects and empty strings are compared as they are.
+    fn without_empty_lists(value: Value) -> Value {
+        match value {
+            Value::Object(map) => Value::Object(
+                map.into_iter()
+                    .filter(|(_, v)| !matches!(v, Value::Array(a) if a.is_empty()))
+                    .map(|(k, v)| (k, without_empty_lists(v)))
+                    .collect(),
+            ),
+            Value::Array(values) => {
+                Value::Array(values.into_iter().map(without_empty_lists).collect())
+            }
+            value => value,
+        }
+    }
+
+    #[test]
+    fn network_round_trip() {
+        let network: HostComputeNetwork =
+            round_trip(include_str!("../tests/payloads/network.json"));
+        assert!(!network.extra_fields.is_empty());
+    }
+
+    #[test]
+    fn endpoint_round_trip() {
+        let endpoint: HostComputeEndpoint =
+            round_trip(include_str!("../tests/payloads/endpoint.json"));
+        assert!(!endpoint.extra_fields.is_empty());
+    }
+
+    #[test]
+    fn namespace_round_trip() {
+        let namespace: HostComputeNamespace =
+            round_trip(include_str!("../tests/payloads/namespace.json"));
+        assert!(!namespace.extra_fields.is_empty());
+        assert_eq!(
+            namespace.endpoints(),
+            vec!["7E9F1A2B-3C4D-4E5F-8A9B-0C1D2E3F4A5B"]
+        );
+        assert_eq!(
+            namespace.containers(),
+            vec!["3B2A1F0E-9D8C-4B7A-6F5E-4D3C2B1A0F9E"]
+        );
+        match &namespace.resources[2] {
+            NamespaceResource::Other(value) => assert_eq!(value["Type"], "Device"),
+            other => panic!("unexpected resource {:?}", other),
+        }
+        assert_eq!(namespace.resources[2].resource_type(), None);
+    }
+
+    #[test]
+    fn load_balancer_round_trip() {
+        let load_balancer: HostComputeLoadBalancer =
+            round_trip(include_str!("../tests/payloads/load_balancer.json"));
+        assert!(!load_balancer.extra_fields.is_empty());
+    }
+
+    #[test]
+    fn malformed_known_namespace_resources_are_errors() {
+        let invalid = serde_json::json!({ "Type": "Endpoint", "Data": { "Name": "no id" } });
+        assert!(serde_json::from_value::<NamespaceResource>(invalid).is_err());
+        // without a Type there is nothing to dispatch on, keep it as it is
+        let untyped = serde_json::json!({ "Data": {} });
+        assert_eq!(
+            serde_json::from_value::<NamespaceResource>(untyped.clone()).unwrap(),
+            NamespaceResource::Other(untyped)
+        );
+    }
+
+    #[test]
+    fn namespace_resources_accept_either_id_casing() {
+        let namespace: HostComputeNamespace = serde_json::from_value(serde_json::json!({
+            "NamespaceType": "Guest",
+            "Resources": [
+                { "Type": "Container", "Data": { "Id": "c1" } },
+                { "Type": "Endpoint", "Data": { "ID": "e1" } },
+            ],
+        }))
+        .unwrap();
+        assert_eq!(namespace.namespace_type, Some(NamespaceType::Guest));
+        assert_eq!(namespace.containers(), vec!["c1"]);
+        assert_eq!(namespace.endpoints(), vec!["e1"]);
+    }
+
+    #[test]
+    fn only_empty_lists_are_normalized() {
+        let value = serde_json::json!({
+            "Empty": [],
+            "Null": null,
+            "Object": {},
+            "String": "",
+            "Nested": [{ "Empty": [], "Kept": [[]] }],
+        });
+        assert_eq!(
+            without_empty_lists(value),
+            serde_json::json!({
+                "Null": null,
+                "Object": {},
+                "String": "",
+                "Nested": [{ "Kept": [[]] }],
+            })
+        );
+    }
 }
@@ -0,0 +1,936 @@
+//! Checks run before handing objects to HNS.
+//!
+//! HNS answers most configuration mistakes with a generic "The parameter is
+//! incorrect". The `validate` methods catch the common ones up front and say
+//! what is actually wrong.
+
+use crate::mac::{overlapping_ranges, parse_range, MacAddress};
+use crate::prefix::{IpFamily, IpPrefix};
+use crate::schema::*;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    MissingNetworkType,
+    InvalidPrefix {
+        prefix: String,
+    },
+    InvalidAddress {
+        field: &'static str,
+        address: String,
+    },
+    OverlappingSubnets {
+        first: String,
+        second: String,
+    },
+    NextHopOutsideSubnet {
+        subnet: String,
+        next_hop: String,
+    },
+    RouteFamilyMismatch {
+        destination: String,
+        next_hop: String,
+    },
+    /// A dual-stack network has subnets of this family but no default route
+    /// for it.
+    MissingGateway {
+        family: IpFamily,
+    },
+    /// The network type needs a policy that is not configured.
+    MissingPolicy {
+        network_type: NetworkType,
+        policy: NetworkPolicyType,
+    },
+    /// The policy only applies to other network types.
+    UnsupportedPolicy {
+        network_type: NetworkType,
+        policy: NetworkPolicyType,
+    },
+    InvalidPolicySettings {
+        policy: NetworkPolicyType,
+        reason: String,
+    },
+    /// Overlay subnets need a VSID policy carrying the VXLAN network identifier.
+    MissingIsolationId {
+        subnet: String,
+    },
+    InvalidMacRange {
+        index: usize,
+        reason: String,
+    },
+    OverlappingMacRanges {
+        first: usize,
+        second: usize,
+    },
+    InvalidPrefixLength {
+        address: String,
+        prefix_length: u8,
+    },
+    /// HNS takes at most one IP configuration per family.
+    DuplicateAddressFamily {
+        family: IpFamily,
+    },
+    /// A VIP's family does not match the load balancer's IPv6 flag.
+    VipFamilyMismatch {
+        field: &'static str,
+        address: String,
+    },
+    MissingEndpoints,
+    /// DSR load balancers need the source VIP the backends reply from.
+    MissingSourceVip,
+    InvalidPortMapping {
+        index: usize,
+        reason: String,
+    },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::MissingNetworkType => write!(f, "network type is not set"),
+            ValidationError::InvalidPrefix { prefix } => write!(f, "invalid prefix {}", prefix),
+            ValidationError::InvalidAddress { field, address } => {
+                write!(f, "invalid {} address {}", field, address)
+            }
+            ValidationError::OverlappingSubnets { first, second } => {
+                write!(f, "subnets {} and {} overlap", first, second)
+            }
+            ValidationError::NextHopOutsideSubnet { subnet, next_hop } => {
+                write!(f, "next hop {} is outside subnet {}", next_hop, subnet)
+            }
+            ValidationError::RouteFamilyMismatch {
+                destination,
+                next_hop,
+            } => write!(
+                f,
+                "next hop {} is not in the family of {}",
+                next_hop, destination
+            ),
+            ValidationError::MissingGateway { family } => {
+                write!(f, "dual-stack network has no {} default route", family)
+            }
+            ValidationError::MissingPolicy {
+                network_type,
+                policy,
+            } => write!(
+                f,
+                "{:?} networks require a {:?} policy",
+                network_type, policy
+            ),
+            ValidationError::UnsupportedPolicy {
+                network_type,
+                policy,
+            } => write!(
+                f,
+                "{:?} policy is not supported on {:?} networks",
+                policy, network_type
+            ),
+            ValidationError::InvalidPolicySettings { policy, reason } => {
+                write!(f, "invalid {:?} policy: {}", policy, reason)
+            }
+            ValidationError::MissingIsolationId { subnet } => {
+                write!(f, "overlay subnet {} has no VSID policy", subnet)
+            }
+            ValidationError::InvalidMacRange { index, reason } => {
+                write!(f, "invalid MAC range {}: {}", index, reason)
+            }
+            ValidationError::OverlappingMacRanges { first, second } => {
+                write!(f, "MAC ranges {} and {} overlap", first, second)
+            }
+            ValidationError::InvalidPrefixLength {
+                address,
+                prefix_length,
+            } => write!(f, "invalid prefix length {} for {}", prefix_length, address),
+            ValidationError::DuplicateAddressFamily { family } => {
+                write!(f, "more than one {} IP configuration", family)
+            }
+            ValidationError::VipFamilyMismatch { field, address } => write!(
+                f,
+                "{} {} does not match the load balancer's IPv6 flag",
+                field, address
+            ),
+            ValidationError::MissingEndpoints => write!(f, "no backend endpoints"),
+            ValidationError::MissingSourceVip => write!(f, "DSR requires a source VIP"),
+            ValidationError::InvalidPortMapping { index, reason } => {
+                write!(f, "invalid port mapping {}: {}", index, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Every problem found by a `validate` call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationErrors(pub Vec<ValidationError>);
+
+impl ValidationErrors {
+    fn into_result(self) -> Result<(), ValidationErrors> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let errors: Vec<String> = self.0.iter().map(|e| e.to_string()).collect();
+        write!(f, "{}", errors.join("; "))
+    }
+}
+
+impl std::error::Error for ValidationErrors {}
+
+impl HostComputeNetwork {
+    pub fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = vec![];
+
+        let Some(network_type) = self.network_type else {
+            errors.push(ValidationError::MissingNetworkType);
+            return ValidationErrors(errors).into_result();
+        };
+
+        validate_subnets(self, network_type, &mut errors);
+        validate_policies(self, network_type, &mut errors);
+        if let Some(mac_pool) = &self.mac_pool {
+            validate_mac_pool(mac_pool, &mut errors);
+        }
+
+        ValidationErrors(errors).into_result()
+    }
+}
+
+impl HostComputeEndpoint {
+    pub fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = vec![];
+
+        let mut families = BTreeSet::new();
+        for config in &self.ip_configurations {
+            match config.ip_address.parse::<IpAddr>() {
+                Ok(ip) => {
+                    if !families.insert(IpFamily::of(&ip)) {
+                        errors.push(ValidationError::DuplicateAddressFamily {
+                            family: IpFamily::of(&ip),
+                        });
+                    }
+                    if let Some(prefix_length) = config.prefix_length {
+                        if prefix_length > IpFamily::of(&ip).bits() {
+                            errors.push(ValidationError::InvalidPrefixLength {
+                                address: config.ip_address.clone(),
+                                prefix_length,
+                            });
+                        }
+                    }
+                }
+                Err(_) => errors.push(ValidationError::InvalidAddress {
+                    field: "IpAddress",
+                    address: config.ip_address.clone(),
+                }),
+            }
+        }
+
+        if !self.mac_address.is_empty() && self.mac_address.parse::<MacAddress>().is_err() {
+            errors.push(ValidationError::InvalidAddress {
+                field: "MacAddress",
+                address: self.mac_address.clone(),
+            });
+        }
+
+        for route in &self.routes {
+            validate_route(route, &mut errors);
+        }
+
+        ValidationErrors(errors).into_result()
+    }
+}
+
+impl HostComputeLoadBalancer {
+    pub fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = vec![];
+
+        if self.host_compute_endpoints.is_empty() {
+            errors.push(ValidationError::MissingEndpoints);
+        }
+
+        if self.is_dsr() && self.source_vip.is_empty() {
+            errors.push(ValidationError::MissingSourceVip);
+        }
+
+        let source_vip = Some(&self.source_vip).filter(|v| !v.is_empty());
+        let vips = source_vip
+            .map(|v| ("SourceVIP", v))
+            .into_iter()
+            .chain(self.frontend_vips.iter().map(|v| ("FrontendVIPs", v)));
+        for (field, vip) in vips {
+            match vip.parse::<IpAddr>() {
+                Ok(ip) => {
+                    if ip.is_ipv6() != self.is_ipv6() {
+                        errors.push(ValidationError::VipFamilyMismatch {
+                            field,
+                            address: vip.clone(),
+                        });
+                    }
+                }
+                Err(_) => errors.push(ValidationError::InvalidAddress {
+                    field,
+                    address: vip.clone(),
+                }),
+            }
+        }
+
+        for (index, mapping) in self.port_mappings.iter().enumerate() {
+            let reason = match mapping.protocol {
+                None => Some("protocol is not set".to_string()),
+                Some(6) | Some(17) if mapping.internal_port.unwrap_or_default() == 0 => {
+                    Some("internal port is not set".to_string())
+                }
+                Some(6) | Some(17) if mapping.external_port.unwrap_or_default() == 0 => {
+                    Some("external port is not set".to_string())
+                }
+                _ => None,
+            };
+            if let Some(reason) = reason {
+                errors.push(ValidationError::InvalidPortMapping { index, reason });
+            }
+        }
+
+        ValidationErrors(errors).into_result()
+    }
+}
+
+fn validate_subnets(
+    network: &HostComputeNetwork,
+    network_type: NetworkType,
+    errors: &mut Vec<ValidationError>,
+) {
+    let mut seen: Vec<IpPrefix> = vec![];
+    let mut gateways = BTreeSet::new();
+
+    for subnet in network.ipams.iter().flat_map(|i| i.subnets.iter()) {
+        let Some(prefix) = &subnet.ip_address_prefix else {
+            continue;
+        };
+        let Ok(prefix) = prefix.parse::<IpPrefix>() else {
+            errors.push(ValidationError::InvalidPrefix {
+                prefix: prefix.clone(),
+            });
+            continue;
+        };
+
+        if let Some(other) = seen.iter().find(|s| s.overlaps(&prefix)) {
+            errors.push(ValidationError::OverlappingSubnets {
+                first: other.to_string(),
+                second: prefix.to_string(),
+            });
+        }
+        seen.push(prefix);
+
+        for route in &subnet.routes {
+            validate_route(route, errors);
+            if route.is_default() && route.next_hop.is_some() {
+                gateways.insert(prefix.family());
+            }
+            if let Some(Ok(next_hop)) = route.next_hop.as_ref().map(|h| h.parse::<IpAddr>()) {
+                if !prefix.contains(&next_hop) {
+                    errors.push(ValidationError::NextHopOutsideSubnet {
+                        subnet: prefix.to_string(),
+                        next_hop: next_hop.to_string(),
+                    });
+                }
+            }
+        }
+
+        if network_type == NetworkType::Overlay && !has_isolation_id(subnet) {
+            errors.push(ValidationError::MissingIsolationId {
+                subnet: prefix.to_string(),
+            });
+        }
+    }
+
+    if network.is_dual_stack() {
+        for family in network.families().difference(&gateways) {
+            errors.push(ValidationError::MissingGateway { family: *family });
+        }
+    }
+}
+
+fn validate_route(route: &Route, errors: &mut Vec<ValidationError>) {
+    if let Some(next_hop) = &route.next_hop {
+        if next_hop.parse::<IpAddr>().is_err() {
+            errors.push(ValidationError::InvalidAddress {
+                field: "NextHop",
+                address: next_hop.clone(),
+            });
+        }
+    }
+    if let Some(destination) = &route.destination_prefix {
+        match destination.parse::<IpPrefix>() {
+            Ok(prefix) => {
+                if let Some(Ok(next_hop)) = route.next_hop.as_ref().map(|h| h.parse::<IpAddr>()) {
+                    if IpFamily::of(&next_hop) != prefix.family() {
+                        errors.push(ValidationError::RouteFamilyMismatch {
+                            destination: destination.clone(),
+                            next_hop: next_hop.to_string(),
+                        });
+                    }
+                }
+            }
+            Err(_) => errors.push(ValidationError::InvalidPrefix {
+                prefix: destination.clone(),
+            }),
+        }
+    }
+}
+
+fn has_isolation_id(subnet: &Subnet) -> bool {
+    subnet.policies.iter().any(|p| {
+        p.get("Type").and_then(|t| t.as_str()) == Some("VSID")
+            && p.pointer("/Settings/IsolationId")
+                .and_then(|id| id.as_u64())
+                .is_some()
+    })
+}
+
+fn validate_policies(
+    network: &HostComputeNetwork,
+    network_type: NetworkType,
+    errors: &mut Vec<ValidationError>,
+) {
+    let overlay_only = [
+        NetworkPolicyType::VxlanPort,
+        NetworkPolicyType::ProviderAddress,
+        NetworkPolicyType::RemoteSubnetRoute,
+    ];
+    if network_type != NetworkType::Overlay {
+        for policy in overlay_only {
+            if network.policy(policy).is_some() {
+                errors.push(ValidationError::UnsupportedPolicy {
+                    network_type,
+                    policy,
+                });
+            }
+        }
+    }
+
+    // VXLAN needs the port and the address it is sent from, L2 networks the
+    // adapter they are bound to
+    let required: &[NetworkPolicyType] = match network_type {
+        NetworkType::Overlay => &[
+            NetworkPolicyType::VxlanPort,
+            NetworkPolicyType::ProviderAddress,
+        ],
+        NetworkType::L2Bridge | NetworkType::L2Tunnel => &[NetworkPolicyType::NetAdapterName],
+        _ => &[],
+    };
+    for policy in required {
+        if network.policy(*policy).is_none() {
+            errors.push(ValidationError::MissingPolicy {
+                network_type,
+                policy: *policy,
+            });
+        }
+    }
+
+    for policy in &network.policies {
+        if let Err(reason) = check_policy_settings(policy) {
+            errors.push(ValidationError::InvalidPolicySettings {
+                policy: policy.network_type,
+                reason,
+            });
+        }
+    }
+}
+
+fn check_policy_settings(policy: &NetworkPolicy) -> Result<(), String> {
+    match policy.network_type {
+        NetworkPolicyType::NetAdapterName => {
+            let settings: NetAdapterNamePolicySetting =
+                policy.settings_as().map_err(|e| e.to_string())?;
+            if settings.network_adapter_name.is_empty() {
+                return Err("adapter name is empty".to_string());
+            }
+        }
+        NetworkPolicyType::VxlanPort => {
+            let settings: VxlanPortPolicySetting =
+                policy.settings_as().map_err(|e| e.to_string())?;
+            if settings.port == 0 {
+                return Err("port must not be 0".to_string());
+            }
+        }
+        NetworkPolicyType::ProviderAddress => {
+            let settings: ProviderAddressPolicySetting =
+                policy.settings_as().map_err(|e| e.to_string())?;
+            if settings.provider_address.parse::<IpAddr>().is_err() {
+                return Err(format!(
+                    "invalid provider address {}",
+                    settings.provider_address
+                ));
+            }
+        }
+        NetworkPolicyType::RemoteSubnetRoute => {
+            let settings: RemoteSubnetRoutePolicySetting =
+                policy.settings_as().map_err(|e| e.to_string())?;
+            if settings.destination_prefix.parse::<IpPrefix>().is_err() {
+                return Err(format!(
+                    "invalid destination prefix {}",
+                    settings.destination_prefix
+                ));
+            }
+            if settings.provider_address.parse::<IpAddr>().is_err() {
+                return Err(format!(
+                    "invalid provider address {}",
+                    settings.provider_address
+                ));
+            }
+            if settings
+                .distributed_router_mac_address
+                .parse::<MacAddress>()
+                .is_err()
+            {
+                return Err(format!(
+                    "invalid MAC address {}",
+                    settings.distributed_router_mac_address
+                ));
+            }
+        }
+        NetworkPolicyType::DrMacAddress => {
+            let settings: DrMacAddressPolicySetting =
+                policy.settings_as().map_err(|e| e.to_string())?;
+            if settings.address.parse::<MacAddress>().is_err() {
+                return Err(format!("invalid MAC address {}", settings.address));
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn validate_mac_pool(pool: &MacPool, errors: &mut Vec<ValidationError>) {
+    let mut well_formed = true;
+    for (index, range) in pool.ranges.iter().flatten().enumerate() {
+        if let Err(e) = parse_range(range) {
+            well_formed = false;
+            errors.push(ValidationError::InvalidMacRange {
+                index,
+                reason: e.to_string(),
+            });
+        }
+    }
+
+    if well_formed {
+        if let Ok(overlaps) = overlapping_ranges(pool) {
+            errors.extend(
+                overlaps
+                    .into_iter()
+                    .map(|(first, second)| ValidationError::OverlappingMacRanges { first, second }),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::overlay::OverlayNetworkBuilder;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn nat(subnets: &[(&str, &str)]) -> HostComputeNetwork {
+        HostComputeNetwork {
+            network_type: Some(NetworkType::NAT),
+            ipams: vec![Ipam {
+                subnets: subnets
+                    .iter()
+                    .map(|(prefix, gateway)| Subnet::new(&prefix.parse().unwrap(), &ip(gateway)))
+                    .collect(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn overlay() -> HostComputeNetwork {
+        OverlayNetworkBuilder::new("overlay", 4096)
+            .provider_address(ip("192.168.1.10"))
+            .subnet("10.244.1.0/24".parse().unwrap(), ip("10.244.1.1"))
+            .build()
+            .unwrap()
+    }
+
+    fn policy<T: serde::Serialize>(policy_type: NetworkPolicyType, settings: &T) -> NetworkPolicy {
+        NetworkPolicy::new(policy_type, settings).unwrap()
+    }
+
+    fn errors<T>(result: Result<T, ValidationErrors>) -> Vec<ValidationError> {
+        match result {
+            Ok(_) => vec![],
+            Err(errors) => errors.0,
+        }
+    }
+
+    fn network_errors(network: &HostComputeNetwork) -> Vec<ValidationError> {
+        errors(network.validate())
+    }
+
+    #[test]
+    fn valid_networks() {
+        assert_eq!(
+            network_errors(&nat(&[("172.20.0.0/16", "172.20.0.1")])),
+            vec![]
+        );
+        assert_eq!(network_errors(&overlay()), vec![]);
+    }
+
+    #[test]
+    fn missing_network_type() {
+        let network = HostComputeNetwork::default();
+        assert_eq!(
+            network_errors(&network),
+            vec![ValidationError::MissingNetworkType]
+        );
+    }
+
+    #[test]
+    fn invalid_prefix() {
+        let mut network = nat(&[("172.20.0.0/16", "172.20.0.1")]);
+        network.ipams[0].subnets[0].ip_address_prefix = Some("172.20.0.0/33".to_string());
+        assert_eq!(
+            network_errors(&network),
+            vec![ValidationError::InvalidPrefix {
+                prefix: "172.20.0.0/33".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn invalid_address() {
+        let mut network = nat(&[("172.20.0.0/16", "172.20.0.1")]);
+        network.ipams[0].subnets[0].routes[0].next_hop = Some("172.20.0.300".to_string());
+        assert_eq!(
+            network_errors(&network),
+            vec![ValidationError::InvalidAddress {
+                field: "NextHop",
+                address: "172.20.0.300".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn overlapping_subnets() {
+        let network = nat(&[
+            ("172.20.0.0/16", "172.20.0.1"),
+            ("172.20.5.0/24", "172.20.5.1"),
+        ]);
+        assert_eq!(
+            network_errors(&network),
+            vec![ValidationError::OverlappingSubnets {
+                first: "172.20.0.0/16".to_string(),
+                second: "172.20.5.0/24".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn next_hop_outside_subnet() {
+        let network = nat(&[("172.20.0.0/16", "172.21.0.1")]);
+        assert_eq!(
+            network_errors(&network),
+            vec![ValidationError::NextHopOutsideSubnet {
+                subnet: "172.20.0.0/16".to_string(),
+                next_hop: "172.21.0.1".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn route_family_mismatch() {
+        let mut network = nat(&[("172.20.0.0/16", "172.20.0.1")]);
+        network.ipams[0].subnets[0].routes[0].destination_prefix = Some("::/0".to_string());
+        assert!(
+            network_errors(&network).contains(&ValidationError::RouteFamilyMismatch {
+                destination: "::/0".to_string(),
+                next_hop: "172.20.0.1".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn missing_gateway() {
+        let mut network = nat(&[
+            ("172.20.0.0/16", "172.20.0.1"),
+            ("fd00:20::/64", "fd00:20::1"),
+        ]);
+        network.ipams[0].subnets[1].routes.clear();
+        assert_eq!(
+            network_errors(&network),
+            vec![ValidationError::MissingGateway {
+                family: IpFamily::V6
+            }]
+        );
+    }
+
+    #[test]
+    fn overlay_without_vxlan_port_or_provider_address() {
+        let mut network = overlay();
+        network.policies.retain(|p| {
+            !matches!(
+                p.network_type,
+                NetworkPolicyType::VxlanPort | NetworkPolicyType::ProviderAddress
+            )
+        });
+        assert_eq!(
+            network_errors(&network),
+            vec![
+                ValidationError::MissingPolicy {
+                    network_type: NetworkType::Overlay,
+                    policy: NetworkPolicyType::VxlanPort
+                },
+                ValidationError::MissingPolicy {
+                    network_type: NetworkType::Overlay,
+                    policy: NetworkPolicyType::ProviderAddress
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn l2bridge_without_adapter() {
+        let mut network = nat(&[("10.0.0.0/24", "10.0.0.1")]);
+        network.network_type = Some(NetworkType::L2Bridge);
+        assert_eq!(
+            network_errors(&network),
+            vec![ValidationError::MissingPolicy {
+                network_type: NetworkType::L2Bridge,
+                policy: NetworkPolicyType::NetAdapterName
+            }]
+        );
+    }
+
+    #[test]
+    fn unsupported_policy() {
+        let mut network = nat(&[("172.20.0.0/16", "172.20.0.1")]);
+        network.policies.push(policy(
+            NetworkPolicyType::VxlanPort,
+            &VxlanPortPolicySetting {
+                port: 4789,
+                ..Default::default()
+            },
+        ));
+        assert_eq!(
+            network_errors(&network),
+            vec![ValidationError::UnsupportedPolicy {
+                network_type: NetworkType::NAT,
+                policy: NetworkPolicyType::VxlanPort
+            }]
+        );
+    }
+
+    #[test]
+    fn invalid_policy_settings() {
+        let mut network = overlay();
+        network.policies.push(policy(
+            NetworkPolicyType::DrMacAddress,
+            &DrMacAddressPolicySetting {
+                address: "not a mac".to_string(),
+                ..Default::default()
+            },
+        ));
+        assert_eq!(
+            network_errors(&network),
+            vec![ValidationError::InvalidPolicySettings {
+                policy: NetworkPolicyType::DrMacAddress,
+                reason: "invalid MAC address not a mac".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn missing_isolation_id() {
+        let mut network = overlay();
+        network.ipams[0].subnets[0].policies.clear();
+        assert_eq!(
+            network_errors(&network),
+            vec![ValidationError::MissingIsolationId {
+                subnet: "10.244.1.0/24".to_string()
+            }]
+        );
+    }
+
+    fn mac_range(start: &str, end: &str) -> MacRange {
+        MacRange {
+            start_mac_address: Some(start.to_string()),
+            end_mac_address: Some(end.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn invalid_mac_range() {
+        let mut network = nat(&[("172.20.0.0/16", "172.20.0.1")]);
+        network.mac_pool = Some(MacPool {
+            ranges: Some(vec![mac_range("00-15-5D-00-00-FF", "00-15-5D-00-00-00")]),
+            ..Default::default()
+        });
+        assert!(matches!(
+            network_errors(&network)[..],
+            [ValidationError::InvalidMacRange { index: 0, .. }]
+        ));
+    }
+
+    #[test]
+    fn overlapping_mac_ranges() {
+        let mut network = nat(&[("172.20.0.0/16", "172.20.0.1")]);
+        network.mac_pool = Some(MacPool {
+            ranges: Some(vec![
+                mac_range("00-15-5D-00-00-00", "00-15-5D-00-00-FF"),
+                mac_range("00-15-5D-00-01-00", "00-15-5D-00-01-FF"),
+                mac_range("00-15-5D-00-00-80", "00-15-5D-00-01-0F"),
+            ]),
+            ..Default::default()
+        });
+        assert_eq!(
+            network_errors(&network),
+            vec![
+                ValidationError::OverlappingMacRanges {
+                    first: 0,
+                    second: 2
+                },
+                ValidationError::OverlappingMacRanges {
+                    first: 1,
+                    second: 2
+                },
+            ]
+        );
+    }
+
+    fn endpoint(addresses: &[(&str, u8)]) -> HostComputeEndpoint {
+        HostComputeEndpoint {
+            ip_configurations: addresses
+                .iter()
+                .map(|(address, prefix_length)| IpConfig {
+                    ip_address: address.to_string(),
+                    prefix_length: Some(*prefix_length),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn invalid_prefix_length() {
+        assert_eq!(
+            errors(endpoint(&[("10.0.0.5", 33)]).validate()),
+            vec![ValidationError::InvalidPrefixLength {
+                address: "10.0.0.5".to_string(),
+                prefix_length: 33
+            }]
+        );
+    }
+
+    #[test]
+    fn duplicate_address_family() {
+        assert_eq!(
+            errors(endpoint(&[("10.0.0.5", 24), ("fd00::5", 64), ("10.0.0.6", 24)]).validate()),
+            vec![ValidationError::DuplicateAddressFamily {
+                family: IpFamily::V4
+            }]
+        );
+    }
+
+    #[test]
+    fn invalid_endpoint_mac() {
+        let endpoint = HostComputeEndpoint {
+            mac_address: "00-15-5D".to_string(),
+            ..endpoint(&[("10.0.0.5", 24)])
+        };
+        assert_eq!(
+            errors(endpoint.validate()),
+            vec![ValidationError::InvalidAddress {
+                field: "MacAddress",
+                address: "00-15-5D".to_string()
+            }]
+        );
+    }
+
+    fn load_balancer() -> HostComputeLoadBalancer {
+        HostComputeLoadBalancer {
+            host_compute_endpoints: vec!["ep-1".to_string()],
+            frontend_vips: vec!["10.96.0.10".to_string()],
+            port_mappings: vec![LoadBalancerPortMapping {
+                protocol: Some(6),
+                internal_port: Some(8080),
+                external_port: Some(80),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn valid_load_balancer() {
+        assert_eq!(errors(load_balancer().validate()), vec![]);
+    }
+
+    #[test]
+    fn vip_family_mismatch() {
+        let load_balancer = HostComputeLoadBalancer {
+            flags: Some(LOAD_BALANCER_FLAGS_IPV6),
+            ..load_balancer()
+        };
+        assert_eq!(
+            errors(load_balancer.validate()),
+            vec![ValidationError::VipFamilyMismatch {
+                field: "FrontendVIPs",
+                address: "10.96.0.10".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn missing_endpoints() {
+        let load_balancer = HostComputeLoadBalancer {
+            host_compute_endpoints: vec![],
+            ..load_balancer()
+        };
+        assert_eq!(
+            errors(load_balancer.validate()),
+            vec![ValidationError::MissingEndpoints]
+        );
+    }
+
+    #[test]
+    fn missing_source_vip() {
+        let load_balancer = HostComputeLoadBalancer {
+            flags: Some(LOAD_BALANCER_FLAGS_DSR),
+            ..load_balancer()
+        };
+        assert_eq!(
+            errors(load_balancer.validate()),
+            vec![ValidationError::MissingSourceVip]
+        );
+    }
+
+    #[test]
+    fn invalid_port_mapping() {
+        let mut load_balancer = load_balancer();
+        load_balancer.port_mappings.push(LoadBalancerPortMapping {
+            protocol: Some(17),
+            internal_port: Some(53),
+            ..Default::default()
+        });
+        assert_eq!(
+            errors(load_balancer.validate()),
+            vec![ValidationError::InvalidPortMapping {
+                index: 1,
+                reason: "external port is not set".to_string()
+            }]
+        );
+    }
+}